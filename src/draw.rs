@@ -1,5 +1,6 @@
 /* draw.rs: Create a graph of an abstract syntax tree. */
 use crate::parser;
+use crate::parser::{NonTerminal, Terminal};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
@@ -8,104 +9,172 @@ use std::str::from_utf8;
 
 /*
  * The following three macros are used by the graph creation functions and are
- * not exposed publicly. They determine the format of graph IDs and labels. To
+ * not exposed publicly. They determine the format of graph IDs and labels. The
+ * `uid' is unique for every AST node and is what keeps two nodes of the same
+ * type at the same depth from collapsing into one graph node. To
  * add non-terminal types to the graph, too, this set of macros needs to be
  * expanded with appropriate functionality. I.e. for every node in the AST, the
  * graph needs to have 2 nodes, one containing the non-terminal and one
  * containing the terminal type of that AST node.
  */
 macro_rules! start_branch {
-    ( $graph:expr, $ast:expr, $preamble:expr, $side:expr ) => {
-        let id = &format!("\"id={}_{}_{}\"",
-                          $ast.get_long_type(),
-                          $ast.get_depth(),
-                          $side);
-        let name = &format!("\"{}\"", $ast.get_short_type());
+    ( $graph:expr, $ast:expr, $preamble:expr, $uid:expr, $options:expr ) => {
+        let id = &format!("\"id={}_{}\"", $ast.get_long_type(), $uid);
+        let attributes = get_attributes($ast, $options);
         $graph.push_str(&format!("\t{} -- ", id));
-        $preamble.push_str(&format!("\t\t{} [label = {}]\n", id, name));
+        $preamble.push_str(&format!("\t\t{} [{}]\n", id, attributes));
     };
 }
 
 macro_rules! append_to_branch {
-    ( $graph:expr, $ast:expr, $preamble:expr, $side:expr ) => {
-        let id = &format!("\"id={}_{}_{}\"",
-                          $ast.get_long_type(),
-                          $ast.get_depth(),
-                          $side);
-        let name = &format!("\"{}\"", $ast.get_short_type());
+    ( $graph:expr, $ast:expr, $preamble:expr, $uid:expr, $options:expr ) => {
+        let id = &format!("\"id={}_{}\"", $ast.get_long_type(), $uid);
+        let attributes = get_attributes($ast, $options);
         $graph.push_str(&format!("{} -- ", id));
-        $preamble.push_str(&format!("\t\t{} [label = {}]\n", id, name));
+        $preamble.push_str(&format!("\t\t{} [{}]\n", id, attributes));
     };
 }
 
 macro_rules! end_branch {
-    ( $graph:expr, $ast:expr, $preamble:expr, $side:expr ) => {
-        let id = &format!("\"id={}_{}_{}\"",
-                          $ast.get_long_type(),
-                          $ast.get_depth(),
-                          $side);
-        let name = &format!("\"{}\"", $ast.get_short_type());
+    ( $graph:expr, $ast:expr, $preamble:expr, $uid:expr, $options:expr ) => {
+        let id = &format!("\"id={}_{}\"", $ast.get_long_type(), $uid);
+        let attributes = get_attributes($ast, $options);
         $graph.push_str(&format!("{}\n\t", id));
-        $preamble.push_str(&format!("\t\t{} [label = {}]\n", id, name));
+        $preamble.push_str(&format!("\t\t{} [{}]\n", id, attributes));
     };
 }
 
+/* Options which change the layout of a graph, see `create_graph'. */
+pub struct GraphOptions {
+    pub cluster_parens: bool,    /* box parenthesized groups */
+    pub color_levels: bool,      /* fill nodes with the color of their level */
+    pub no_parens: bool, /* connect the child of parentheses to their parent */
+    pub rankdir: Option<String>, /* e.g. `LR', top down if it's `None' */
+}
+
 /*
- * Based on the root node of an AST, this function writes a graphviz `.gv' file
- * to `path' and if `pdf', it also creates a PDF using the `dot' utility, which
- * will be written to `path', too (only the file extension will change to
- * `.pdf'). Currently, only the terminal types of nodes in the graph are drawn
- * out. Non-terminal types like `Expression' could be added quite easily,
- * though.
+ * The attributes of a node in the preamble, i.e. its label and, if
+ * `options.color_levels' is set, a fill color for the precedence level of its
+ * non-terminal: literals and variables are gray and the operators of the
+ * levels `Bitwise' to `Exponent' are purple, blue, green, orange and red.
  */
-pub fn create_graph(ast: &parser::ParseNode, path: &str, pdf: bool)
-                    -> std::io::Result<()> {
-    // the provided path must point to a `.gv' file, otherwise replacing the
-    // file extension with `.pdf' might fail later on
-    if !path.ends_with(".gv") {
-        return Err(Error::new(
-                ErrorKind::InvalidInput,
-                String::from("Provide the path to a `.gv' file (need not exist)")));
+fn get_attributes(ast: &parser::ParseNode, options: &GraphOptions) -> String {
+    let label = format!("label = \"{}\"", ast.get_short_type());
+    if !options.color_levels {
+        return label;
     }
-    let mut file = File::create(path)?;
+    let color = match (&ast.terminal, ast.non_terminal()) {
+        (Terminal::Literal(_), _) | (Terminal::Var(_), _) => "gray",
+        (_, NonTerminal::Conditional) => "pink",
+        (_, NonTerminal::Disjunction) | (_, NonTerminal::Conjunction) => "yellow",
+        (_, NonTerminal::Bitwise) => "purple",
+        (_, NonTerminal::Expression) => "lightblue",
+        (_, NonTerminal::Term) => "lightgreen",
+        (_, NonTerminal::Factor) => "orange",
+        (_, NonTerminal::Exponent) => "tomato",
+    };
+    format!("{}, style = filled, fillcolor = {}", label, color)
+}
+
+/*
+ * Based on the root node of an AST, this function writes a graphviz `.gv' file
+ * and if `render', it also creates an image of the graph using the `dot'
+ * utility. The path of the last file that was written is returned. The format
+ * of the image is inferred from the extension of `path': `.pdf', `.svg' and
+ * `.png' are rendered to `path' while the `.gv' file is written next to it
+ * (only the file extension changes). If `path' points to a `.gv' file, the
+ * image defaults to a PDF. Currently, only the terminal types of nodes in the
+ * graph are drawn out. Non-terminal types like `Expression' could be added
+ * quite easily, though.
+ */
+pub fn create_graph(ast: &parser::ParseNode, path: &str, render: bool,
+                    options: &GraphOptions)
+                    -> std::io::Result<String> {
+    // the graph description always goes to a `.gv' file, the image to a file
+    // with the extension of the requested format
+    let (gv_path, format) = get_output_paths(path)?;
+    let mut file = File::create(&gv_path)?;
 
     // transform an ast data structure into a graph description and write the
     // result to the indicated file
-    let graph = create_graph_from_ast(&ast);
+    let graph = create_graph_from_ast(ast, options);
     file.write_all(graph.as_bytes())?;
 
     // if requested, execute `dot' on the created graph description file and
-    // save the output to a pdf file
-    if pdf {
-        let outfile = path.replace(".gv", ".pdf");
-        let mut file = File::create(&outfile)?;
-        let output = Command::new("dot").arg("-Tpdf")
-                                        .arg(path)
-                                        .output()
-                                        .expect("Failed to execute dot");
-
-        // if anything was printed on `stderr', return with an error
-        let err = output.stderr;
-        if err.len() > 0 {
-            return Err(Error::new(ErrorKind::InvalidInput,
-                                  format!("Faild to execute dot: {}",
-                                          from_utf8(&err[..]).unwrap())));
+    // save the output to a file of the requested format; otherwise, `dot' is
+    // not needed at all and the path of the description is returned
+    if !render {
+        return Ok(gv_path);
+    }
+    let outfile = gv_path.replace(".gv", &format!(".{}", format));
+    let output = Command::new("dot").arg(format!("-T{}", format))
+                                    .arg(&gv_path)
+                                    .output()
+                                    .map_err(|e| {
+                                        Error::new(e.kind(),
+                                                   format!("Failed to execute dot \
+                                                            (is graphviz \
+                                                            installed?): {}",
+                                                           e))
+                                    })?;
+
+    // if anything was printed on `stderr', return with an error
+    let err = output.stderr;
+    if !err.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Faild to execute dot: {}",
+                                      from_utf8(&err[..]).unwrap())));
+    }
+    let mut file = File::create(&outfile)?;
+    file.write_all(&output.stdout[..])?;
+
+    Ok(outfile)
+}
+
+/*
+ * Split the `path' that was passed to `create_graph' into the path of the
+ * `.gv' file and the output format of `dot' (which doubles as the extension of
+ * the rendered file). Unsupported extensions are rejected.
+ */
+fn get_output_paths(path: &str) -> std::io::Result<(String, &'static str)> {
+    const FORMATS: [&str; 3] = ["pdf", "svg", "png"];
+
+    if path.ends_with(".gv") {
+        return Ok((path.to_string(), FORMATS[0]));
+    }
+    for format in FORMATS.iter() {
+        let extension = format!(".{}", format);
+        if path.ends_with(&extension) {
+            let stem = &path[..path.len() - extension.len()];
+            return Ok((format!("{}.gv", stem), format));
         }
-        file.write_all(&output.stdout[..])?;
     }
+    Err(Error::new(ErrorKind::InvalidInput,
+                   format!("Unsupported graph file `{}' (use `.gv', `.pdf', \
+                            `.svg' or `.png')",
+                           path)))
+}
 
-    Ok(())
+/* The parts of a graph description that are filled while traversing an AST. */
+struct Graph<'a> {
+    body: String,              /* relationships between nodes */
+    preamble: String,          /* labels of nodes */
+    clusters: String,          /* `subgraph cluster_*' blocks */
+    next_uid: usize,           /* unique ID of the next visited node */
+    open_clusters: usize,      /* nesting level of the current cluster */
+    options: &'a GraphOptions, /* layout options */
 }
 
 /* The syntax of a `.gv' file is described below. */
-fn create_graph_from_ast(ast: &parser::ParseNode) -> String {
+fn create_graph_from_ast(ast: &parser::ParseNode, options: &GraphOptions) -> String {
     /*
-     * `graph' holds the actual relationships between nodes and the enclosing
+     * `body' holds the actual relationships between nodes and the enclosing
      * `graph { ... }' while the `preamble' remaps node IDs and readable labels
      * (it is actually appended to the end of the graph description body,
      * though). The resulting structure of the `.gv' file is:
      * ```
      * graph {
+     *      rankdir=LR;
      *      "id_node_1" -- "id_node_2" -- "id_node_3"
      *      "id_node_1" -- "id_node_4" -- "id_node_5"
      *      [...]
@@ -113,60 +182,228 @@ fn create_graph_from_ast(ast: &parser::ParseNode) -> String {
      *          "id_node_1" [label = "label of this node"]
      *          [...]
      *      }
+     *      subgraph cluster_2 {
+     *          "id_node_2"
+     *          [...]
+     *      }
      * }
      * ```
      * All node IDs and labels are always enclosed in double-quotes to avoid
-     * syntax errors (`+', `>', etc. are valid `dot' syntax).
+     * syntax errors (`+', `>', etc. are valid `dot' syntax). With
+     * `options.color_levels', the labels come with a fill color (see
+     * `get_attributes'). The clusters are only added if
+     * `options.cluster_parens' is set. They list the nodes of a
+     * parenthesized group and nest like the parentheses do. With
+     * `options.no_parens', there are no parentheses nodes at all: the tree
+     * already encodes the grouping, so their child is connected to their
+     * parent directly (see `add_child'). The `rankdir' line is only written if
+     * `options.rankdir' is set.
      */
-    let mut graph = String::new();
-    let mut preamble = String::new();
-    graph.push_str("graph {\n");
-    preamble.push_str("{\n");
+    let mut graph = Graph { body: String::new(),
+                            preamble: String::new(),
+                            clusters: String::new(),
+                            next_uid: 0,
+                            open_clusters: 0,
+                            options };
+    graph.body.push_str("graph {\n");
+    if let Some(rankdir) = &graph.options.rankdir {
+        graph.body.push_str(&format!("\trankdir={};\n", rankdir));
+    }
+    graph.preamble.push_str("{\n");
 
     // add the root node to the tree and delegate interpretation
     // of the children
+    let ast = if graph.options.no_parens {
+        ast.strip_parens()
+    } else {
+        ast
+    };
+    let (uid, is_cluster) = enter_node(&mut graph, ast);
     if let Some(lchild) = ast.get_lchild() {
         // LHS of the tree
-        start_branch!(graph, ast, preamble, "root");
-        add_child(lchild, &mut graph, &mut preamble, "left");
+        start_branch!(graph.body, ast, graph.preamble, uid, graph.options);
+        add_child(lchild, &mut graph);
 
         if let Some(rchild) = ast.get_rchild() {
             // RHS of the tree
-            append_to_branch!(graph, ast, preamble, "root");
-            add_child(rchild, &mut graph, &mut preamble, "right");
+            append_to_branch!(graph.body, ast, graph.preamble, uid, graph.options);
+            add_child(rchild, &mut graph);
         }
     }
+    leave_node(&mut graph, is_cluster);
 
     // close the right curly braces, add the preamble and return
-    preamble.push_str("\t}\n");
-    graph.push_str(&preamble);
-    graph.push_str("}");
-    graph
+    graph.preamble.push_str("\t}\n");
+    graph.body.push_str(&graph.preamble);
+    graph.body.push_str(&graph.clusters);
+    graph.body.push('}');
+    graph.body
 }
 
 /*
- * NOTE: the `side' would ideally be incremented from level to level,
- * resulting in strings like 'rootleftleftsingleleft'. The current solution
- * still doesn't guarantee unique names for every node. A nicer solution would
- * probably draw graph nodes based on the non-terminal types of the AST nodes.
+ * Assign the next unique ID to `ast_node' and open a cluster if the node is a
+ * parenthesized group that should be boxed. The node is listed in the
+ * innermost cluster that is open, which includes the one it opened itself.
  */
-fn add_child(ast_node: &parser::ParseNode, graph: &mut String,
-             preamble: &mut String, side: &str) {
-    if let None = ast_node.get_lchild() {
-        end_branch!(graph, ast_node, preamble, side);
+fn enter_node(graph: &mut Graph, ast_node: &parser::ParseNode) -> (usize, bool) {
+    let uid = graph.next_uid;
+    graph.next_uid += 1;
+
+    let is_cluster =
+        graph.options.cluster_parens && matches!(ast_node.terminal, Terminal::Paren);
+    if is_cluster {
+        graph.open_clusters += 1;
+        let indent = "\t".repeat(graph.open_clusters);
+        graph.clusters
+             .push_str(&format!("{}subgraph cluster_{} {{\n", indent, uid));
+    }
+    if graph.open_clusters > 0 {
+        let indent = "\t".repeat(graph.open_clusters + 1);
+        graph.clusters.push_str(&format!("{}\"id={}_{}\"\n",
+                                         indent,
+                                         ast_node.get_long_type(),
+                                         uid));
+    }
+    (uid, is_cluster)
+}
+
+/* Close the cluster that `enter_node' opened, if any. */
+fn leave_node(graph: &mut Graph, is_cluster: bool) {
+    if is_cluster {
+        let indent = "\t".repeat(graph.open_clusters);
+        graph.clusters.push_str(&format!("{}}}\n", indent));
+        graph.open_clusters -= 1;
+    }
+}
+
+/*
+ * Every AST node gets its unique ID when it is visited. The ID is handed to
+ * the macros for every edge the node is part of, so a node with two children
+ * shows up as the same graph node on both branches.
+ */
+fn add_child(ast_node: &parser::ParseNode, graph: &mut Graph) {
+    let ast_node = if graph.options.no_parens {
+        ast_node.strip_parens()
+    } else {
+        ast_node
+    };
+    let (uid, is_cluster) = enter_node(graph, ast_node);
+    if ast_node.get_lchild().is_none() {
+        end_branch!(graph.body, ast_node, graph.preamble, uid, graph.options);
     } else if let Some(lchild) = ast_node.get_lchild() {
-        if let None = ast_node.get_rchild() {
+        if ast_node.get_rchild().is_none() {
             // this node has only one child, which means this must be
-            // parentheses (currently this is a somewhat dirty hack)
-            if ast_node.get_long_type().contains("Parentheses") {
-                start_branch!(graph, ast_node, preamble, side);
-                add_child(lchild, graph, preamble, "single");
-            }
+            // parentheses, a negation or an absolute value (currently this is
+            // a somewhat dirty hack)
+            start_branch!(graph.body, ast_node, graph.preamble, uid, graph.options);
+            add_child(lchild, graph);
         } else if let Some(rchild) = ast_node.get_rchild() {
-            append_to_branch!(graph, ast_node, preamble, side);
-            add_child(lchild, graph, preamble, "left");
-            append_to_branch!(graph, ast_node, preamble, side);
-            add_child(rchild, graph, preamble, "right");
+            append_to_branch!(graph.body,
+                              ast_node,
+                              graph.preamble,
+                              uid,
+                              graph.options);
+            add_child(lchild, graph);
+            append_to_branch!(graph.body,
+                              ast_node,
+                              graph.preamble,
+                              uid,
+                              graph.options);
+            add_child(rchild, graph);
+        }
+    }
+    leave_node(graph, is_cluster);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> GraphOptions {
+        GraphOptions { cluster_parens: false,
+                       color_levels: false,
+                       no_parens: false,
+                       rankdir: None }
+    }
+
+    fn graph_of(input: &str, options: &GraphOptions) -> String {
+        match parser::parse_str(input) {
+            Ok(ast) => create_graph_from_ast(&ast, options),
+            Err(err) => panic!("`{}' doesn't parse: {}", input, err.msg),
         }
     }
+
+    #[test]
+    fn repeated_literals_get_distinct_ids() {
+        let graph = graph_of("2+2+2", &options());
+        let mut ids: Vec<&str> = graph.lines()
+                                      .map(str::trim)
+                                      .filter(|l| l.starts_with("\"id=Literal=2_"))
+                                      .filter_map(|l| l.split(' ').next())
+                                      .collect();
+        assert_eq!(ids.len(), 3);
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+    }
+
+    #[test]
+    fn clusters_box_parenthesized_groups() {
+        let mut clustered = options();
+        clustered.cluster_parens = true;
+        let graph = graph_of("(1+2)*3", &clustered);
+        assert_eq!(graph.matches("subgraph cluster_").count(), 1);
+        let cluster = &graph[graph.find("subgraph cluster_").unwrap()..];
+        assert!(cluster.contains("\"id=Op=PLUS_"));
+        assert!(!cluster.contains("\"id=Op=MULTIPLICATION_"));
+        assert!(!graph_of("(1+2)*3", &options()).contains("subgraph cluster"));
+    }
+
+    #[test]
+    fn colored_levels_fill_the_nodes() {
+        let mut colored = options();
+        colored.color_levels = true;
+        let graph = graph_of("1+2", &colored);
+        assert!(graph.contains("[label = \"+\", style = filled, fillcolor = lightblue]"));
+        assert!(!graph_of("1+2", &options()).contains("fillcolor"));
+    }
+
+    #[test]
+    fn parentheses_can_be_left_out() {
+        let mut no_parens = options();
+        no_parens.no_parens = true;
+        let graph = graph_of("((1+2))*(3)", &no_parens);
+        assert!(!graph.contains("Parentheses"));
+        assert!(graph.contains("\"id=Op=PLUS_"));
+        assert!(graph_of("((1+2))*(3)", &options()).contains("Parentheses"));
+    }
+
+    #[test]
+    fn rankdir_is_only_written_if_set() {
+        let mut left_right = options();
+        left_right.rankdir = Some("LR".to_string());
+        assert!(graph_of("1+2", &left_right).lines()
+                                            .any(|l| l == "\trankdir=LR;"));
+        assert!(!graph_of("1+2", &options()).contains("rankdir"));
+    }
+
+    #[test]
+    fn only_the_description_is_written_without_render() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("expr_parser_no_pdf_test.pdf");
+        let gv_path = dir.join("expr_parser_no_pdf_test.gv");
+        let _ = std::fs::remove_file(&path);
+        let ast = match parser::parse_str("1+2") {
+            Ok(ast) => ast,
+            Err(err) => panic!("{}", err.msg),
+        };
+
+        // `dot' is never executed, so this works without graphviz, too
+        let written = create_graph(&ast, path.to_str().unwrap(), false, &options());
+        assert_eq!(written.unwrap(), gv_path.to_str().unwrap());
+        assert!(std::fs::read_to_string(&gv_path).unwrap()
+                                                 .starts_with("graph {"));
+        assert!(!path.exists());
+        std::fs::remove_file(&gv_path).unwrap();
+    }
 }
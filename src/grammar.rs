@@ -0,0 +1,253 @@
+/* grammar.rs: The operator symbols, precedence levels and associativity. */
+use crate::lexer::Token;
+use crate::parser::Terminal;
+#[cfg(feature = "std")]
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+
+/*
+ * Binary operators are grouped into these precedence levels. They correspond
+ * to the non-terminals `Bitwise', `Expression', `Term' and `Factor' of the
+ * grammar, i.e. level 0 binds weakest and level 3 binds tightest. Only the
+ * bitwise shifts and `xor' (see `--c-operators') are on level 0 by default.
+ */
+pub const MIN_LEVEL: u8 = 0;
+pub const MAX_LEVEL: u8 = 3;
+
+/* A binary operator: its symbol in the input and how it is parsed. */
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub name: &'static str, /* e.g. `mult', used in grammar files */
+    pub symbol: String,     /* e.g. `*' or `**' */
+    pub token: Token,       /* the token the lexer emits for `symbol' */
+    pub level: u8,          /* precedence level, see above */
+    pub right_assoc: bool,  /* `2^3^2' is `2^(3^2)' */
+}
+
+/*
+ * The operator table that is consulted by the lexer and the parser. Without
+ * a grammar file, the default table implements the grammar in GRAMMAR.md.
+ */
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    operators: Vec<Operator>,
+}
+
+impl Default for Grammar {
+    fn default() -> Grammar {
+        Grammar { operators: vec![op("add", "+", Token::OpAdd, 1, false),
+                                  op("sub", "-", Token::OpSub, 1, false),
+                                  op("mult", "*", Token::OpMult, 2, false),
+                                  op("div", "/", Token::OpDiv, 2, false),
+                                  op("mod", "%", Token::OpMod, 2, false),
+                                  op("exp", "^", Token::OpExp, 3, true),
+                                  op("shl", "<<", Token::OpShl, 0, false),
+                                  op("shr", ">>", Token::OpShr, 0, false),
+                                  op("ushr", ">>>", Token::OpUshr, 0, false),] }
+    }
+}
+
+fn op(name: &'static str, symbol: &str, token: Token, level: u8,
+      right_assoc: bool)
+      -> Operator {
+    Operator { name,
+               symbol: symbol.to_string(),
+               token,
+               level,
+               right_assoc }
+}
+
+impl Grammar {
+    /*
+     * The operators of C (see `--c-operators'): `^' is a bitwise exclusive or
+     * that binds weaker than all arithmetic operators and `**' is the
+     * exponentiation. Everything else is the same as in the default grammar.
+     */
+    pub fn c_operators() -> Grammar {
+        let mut grammar = Grammar::default();
+        for operator in grammar.operators.iter_mut() {
+            if operator.token == Token::OpExp {
+                operator.symbol = String::from("**");
+            }
+        }
+        grammar.operators
+               .push(op("xor", "^", Token::OpXor, 0, false));
+        grammar
+    }
+
+    /*
+     * Get the token of the operator whose symbol starts the characters
+     * `input' and the length of that symbol. If several symbols match, the
+     * longest one wins, so `**' isn't lexed as two `*'.
+     */
+    pub fn match_token(&self, input: impl Iterator<Item = char> + Clone)
+                       -> Option<(Token, usize)> {
+        self.operators
+            .iter()
+            .filter(|op| {
+                let mut rest = input.clone();
+                op.symbol.chars().all(|c| rest.next() == Some(c))
+            })
+            .max_by_key(|op| op.symbol.chars().count())
+            .map(|op| (op.token.clone(), op.symbol.chars().count()))
+    }
+
+    /* Get the binary operator that `token' stands for, if any. */
+    pub fn get_operator(&self, token: &Token) -> Option<&Operator> {
+        self.operators.iter().find(|op| &op.token == token)
+    }
+
+    pub fn get_operators(&self) -> &[Operator] {
+        &self.operators
+    }
+}
+
+/*
+ * Map the tokens of binary operators to their terminal in the parse tree,
+ * including the fixed operators that aren't part of a grammar.
+ */
+pub fn get_terminal(token: &Token) -> Option<Terminal> {
+    match token {
+        Token::OpAdd => Some(Terminal::Sum),
+        Token::OpSub => Some(Terminal::Sub),
+        Token::OpMod => Some(Terminal::Mod),
+        Token::OpMult => Some(Terminal::Mult),
+        Token::OpDiv => Some(Terminal::Div),
+        Token::OpExp => Some(Terminal::Exp),
+        Token::OpXor => Some(Terminal::Xor),
+        Token::OpShl => Some(Terminal::Shl),
+        Token::OpShr => Some(Terminal::Shr),
+        Token::OpUshr => Some(Terminal::Ushr),
+        Token::OpAnd => Some(Terminal::And),
+        Token::OpOr => Some(Terminal::Or),
+        Token::Question => Some(Terminal::Cond),
+        _ => None,
+    }
+}
+
+/*
+ * Load a grammar file that redefines the operators of the `base' grammar.
+ * Every non-empty line that doesn't start with `#' has four columns:
+ * ```
+ * # name  symbol  level  associativity
+ * mult    ×       2      left
+ * ```
+ * `name' is one of `add', `sub', `mod', `mult', `div', `exp', `shl', `shr'
+ * and `ushr' (and `xor' with `--c-operators'), `level' is a precedence
+ * level from 0 to 3 and `associativity' is `left' or `right'. Operators that
+ * the file doesn't mention keep their definition in `base'. Conflicts are
+ * reported with the offending line: two operators can't share a symbol, a
+ * symbol can't contain a digit, whitespace, a parenthesis, a `|', a `,', a
+ * `#' (which starts a comment), a `=' (of assignments), a `&' or `!' (of the
+ * logical operators), a `~' (of the bitwise not), a `?' or `:' (of
+ * conditionals), `e' or `E' (which start the exponent of a literal like
+ * `1e3') and all operators of a level must have the same associativity
+ * (otherwise `1 a 2 b 3' would be ambiguous).
+ */
+#[cfg(feature = "std")]
+pub fn load_grammar(path: &str, base: Grammar) -> Result<Grammar, String> {
+    let content = fs::read_to_string(path).map_err(|e| {
+                      format!("Cannot read grammar file `{}': {}", path, e)
+                  })?;
+    let mut grammar = base;
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: String| format!("{}:{}: {}", path, i + 1, msg);
+
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() != 4 {
+            return Err(err(format!("Expected 4 columns, found {}", columns.len())));
+        }
+        let op =
+            grammar.operators
+                   .iter_mut()
+                   .find(|op| op.name == columns[0])
+                   .ok_or_else(|| {
+                       err(format!("Unknown operator `{}'", columns[0]))
+                   })?;
+
+        if columns[1].chars()
+                     .any(|c| c.is_numeric() || "()|,#=&!~?:eE".contains(c))
+        {
+            return Err(err(format!("Invalid symbol `{}'", columns[1])));
+        }
+        op.symbol = columns[1].to_string();
+        op.level = match columns[2].parse::<u8>() {
+            Ok(level) if (MIN_LEVEL..=MAX_LEVEL).contains(&level) => level,
+            _ => {
+                return Err(err(format!("Precedence level must be {} to {}, found `{}'",
+                                       MIN_LEVEL, MAX_LEVEL, columns[2])))
+            }
+        };
+        op.right_assoc = match columns[3] {
+            "left" => false,
+            "right" => true,
+            other => return Err(err(format!("Unknown associativity `{}'", other))),
+        };
+    }
+
+    check_conflicts(&grammar).map_err(|msg| format!("{}: {}", path, msg))?;
+    Ok(grammar)
+}
+
+#[cfg(feature = "std")]
+fn check_conflicts(grammar: &Grammar) -> Result<(), String> {
+    for (i, op) in grammar.operators.iter().enumerate() {
+        for other in &grammar.operators[i + 1..] {
+            if op.symbol == other.symbol {
+                return Err(format!("`{}' and `{}' share the symbol `{}'",
+                                   op.name, other.name, op.symbol));
+            }
+            if op.level == other.level && op.right_assoc != other.right_assoc {
+                return Err(format!("`{}' and `{}' are on level {} but have a \
+                                    different associativity",
+                                   op.name, other.name, op.level));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_with;
+    use crate::parser::{parse_with, DEFAULT_MAX_DEPTH};
+    use crate::vm::evaluate;
+
+    fn eval_with(input: &str, grammar: &Grammar) -> Option<i64> {
+        let tokens = lex_with(input, grammar);
+        let ast = parse_with(tokens, grammar, DEFAULT_MAX_DEPTH).ok()?;
+        evaluate(&ast).ok()
+    }
+
+    #[test]
+    fn a_grammar_file_renames_an_operator() {
+        let path = std::env::temp_dir().join("expr_parser_grammar_test.txt");
+        fs::write(&path, "# multiply with a times sign\nmult × 2 left\n").unwrap();
+        let grammar = load_grammar(path.to_str().unwrap(), Grammar::default());
+        fs::remove_file(&path).unwrap();
+
+        let grammar = grammar.unwrap();
+        assert_eq!(eval_with("2×3", &grammar), Some(6));
+        assert_eq!(eval_with("1+2×3", &grammar), Some(7));
+        assert_eq!(eval_with("2*3", &grammar), None);
+    }
+
+    #[test]
+    fn the_caret_is_xor_with_c_operators() {
+        assert_eq!(eval_with("5^3", &Grammar::default()), Some(125));
+        assert_eq!(eval_with("5^3", &Grammar::c_operators()), Some(6));
+        assert_eq!(eval_with("5**3", &Grammar::c_operators()), Some(125));
+        // the xor binds weaker than all arithmetic operators
+        assert_eq!(eval_with("1+4^2*3", &Grammar::c_operators()), Some(3));
+    }
+}
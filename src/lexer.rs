@@ -1,90 +1,289 @@
 /* lexer.rs: The lexer. */
-use std::iter::Peekable;
+use crate::grammar::Grammar;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
+use core::ops::Range;
+use core::str::Chars;
 
 /* Lexing can return these tokens. */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    OpAdd,      /* + */
-    OpSub,      /* - */
-    OpMod,      /* % */
-    OpMult,     /* * */
-    OpDiv,      /* / */
-    OpExp,      /* ^ */
-    LeftParen,  /* ( */
-    RightParen, /* ) */
+    OpAdd,         /* + */
+    OpSub,         /* - */
+    OpMod,         /* % */
+    OpMult,        /* * */
+    OpDiv,         /* / */
+    OpExp,         /* ^ (or ** with `--c-operators') */
+    OpXor,         /* ^ with `--c-operators' */
+    OpShl,         /* << */
+    OpShr,         /* >> */
+    OpUshr,        /* >>> */
+    OpAssign,      /* = */
+    OpAddAssign,   /* += */
+    OpSubAssign,   /* -= */
+    OpMultAssign,  /* *= */
+    OpDivAssign,   /* /= */
+    OpAnd,         /* && */
+    OpOr,          /* || (or two `|' of absolute values, see the parser) */
+    OpNot,         /* ! */
+    Tilde,         /* ~, the bitwise not */
+    Question,      /* ? of a conditional `c ? a : b' */
+    Colon,         /* : of a conditional */
+    LeftParen,     /* ( */
+    RightParen,    /* ) */
+    Bar,           /* | */
+    Comma,         /* , */
+    Ident(String), /* e.g. the name of a function in `max(1,2)' */
     Number(i64),
 }
 
 /*
- * A generic error type that is used by the lexer and holds a message and the
- * token at which the error occured. A vector of tokens up to the error is
- * included for better error reporting.
+ * A token as it is written in the input, e.g. `+' or `12', for error
+ * messages. Operators are shown with their default symbols, so `OpExp' is
+ * `^' even with `--c-operators' (see `Grammar' for the actual symbols).
  */
-#[derive(Debug)]
-pub struct LexerError {
-    pub msg: String,
-    pub token_no: usize,
-    pub tokens: Vec<Token>, /* tokens up to the error */
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Token::OpAdd => "+",
+            Token::OpSub => "-",
+            Token::OpMod => "%",
+            Token::OpMult => "*",
+            Token::OpDiv => "/",
+            Token::OpExp | Token::OpXor => "^",
+            Token::OpShl => "<<",
+            Token::OpShr => ">>",
+            Token::OpUshr => ">>>",
+            Token::OpAssign => "=",
+            Token::OpAddAssign => "+=",
+            Token::OpSubAssign => "-=",
+            Token::OpMultAssign => "*=",
+            Token::OpDivAssign => "/=",
+            Token::OpAnd => "&&",
+            Token::OpOr => "||",
+            Token::OpNot => "!",
+            Token::Tilde => "~",
+            Token::Question => "?",
+            Token::Colon => ":",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::Bar => "|",
+            Token::Comma => ",",
+            Token::Ident(name) => return write!(f, "{}", name),
+            Token::Number(n) => return write!(f, "{}", n),
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
-/* The lexer which emits a token stream or an error. */
-pub fn lex(input: &String) -> Result<Vec<Token>, LexerError> {
-    let mut progress = 0;
-    let mut result = vec![];
-    let mut token_stream = input.chars().peekable();
-
-    while let Some(&c) = token_stream.peek() {
-        progress += 1;
-
-        match c {
-            '0'..='9' => {
-                token_stream.next();
+/*
+ * Write `tokens' as a canonical expression with single spaces around the
+ * binary operators, e.g. `(1+2)*-3' is `(1 + 2) * -3', so that lexing the
+ * output yields the same tokens again. Operators are written with their
+ * default symbols (see the `Display' of `Token'), i.e. the output is meant
+ * for `lex' and not for a custom grammar.
+ */
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+    let mut output = String::new();
+    let mut follows_operand = false; /* like in `Lexer', but for bars, too */
+    let mut groups: Vec<&Token> = vec![]; /* the open `(' and `|' */
+    let mut is_tight = true; /* no space after the previous token */
 
-                // pass the already consumed char and the stream to a fn that
-                // parses the whole number
-                let n = get_number(c, &mut token_stream);
-                result.push(Token::Number(n));
-            }
-            '+' => {
-                result.push(Token::OpAdd);
-                token_stream.next();
+    for (i, token) in tokens.iter().enumerate() {
+        // where an operand is expected, `-', `+', `!', `~', `|' and `||' are
+        // prefixes that an operand follows without a space, and `)', `|'
+        // and a postfix `%' follow their operand without one
+        let (tight_before, tight_after, operand) = match token {
+            Token::Number(_) | Token::Ident(_) => (false, false, true),
+            Token::LeftParen => {
+                groups.push(token);
+                (matches!(i.checked_sub(1).map(|i| &tokens[i]),
+                          Some(Token::Ident(_))),
+                 true,
+                 false)
             }
-            '-' => {
-                result.push(Token::OpSub);
-                token_stream.next();
+            Token::RightParen => {
+                groups.pop();
+                (true, false, true)
             }
-            '%' => {
-                result.push(Token::OpMod);
-                token_stream.next();
+            Token::Comma => (true, false, false),
+            Token::Bar if follows_operand => {
+                groups.pop();
+                (true, false, true)
             }
-            '*' => {
-                result.push(Token::OpMult);
-                token_stream.next();
+            Token::Bar => {
+                groups.push(token);
+                (false, true, false)
             }
-            '/' => {
-                result.push(Token::OpDiv);
-                token_stream.next();
+            // a `||' opens or closes two absolute values like two `|' do,
+            // except after an operand outside of them, where it's an `or'
+            Token::OpOr if !follows_operand => {
+                groups.extend([token, token]);
+                (false, true, false)
             }
-            '^' => {
-                result.push(Token::OpExp);
-                token_stream.next();
+            Token::OpOr if groups.last() == Some(&&Token::Bar) => {
+                groups.truncate(groups.len().saturating_sub(2));
+                (true, false, true)
             }
-            '(' => {
-                result.push(Token::LeftParen);
-                token_stream.next();
+            Token::OpSub | Token::OpAdd | Token::OpNot | Token::Tilde
+                if !follows_operand =>
+            {
+                (false, true, false)
             }
-            ')' => {
-                result.push(Token::RightParen);
-                token_stream.next();
+            Token::OpMod
+                if follows_operand && !is_modulo(tokens.get(i + 1), &groups) =>
+            {
+                (true, false, true)
             }
-            ' ' => {
-                token_stream.next();
-            }
-            _ => {
-                return Err(LexerError { msg:
-                                            format!("Unexpected character `{}'", c),
-                                        token_no: progress,
-                                        tokens: result });
+            _ => (false, false, false),
+        };
+
+        let symbol = format!("{}", token);
+        if !is_tight && !tight_before || i > 0 && is_merged(&tokens[i - 1], token) {
+            output.push(' ');
+        }
+        output.push_str(&symbol);
+        is_tight = tight_after;
+        follows_operand = operand;
+    }
+    output
+}
+
+/* Check if a `%' before `next' is a modulo, see `is_percent' of the parser. */
+fn is_modulo(next: Option<&Token>, groups: &[&Token]) -> bool {
+    match next {
+        Some(Token::Number(_))
+        | Some(Token::LeftParen)
+        | Some(Token::Ident(_))
+        | Some(Token::OpNot)
+        | Some(Token::Tilde)
+        | Some(Token::OpSub)
+        | Some(Token::OpAdd) => true,
+        Some(Token::Bar) => groups.last() != Some(&&Token::Bar),
+        _ => false,
+    }
+}
+
+/*
+ * Check if `first' and `second' would be lexed differently without a space
+ * between them, e.g. two `|' are a `||' and `1' and `e3' are the literal
+ * `1e3'. A negative number is always spaced, since its `-' would otherwise
+ * be taken for an operator.
+ */
+fn is_merged(first: &Token, second: &Token) -> bool {
+    match (first, second) {
+        (_, Token::Number(n)) if *n < 0 => true,
+        (Token::Bar, Token::Bar) | (Token::Bar, Token::OpOr) => true,
+        (Token::OpAdd, Token::OpAssign)
+        | (Token::OpSub, Token::OpAssign)
+        | (Token::OpMult, Token::OpAssign)
+        | (Token::OpDiv, Token::OpAssign) => true,
+        (Token::OpShr, Token::OpShr) | (Token::OpShr, Token::OpUshr) => true,
+        (Token::Ident(_), Token::Ident(_)) | (Token::Ident(_), Token::Number(_)) => {
+            true
+        }
+        (Token::Number(_), Token::Number(_)) => true,
+        (Token::Number(_), Token::Ident(name)) => {
+            name.starts_with('e') || name.starts_with('E')
+        }
+        _ => false,
+    }
+}
+
+/*
+ * A generic error type that is used by the lexer and holds a message and the
+ * token at which the error occured. A vector of tokens up to the error and
+ * the byte offset of the error in the input are included for better error
+ * reporting.
+ */
+#[derive(Debug)]
+pub struct LexerError {
+    pub msg: String,
+    pub token_no: usize,
+    pub tokens: Vec<Token>, /* tokens up to the error */
+    pub offset: usize,      /* e.g. 4 for the `@' in `1 + @' */
+}
+
+/*
+ * The lexer which emits a token stream or an error. It never panics: only the
+ * ASCII digits start a literal, so numerals like `²' or `٣' (which are
+ * `char::is_numeric') are unexpected characters.
+ */
+pub fn lex(input: &str) -> Result<Vec<Token>, LexerError> {
+    lex_with(input, &Grammar::default())
+}
+
+/*
+ * The number of characters that `lex' and its variants accept by default.
+ * Longer input is rejected before any token is lexed, so untrusted input
+ * can't make the lexer build a huge token vector. No expression that is
+ * typed or kept in a file comes close to it.
+ */
+pub const DEFAULT_MAX_INPUT_LEN: usize = 1_000_000;
+
+/* Like `lex', but the symbols of operators are looked up in `grammar'. */
+pub fn lex_with(input: &str, grammar: &Grammar) -> Result<Vec<Token>, LexerError> {
+    lex_with_limit(input, grammar, DEFAULT_MAX_INPUT_LEN)
+}
+
+/* Like `lex_with', but the input may be at most `max_len' characters long. */
+pub fn lex_with_limit(input: &str, grammar: &Grammar, max_len: usize)
+                      -> Result<Vec<Token>, LexerError> {
+    let tokens = lex_spanned_with_limit(input, grammar, max_len, None, i64::MAX)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/*
+ * Like `lex_with', but a literal may have at most `max_digits' digits if it
+ * is given (see `--limit-literal-digits' and `Lexer::set_max_digits') and it
+ * may be at most `max_literal' (see `--int-width' and
+ * `Lexer::set_max_literal').
+ */
+pub fn lex_with_literal_limits(input: &str, grammar: &Grammar,
+                               max_digits: Option<usize>, max_literal: i64)
+                               -> Result<Vec<Token>, LexerError> {
+    let tokens = lex_spanned_with_limit(input,
+                                        grammar,
+                                        DEFAULT_MAX_INPUT_LEN,
+                                        max_digits,
+                                        max_literal)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/*
+ * Like `lex', but every token comes with the byte range of `input' that it
+ * was lexed from, e.g. `12 + 3' is `Number(12)' at `0..2', `OpAdd' at `3..4'
+ * and `Number(3)' at `5..6'. Whitespace is part of no token.
+ */
+pub fn lex_spanned(input: &str) -> Result<Vec<(Token, Range<usize>)>, LexerError> {
+    lex_spanned_with(input, &Grammar::default())
+}
+
+/* Like `lex_spanned', but with the operators of `grammar'. */
+pub fn lex_spanned_with(input: &str, grammar: &Grammar)
+                        -> Result<Vec<(Token, Range<usize>)>, LexerError> {
+    lex_spanned_with_limit(input, grammar, DEFAULT_MAX_INPUT_LEN, None, i64::MAX)
+}
+
+fn lex_spanned_with_limit(input: &str, grammar: &Grammar, max_len: usize,
+                          max_digits: Option<usize>, max_literal: i64)
+                          -> Result<Vec<(Token, Range<usize>)>, LexerError> {
+    check_length(input, max_len)?;
+    let mut lexer = Lexer::with_grammar(input, grammar);
+    if let Some(max_digits) = max_digits {
+        lexer.set_max_digits(max_digits);
+    }
+    lexer.set_max_literal(max_literal);
+    let mut result: Vec<(Token, Range<usize>)> = vec![];
+
+    while let Some(next) = lexer.next_spanned() {
+        match next {
+            Ok(token) => result.push(token),
+            Err(mut e) => {
+                e.tokens = result.into_iter().map(|(token, _)| token).collect();
+                return Err(e);
             }
         }
     }
@@ -92,28 +291,665 @@ pub fn lex(input: &String) -> Result<Vec<Token>, LexerError> {
 }
 
 /*
- * Get a number from a token stream. NOTE: the generic is required to force
- * static dispatch with a type of unknown size. Using a `Box' would be an
- * alternative, too.
- * FIXME: Improve this function.
+ * A lexer that produces the tokens of `input' one at a time instead of
+ * collecting them, so a consumer can stop early or process the tokens while
+ * they are lexed. After the first error, it doesn't produce any more tokens.
+ * Unlike the errors of `lex', its errors don't include the preceding tokens
+ * (that would require to collect them after all).
  */
-fn get_number<T: Iterator<Item = char>>(c: char, iter: &mut Peekable<T>) -> i64 {
-    // parse the character that was already consumed and passed as `c'
-    let mut number = c.to_string()
-                      .parse::<i64>()
-                      .expect("Failed to parse `char' as `i64'");
+pub struct Lexer<'a> {
+    input: &'a str,
+    token_stream: Chars<'a>,
+    grammar: Grammar,
+    progress: usize,           /* tokens and whitespace lexed so far */
+    follows_operand: bool,     /* the previous token ends an operand, see `mod' */
+    max_digits: Option<usize>, /* see `set_max_digits' */
+    max_literal: i64,          /* see `set_max_literal' */
+    is_finished: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer::with_grammar(input, &Grammar::default())
+    }
+
+    pub fn with_grammar(input: &'a str, grammar: &Grammar) -> Lexer<'a> {
+        Lexer { input,
+                token_stream: input.chars(),
+                grammar: grammar.clone(),
+                progress: 0,
+                follows_operand: false,
+                max_digits: None,
+                max_literal: i64::MAX,
+                is_finished: false }
+    }
 
     /*
-     * Consume characters as long as parsing them to `i64' succeeds
-     * by only peeking without advancing the iterator, the next non-
-     * digit character.
-     *
-     * TODO: This could be implemented using `.position(|&c| c == ' ')'
-     * and `.take()' on the iterator to avoid peeking.
+     * Reject literals with more than `max_digits' digits (not counting an
+     * exponent like `e3'), e.g. a million `9's of untrusted input. The error
+     * is returned as soon as the limit is exceeded, so the value of such a
+     * literal is never computed.
      */
-    while let Some(Ok(digit)) = iter.peek().map(|c| c.to_string().parse::<i64>()) {
-        number = number * 10 + digit;
+    pub fn set_max_digits(&mut self, max_digits: usize) {
+        self.max_digits = Some(max_digits);
+    }
+
+    /*
+     * Reject literals greater than `max_literal', e.g. `3000000000' with
+     * `--int-width 32' (see `IntWidth::max_literal'). The error points at the
+     * literal instead of failing when the expression is evaluated.
+     */
+    pub fn set_max_literal(&mut self, max_literal: i64) {
+        self.max_literal = max_literal;
+    }
+
+    /* Like `next', but with the byte range of the token in the input. */
+    pub fn next_spanned(&mut self)
+                        -> Option<Result<(Token, Range<usize>), LexerError>> {
+        if self.is_finished {
+            return None;
+        }
+        let res = self.lex_token();
+        if let Some(Err(_)) | None = res {
+            self.is_finished = true;
+        }
+        res
+    }
+
+    fn lex_token(&mut self) -> Option<Result<(Token, Range<usize>), LexerError>> {
+        while let Some(c) = peek(&self.token_stream) {
+            self.progress += 1;
+            let start = self.get_offset();
+
+            let token = match c {
+                '0'..='9' => {
+                    self.token_stream.next();
+
+                    // pass the already consumed char and the stream to a fn
+                    // that parses the whole number
+                    match get_number(c,
+                                     &mut self.token_stream,
+                                     self.max_digits,
+                                     self.max_literal)
+                    {
+                        Ok(n) => Token::Number(n),
+                        Err(NumberError::Literal(msg)) => {
+                            return Some(Err(self.error(msg, start)))
+                        }
+                        Err(NumberError::Exponent(msg)) => {
+                            let offset = self.get_offset();
+                            return Some(Err(self.error(msg, offset)));
+                        }
+                    }
+                }
+                '(' => {
+                    self.token_stream.next();
+                    Token::LeftParen
+                }
+                ')' => {
+                    self.token_stream.next();
+                    Token::RightParen
+                }
+                // the doubled chars of `||' and `&&' are peeked at
+                '|' => {
+                    self.token_stream.next();
+                    if peek(&self.token_stream) == Some('|') {
+                        self.token_stream.next();
+                        Token::OpOr
+                    } else {
+                        Token::Bar
+                    }
+                }
+                '&' => {
+                    self.token_stream.next();
+                    if peek(&self.token_stream) != Some('&') {
+                        let msg = String::from("Expected `&&', found a single `&'");
+                        return Some(Err(self.error(msg, start)));
+                    }
+                    self.token_stream.next();
+                    Token::OpAnd
+                }
+                '!' => {
+                    self.token_stream.next();
+                    Token::OpNot
+                }
+                '~' => {
+                    self.token_stream.next();
+                    Token::Tilde
+                }
+                ',' => {
+                    self.token_stream.next();
+                    Token::Comma
+                }
+                '?' => {
+                    self.token_stream.next();
+                    Token::Question
+                }
+                ':' => {
+                    self.token_stream.next();
+                    Token::Colon
+                }
+                // a comment like in `1 + 2 # add them' runs to the end of the
+                // line (or of the input) and is skipped like whitespace
+                '#' => {
+                    for c in self.token_stream.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                // spaces, tabs and newlines (e.g. of a pasted multi-line
+                // expression) only separate tokens
+                c if c.is_whitespace() => {
+                    self.token_stream.next();
+                    continue;
+                }
+                '=' => {
+                    self.token_stream.next();
+                    Token::OpAssign
+                }
+                // the symbols of the grammar win over the Unicode aliases
+                _ => match self.grammar
+                               .match_token(self.token_stream.clone())
+                               .or_else(|| get_alias(c).map(|token| (token, 1)))
+                {
+                    Some((token, len)) => {
+                        self.token_stream.nth(len - 1);
+
+                        // peek for the `=' of a compound assignment like `+='
+                        match get_compound(&token) {
+                            Some(compound)
+                                if peek(&self.token_stream) == Some('=') =>
+                            {
+                                self.token_stream.next();
+                                compound
+                            }
+                            _ => token,
+                        }
+                    }
+                    // `7 mod 3' is `7 % 3', so `mod' is reserved and can only
+                    // follow an operand like a binary operator does
+                    None if c.is_ascii_alphabetic() || c == '_' => {
+                        match get_ident(&mut self.token_stream) {
+                            name if name == "mod" && !self.follows_operand => {
+                                let msg = String::from("`mod' is reserved for the modulo and can't name a variable or a function");
+                                return Some(Err(self.error(msg, start)));
+                            }
+                            name if name == "mod" => Token::OpMod,
+                            name => Token::Ident(name),
+                        }
+                    }
+                    None => {
+                        let msg = format!("Unexpected character `{}'", c);
+                        return Some(Err(self.error(msg, start)));
+                    }
+                },
+            };
+            // a `|' might close an absolute value and a `%' might be a
+            // percentage, so both could end an operand
+            self.follows_operand = matches!(token,
+                                            Token::Number(_)
+                                            | Token::Ident(_)
+                                            | Token::RightParen
+                                            | Token::Bar
+                                            | Token::OpMod);
+            return Some(Ok((token, start..self.get_offset())));
+        }
+        None
+    }
+
+    /* The number of bytes of the input that were consumed so far. */
+    fn get_offset(&self) -> usize {
+        self.input.len() - self.token_stream.as_str().len()
+    }
+
+    fn error(&self, msg: String, offset: usize) -> LexerError {
+        LexerError { msg,
+                     token_no: self.progress,
+                     tokens: vec![],
+                     offset }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token, LexerError>;
+
+    fn next(&mut self) -> Option<Result<Token, LexerError>> {
+        self.next_spanned().map(|res| res.map(|(token, _)| token))
+    }
+}
+
+/*
+ * Reject `input' if it is longer than `max_len' characters. The error points
+ * at the first character after the limit. A string has at least as many
+ * bytes as characters, so most input is accepted without counting them.
+ */
+fn check_length(input: &str, max_len: usize) -> Result<(), LexerError> {
+    if input.len() <= max_len {
+        return Ok(());
+    }
+    match input.char_indices().nth(max_len) {
+        Some((offset, _)) => {
+            Err(LexerError { msg:
+                                 format!("The input is longer than {} characters",
+                                         max_len),
+                             token_no: 0,
+                             tokens: vec![],
+                             offset })
+        }
+        None => Ok(()),
+    }
+}
+
+/*
+ * Reject anything but a single trailing newline after the last token of
+ * `input', i.e. trailing whitespace and a trailing comment like `1 # one'.
+ * `lex' itself skips both anywhere, so this check is an opt-in (see
+ * `--strict-eof'). The `tokens' lexed from `input' with the operators of
+ * `grammar' are passed through.
+ */
+pub fn check_eof(input: &str, grammar: &Grammar, tokens: Vec<Token>)
+                 -> Result<Vec<Token>, LexerError> {
+    let body = input.strip_suffix('\n').unwrap_or(input);
+    let end = match lex_spanned_with(body, grammar)?.last() {
+        Some((_, span)) => span.end,
+        None => 0,
+    };
+    let trailer = &body[end..];
+    let (msg, offset) = match trailer.find('#') {
+        Some(comment) => ("Unexpected trailing comment", end + comment),
+        None if !trailer.is_empty() => ("Unexpected trailing whitespace", end),
+        None => return Ok(tokens),
+    };
+    Err(LexerError { msg: String::from(msg),
+                     token_no: tokens.len() + 1,
+                     tokens,
+                     offset })
+}
+
+/*
+ * Why `get_number' rejected a literal. A literal that is invalid as a whole
+ * is reported at its start, missing digits of an exponent (`1e' or `1e+')
+ * where they are expected, i.e. right after the characters that were read.
+ */
+enum NumberError {
+    Literal(String),
+    Exponent(String),
+}
+
+/*
+ * Get a number from a token stream, optionally in scientific notation like
+ * `1e3' or `15E-1'. The exponent can be signed but the value must still be an
+ * integer, so `25e-1' is an error while `250e-1' is `25'. Errors describe why
+ * the literal is invalid, including a literal of more than `max_digits' digits
+ * or one that is greater than `max_literal'.
+ */
+fn get_number(c: char, iter: &mut Chars, max_digits: Option<usize>,
+              max_literal: i64)
+              -> Result<i64, NumberError> {
+    // parse the character that was already consumed and passed as `c' and
+    // all digits that follow it (the digits are consumed even on overflow)
+    let check_digits = |digits: usize| match max_digits {
+        Some(max_digits) if digits > max_digits => {
+            Err(NumberError::Literal(format!("Numeric literal has too many digits \
+                                              (at most {})",
+                                             max_digits)))
+        }
+        _ => Ok(()),
+    };
+    let check_max = |n: i64| {
+        if n > max_literal {
+            Err(NumberError::Literal(format!("Integer literal too large (at most {})",
+                                             max_literal)))
+        } else {
+            Ok(n)
+        }
+    };
+    let mut number = c.to_digit(10).map(|digit| digit as i64);
+    let mut digits = 1;
+    check_digits(digits)?;
+    while let Some(digit) = peek(iter).and_then(|c| c.to_digit(10)) {
+        digits += 1;
+        check_digits(digits)?;
+        number = number.and_then(|n| n.checked_mul(10))
+                       .and_then(|n| n.checked_add(digit as i64));
+        iter.next();
+    }
+
+    // an exponent suffix is optional, but it needs digits if it is present
+    if let Some('e') | Some('E') = peek(iter) {
         iter.next();
+        let is_negative = match peek(iter) {
+            Some(sign) if sign == '+' || sign == '-' => {
+                iter.next();
+                sign == '-'
+            }
+            _ => false,
+        };
+        if !peek(iter).is_some_and(|c| c.is_ascii_digit()) {
+            let msg = "Expected digits in the exponent of an integer literal";
+            return Err(NumberError::Exponent(String::from(msg)));
+        }
+        let mut exp: Option<u32> = Some(0);
+        while let Some(digit) = peek(iter).and_then(|c| c.to_digit(10)) {
+            exp = exp.and_then(|e| e.checked_mul(10))
+                     .and_then(|e| e.checked_add(digit));
+            iter.next();
+        }
+        let number = number.ok_or_else(too_large)?;
+        return apply_exponent(number, exp, is_negative).map_err(NumberError::Literal)
+                                                         .and_then(check_max);
+    }
+    number.ok_or_else(too_large).and_then(check_max)
+}
+
+fn too_large() -> NumberError {
+    NumberError::Literal(String::from("Integer literal too large"))
+}
+
+/*
+ * Get an identifier like `max' from a token stream. It starts with a letter
+ * or `_' and continues with letters, digits and `_'. Operator symbols aren't
+ * recognized inside of it, so a symbol like `x' doesn't split `max'.
+ */
+fn get_ident(iter: &mut Chars) -> String {
+    let mut ident = String::new();
+    while let Some(c) = peek(iter).filter(|&c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        ident.push(c);
+        iter.next();
+    }
+    ident
+}
+
+/*
+ * The operator of a Unicode symbol that is often pasted from documents:
+ * `×' is `*', `÷' is `/' and the minus sign `−' (U+2212) is `-'.
+ */
+fn get_alias(c: char) -> Option<Token> {
+    match c {
+        '×' => Some(Token::OpMult),
+        '÷' => Some(Token::OpDiv),
+        '\u{2212}' => Some(Token::OpSub),
+        _ => None,
+    }
+}
+
+/* The compound assignment of an operator that is followed by `=', if any. */
+fn get_compound(token: &Token) -> Option<Token> {
+    match token {
+        Token::OpAdd => Some(Token::OpAddAssign),
+        Token::OpSub => Some(Token::OpSubAssign),
+        Token::OpMult => Some(Token::OpMultAssign),
+        Token::OpDiv => Some(Token::OpDivAssign),
+        _ => None,
+    }
+}
+
+/* The next character of `iter' without consuming it. */
+fn peek(iter: &Chars) -> Option<char> {
+    iter.clone().next()
+}
+
+/* Scale `number' by 10 to the power of `exp' (`None' if that's too large). */
+fn apply_exponent(number: i64, exp: Option<u32>, is_negative: bool)
+                  -> Result<i64, String> {
+    if number == 0 {
+        return Ok(0);
+    }
+    let scale = exp.and_then(|exp| 10_i64.checked_pow(exp));
+    match (scale, is_negative) {
+        (Some(scale), false) => {
+            number.checked_mul(scale)
+                  .ok_or_else(|| String::from("Integer literal too large"))
+        }
+        (Some(scale), true) if number % scale == 0 => Ok(number / scale),
+        (_, true) => Err(String::from("Integer literal has a fractional value")),
+        (None, false) => Err(String::from("Integer literal too large")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn tabs_and_newlines_are_whitespace() {
+        let expected = vec![Token::Number(1), Token::OpAdd, Token::Number(2)];
+        assert_eq!(lex("1 + 2").unwrap(), expected);
+        assert_eq!(lex("1\t+\n2").unwrap(), expected);
+        assert_eq!(lex("\r\n 1\t\t+ \n\n2 \t").unwrap(), expected);
+    }
+
+    #[test]
+    fn overflowing_literals_are_rejected() {
+        assert_eq!(lex("9223372036854775807").unwrap(),
+                   vec![Token::Number(i64::MAX)]);
+        let err = lex("223^11-99999999999999999999").unwrap_err();
+        assert_eq!(err.msg, "Integer literal too large");
+        assert_eq!(err.tokens,
+                   vec![Token::Number(223),
+                        Token::OpExp,
+                        Token::Number(11),
+                        Token::OpSub]);
+        assert_eq!(lex("9223372036854775808").unwrap_err().msg,
+                   "Integer literal too large");
+    }
+
+    #[test]
+    fn tokens_are_displayed_as_they_are_written() {
+        let tokens = [(Token::OpUshr, ">>>"),
+                      (Token::OpMultAssign, "*="),
+                      (Token::OpOr, "||"),
+                      (Token::Tilde, "~"),
+                      (Token::RightParen, ")"),
+                      (Token::Ident("max".to_string()), "max"),
+                      (Token::Number(-12), "-12")];
+        for (token, symbol) in tokens.iter() {
+            assert_eq!(token.to_string(), *symbol);
+        }
+        // the default symbols, whatever the grammar is
+        assert_eq!(Token::OpXor.to_string(), "^");
+        assert_eq!(Token::OpExp.to_string(), "^");
+    }
+
+    #[test]
+    fn literals_can_have_an_exponent() {
+        assert_eq!(lex("1e3").unwrap(), vec![Token::Number(1000)]);
+        assert_eq!(lex("1E3").unwrap(), vec![Token::Number(1000)]);
+        assert_eq!(lex("15E-1").unwrap_err().msg,
+                   "Integer literal has a fractional value");
+        assert_eq!(lex("250e-1").unwrap(), vec![Token::Number(25)]);
+        assert_eq!(lex("0e99").unwrap(), vec![Token::Number(0)]);
+        assert_eq!(lex("1e19").unwrap_err().msg, "Integer literal too large");
+    }
+
+    #[test]
+    fn missing_exponent_digits_are_reported_where_they_belong() {
+        let msg = "Expected digits in the exponent of an integer literal";
+        for (input, offset) in
+            [("1e", 2), ("1e+", 3), ("223^11-5e", 9), ("2e-x", 3)].iter()
+        {
+            let err = lex(input).unwrap_err();
+            assert_eq!(err.msg, msg);
+            assert_eq!(err.offset, *offset, "{}", input);
+        }
+        // a literal that is invalid as a whole is reported at its start
+        assert_eq!(lex("1 + 25e-1").unwrap_err().offset, 4);
+    }
+
+    #[test]
+    fn spans_are_byte_ranges_of_the_input() {
+        assert_eq!(lex_spanned("12 + 3").unwrap(),
+                   vec![(Token::Number(12), 0..2),
+                        (Token::OpAdd, 3..4),
+                        (Token::Number(3), 5..6)]);
+        // `×' takes two bytes, so the `3' starts at byte 3
+        assert_eq!(lex_spanned("2×3").unwrap(),
+                   vec![(Token::Number(2), 0..1),
+                        (Token::OpMult, 1..3),
+                        (Token::Number(3), 3..4)]);
+        assert_eq!(lex_spanned(" (1)").unwrap(),
+                   vec![(Token::LeftParen, 1..2),
+                        (Token::Number(1), 2..3),
+                        (Token::RightParen, 3..4)]);
+    }
+
+    #[test]
+    fn the_iterator_stops_after_an_error() {
+        let mut lexer = Lexer::new("1 + @ 2");
+        assert_eq!(lexer.next().unwrap().unwrap(), Token::Number(1));
+        assert_eq!(lexer.next().unwrap().unwrap(), Token::OpAdd);
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(err.msg, "Unexpected character `@'");
+        assert_eq!(err.offset, 4);
+        assert!(lexer.next().is_none());
+        assert!(lexer.next().is_none());
+
+        let tokens: Vec<Result<Token, LexerError>> = Lexer::new("2 3").collect();
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn non_ascii_numerals_are_unexpected_characters() {
+        let err = lex("2^²").unwrap_err();
+        assert_eq!(err.msg, "Unexpected character `²'");
+        assert_eq!(err.tokens, vec![Token::Number(2), Token::OpExp]);
+        assert_eq!(err.offset, 2);
+        assert_eq!(lex("٣").unwrap_err().msg, "Unexpected character `٣'");
+        assert_eq!(lex("1\u{FFFD}").unwrap_err().msg,
+                   "Unexpected character `\u{FFFD}'");
+    }
+
+    #[test]
+    fn input_beyond_the_limit_is_rejected() {
+        let grammar = Grammar::default();
+        assert!(lex_with_limit("1+2", &grammar, 3).is_ok());
+        let err = lex_with_limit("1+23", &grammar, 3).unwrap_err();
+        assert_eq!(err.msg, "The input is longer than 3 characters");
+        assert_eq!((err.token_no, err.offset), (0, 3));
+        assert!(err.tokens.is_empty());
+        // the limit counts characters, the offset is in bytes
+        assert!(lex_with_limit("2×3", &grammar, 3).is_ok());
+        assert_eq!(lex_with_limit("2×3×4", &grammar, 3).unwrap_err().offset, 4);
+
+        let longest = format!("1{}", " ".repeat(DEFAULT_MAX_INPUT_LEN - 1));
+        assert_eq!(lex(&longest).unwrap(), vec![Token::Number(1)]);
+        let err = lex(&format!("{} ", longest)).unwrap_err();
+        assert_eq!(err.msg, "The input is longer than 1000000 characters");
+        assert_eq!(err.offset, DEFAULT_MAX_INPUT_LEN);
+    }
+
+    #[test]
+    fn comments_produce_no_tokens() {
+        assert_eq!(lex("# just a comment").unwrap(), vec![]);
+        assert_eq!(lex("1 + 2 # add them").unwrap(), lex("1 + 2").unwrap());
+        assert_eq!(lex("1 +# 3 *\n2").unwrap(), lex("1 + 2").unwrap());
+        assert_eq!(lex("1 + 2 # an @ is fine here").unwrap(),
+                   lex("1 + 2").unwrap());
+    }
+
+    #[test]
+    fn strict_eof_rejects_trailing_whitespace_and_comments() {
+        let grammar = Grammar::default();
+        let strict = |input: &str| check_eof(input, &grammar, lex(input).unwrap());
+        assert_eq!(strict("2+3").unwrap(), lex("2+3").unwrap());
+        assert_eq!(strict("2+3\n").unwrap(), lex("2+3").unwrap());
+        assert_eq!(strict(" 2 + 3").unwrap(), lex("2+3").unwrap());
+
+        let err = strict("2+3   ").unwrap_err();
+        assert_eq!(err.msg, "Unexpected trailing whitespace");
+        assert_eq!((err.token_no, err.offset), (4, 3));
+        assert_eq!(err.tokens, lex("2+3").unwrap());
+        assert_eq!(strict("2+3\n\n").unwrap_err().msg,
+                   "Unexpected trailing whitespace");
+        let err = strict("1 # c").unwrap_err();
+        assert_eq!(err.msg, "Unexpected trailing comment");
+        assert_eq!(err.offset, 2);
+        assert_eq!(strict("1#c\n").unwrap_err().msg,
+                   "Unexpected trailing comment");
+    }
+
+    #[test]
+    fn mod_is_a_reserved_modulo() {
+        assert_eq!(lex("7 mod 3").unwrap(), lex("7 % 3").unwrap());
+        assert_eq!(lex("x mod(3)").unwrap(),
+                   vec![Token::Ident("x".to_string()),
+                        Token::OpMod,
+                        Token::LeftParen,
+                        Token::Number(3),
+                        Token::RightParen]);
+        let err = lex("mod = 3").unwrap_err();
+        assert!(err.msg.starts_with("`mod' is reserved"), "{}", err.msg);
+        assert_eq!(err.offset, 0);
+        assert!(lex("1 + mod").is_err());
+    }
+
+    #[test]
+    fn literals_can_be_limited_in_their_digits() {
+        let grammar = Grammar::default();
+        let limited = |input: &str, max| {
+            lex_with_literal_limits(input, &grammar, max, i64::MAX)
+        };
+        assert_eq!(limited("12345 + 1", Some(5)).unwrap(),
+                   vec![Token::Number(12345), Token::OpAdd, Token::Number(1)]);
+        let err = limited("1+123456", Some(5)).unwrap_err();
+        assert_eq!(err.msg, "Numeric literal has too many digits (at most 5)");
+        assert_eq!(err.token_no, 3);
+        assert_eq!(err.offset, 2);
+        let nines = "9".repeat(100000);
+        assert!(limited(&nines, Some(20)).unwrap_err()
+                                         .msg
+                                         .contains("too many digits"));
+        assert_eq!(limited("123456789012345678901", None).unwrap_err().msg,
+                   "Integer literal too large");
+    }
+
+    #[test]
+    fn unicode_operators_are_aliases() {
+        assert_eq!(lex("2 × 3").unwrap(), lex("2 * 3").unwrap());
+        assert_eq!(lex("6 ÷ 2").unwrap(), lex("6 / 2").unwrap());
+        assert_eq!(lex("5 − 1").unwrap(), lex("5 - 1").unwrap());
+        assert_eq!(lex("−1×2").unwrap(), lex("-1*2").unwrap());
+    }
+
+    #[test]
+    fn written_tokens_lex_to_the_same_tokens() {
+        let inputs = ["1+2*3",
+                      "-(-2)^-3",
+                      "2^3^2",
+                      "max(1,-2)%3",
+                      "|2-|3-5||",
+                      "||-1|-2|",
+                      "x+=7 mod 3",
+                      "y=c?50%:1e3",
+                      "!a||~b&&1<<2>>>1",
+                      "200+10%-5"];
+        for input in inputs.iter() {
+            let tokens = lex(input).unwrap();
+            let output = tokens_to_string(&tokens);
+            assert_eq!(lex(&output).unwrap(), tokens, "{} → {}", input, output);
+            assert_eq!(tokens_to_string(&lex(&output).unwrap()), output);
+        }
+        assert_eq!(tokens_to_string(&lex("(1+2)*-3").unwrap()), "(1 + 2) * -3");
+        assert_eq!(tokens_to_string(&[]), "");
+    }
+
+    #[test]
+    fn merged_tokens_are_spaced() {
+        let pairs =
+            [(vec![Token::Bar, Token::Bar], "| |"),
+             (vec![Token::Bar, Token::OpOr], "| ||"),
+             (vec![Token::OpShr, Token::OpShr], ">> >>"),
+             (vec![Token::OpAdd, Token::OpAssign], "+ ="),
+             (vec![Token::Number(1), Token::Ident(String::from("e3"))], "1 e3"),
+             (vec![Token::Ident(String::from("x")), Token::Number(2)], "x 2"),
+             (vec![Token::Number(-3)], "-3")];
+        for (tokens, output) in pairs.iter() {
+            assert_eq!(tokens_to_string(tokens), *output);
+        }
     }
-    number
 }
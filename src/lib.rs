@@ -0,0 +1,42 @@
+/* lib.rs: The lexer, parser and evaluator, which `main.rs' builds on. */
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
+pub mod grammar;
+pub mod lexer;
+pub mod parser;
+pub mod rational;
+pub mod vm;
+
+// the graphs and the command line need files, processes and `clap'
+#[cfg(feature = "std")]
+pub mod draw;
+#[cfg(feature = "std")]
+pub mod repl;
+#[cfg(feature = "std")]
+pub mod utils;
+
+/*
+ * Without `std', only the modules above are built. This checks that they
+ * still lex, parse and evaluate, see `cargo test --no-default-features'.
+ */
+#[cfg(all(test, not(feature = "std")))]
+mod tests {
+    use crate::lexer::lex;
+    use crate::parser::{parse, parse_str};
+    use crate::rational::Rational;
+    use crate::vm::{evaluate, evaluate_rational, Interpreter, Value};
+
+    #[test]
+    fn expressions_evaluate_without_std() {
+        let tokens = lex("(18-6)/4*5+2^3");
+        assert_eq!(tokens.as_ref().unwrap().len(), 13);
+        let ast = parse(tokens).unwrap();
+        assert_eq!(evaluate(&ast).unwrap(), 23);
+        assert_eq!(evaluate_rational(&parse_str("1/3+1/6").unwrap()).unwrap(),
+                   Rational::new(1, 2).unwrap());
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(interpreter.eval("x = 7"), Ok(None)));
+        assert!(matches!(interpreter.eval("x * 6"), Ok(Some(Value::Integer(42)))));
+    }
+}
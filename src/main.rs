@@ -3,61 +3,284 @@
  * Date:    04/26/2019
  * License: MIT
  *          (see LICENSE.md at https://github.com/DanielSchuette/expr_parser)
- * TODO: correct the mult-div parsing error!
  */
 #![allow(dead_code)]
 #![allow(unused_imports)]
-mod draw;
-mod lexer;
-mod parser;
-mod utils;
-mod vm;
-
-use lexer::lex;
-use parser::parse;
+use expr_parser::{lexer, parser, repl, utils, vm};
+use lexer::{check_eof, lex_with_literal_limits};
+use parser::{parse_recovering_with, parse_with, ParseNode};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::process::exit;
-use utils::{exit_with_err, get_configs};
+use std::time::Instant;
+use utils::{
+    exit_with_err, exit_with_lexer_err, format_tokens, format_value, get_configs,
+    print_info, red, report_eval_err_pos, report_parser_err, report_times, Config,
+};
 
 fn main() {
     let configs = get_configs();
 
+    if configs.info {
+        print_info(&configs);
+        exit(0);
+    }
+
+    if let Some(path) = &configs.file {
+        exit(evaluate_file(path, &configs));
+    }
+
     // if the user provided an expression via `-e', lex & parse and
     // evaluate it manually; return afterwards
-    if !configs.expression.is_empty() {
-        /*
-         * FIXME: handle errors inbetween lexing and parsing instead of
-         * delegating things to `parse'. This involves calling `exit_with_err'
-         * when appropriate.
-         */
-        let tokens = lex(&configs.expression);
-        let res = parse(tokens);
+    if configs.has_expression {
+        // with `--result-as-bool-exit', the exit code mirrors shell truthiness
+        // (like `test', a nonzero result is true and exits with 0), so errors
+        // need a code of their own to not be mistaken for a false result
+        let err_code = if configs.bool_exit { 2 } else { 1 };
+        let mut out = open_output(&configs, err_code);
+
+        let start = Instant::now();
+        let mut tokens = lex_with_literal_limits(&configs.expression,
+                                                 &configs.grammar,
+                                                 configs.max_digits,
+                                                 configs.int_width.max_literal());
+        if configs.strict_eof {
+            tokens = tokens.and_then(|t| {
+                               check_eof(&configs.expression, &configs.grammar, t)
+                           });
+        }
+        let mut times = vec![("lex", start.elapsed())];
+
+        // lexer errors are reported right away since they know their exact
+        // position in the input, unlike the errors of `parse'
+        if let Err(e) = &tokens {
+            report_times(&configs, &times);
+            exit_with_lexer_err(e,
+                                &configs.expression,
+                                err_code,
+                                configs.color_errors);
+        }
+        // an input of only whitespace and comments has no tokens, which would
+        // be an unexpected end of input for the parser
+        if let Ok(tokens) = &tokens {
+            if tokens.is_empty() {
+                report_times(&configs, &times);
+                eprintln!("{}: error: Empty expression", configs.progname);
+                exit(err_code);
+            }
+        }
+        if configs.is_debug {
+            // the tokens are `Ok' since lexer errors exited above
+            let tokens = tokens.as_deref().unwrap_or_default();
+            eprintln!("{}: tokens: {}", configs.progname, format_tokens(tokens));
+        }
+
+        let start = Instant::now();
+        let res = parse_with(tokens, &configs.grammar, configs.max_depth);
+        times.push(("parse", start.elapsed()));
 
         if let Ok(ast) = res {
-            if configs.is_debug {
+            // `--dump-ast' prints the tree without the rest of `--debug'
+            if configs.is_debug || configs.dump_ast {
                 eprintln!("{}: {:#?}", configs.progname, ast);
             }
+            if configs.is_debug {
+                eprintln!("{}: {} nodes, height {}",
+                          configs.progname,
+                          ast.node_count(),
+                          ast.height());
+            }
+            let ast = if configs.optimize {
+                let start = Instant::now();
+                let ast = optimize(ast, &configs);
+                times.push(("optimize", start.elapsed()));
+                ast
+            } else {
+                ast
+            };
+            // after `--optimize', so the folded tree is shown
+            if configs.show_precedence {
+                for line in ast.describe().lines() {
+                    eprintln!("{}: {}", configs.progname, line);
+                }
+            }
             if configs.make_graph {
-                utils::draw(&ast, &configs.graph_file, true);
+                utils::draw(&ast, &configs, !configs.no_pdf);
             }
 
-        /*
-        let res = vm::evaluate(&ast);
+            // output modes print the expression instead of its result
+            let output = if configs.latex {
+                Some(ast.to_latex())
+            } else if configs.prefix {
+                Some(ast.to_prefix_string())
+            } else if configs.rpn {
+                Some(ast.to_rpn_string())
+            } else {
+                None
+            };
+            if let Some(output) = output {
+                report_times(&configs, &times);
+                write_result(&mut out, &output, &configs);
+                exit(0);
+            }
 
-        // TODO: clean this code up
-        if let Ok(res) = res {
-            eprintln!("{}: Expression result = {}.", configs.progname, res);
-        } else if let Err(e) = res {
-            eprintln!("{}: error: {}", configs.progname, e);
-        }
-        */
+            if configs.is_explain {
+                for step in ast.explain(&configs.vars) {
+                    eprintln!("{}: {}", configs.progname, step);
+                }
+            }
+
+            let start = Instant::now();
+            let res = vm::evaluate_value(&ast, &configs);
+            times.push(("eval", start.elapsed()));
+            report_times(&configs, &times);
+
+            match res {
+                Ok(res) if configs.bool_exit => {
+                    exit(if res.is_zero() { 1 } else { 0 })
+                }
+                Ok(res) => {
+                    write_result(&mut out, &format_value(&res, &configs), &configs)
+                }
+                Err(e) => {
+                    let msg = format!("{}: error: {}", configs.progname, e);
+                    eprintln!("{}", red(&msg, configs.color_errors));
+                    report_eval_err_pos(&e,
+                                        &configs.expression,
+                                        &configs.grammar,
+                                        configs.color_errors);
+                    exit(err_code);
+                }
+            }
         } else if let Err(e) = res {
-            exit_with_err(e, &configs.expression, 1);
+            report_times(&configs, &times);
+            exit_with_err(e,
+                          &configs.expression,
+                          &configs.grammar,
+                          err_code,
+                          configs.color_errors);
         }
 
         exit(0);
     }
 
     // delegate IO, lexing & parsing, and evaluation of a resulting AST to the
-    // virtual machine
-    vm::run(&configs);
+    // interactive mode
+    repl::run(&configs);
+}
+
+/*
+ * Evaluate every non-empty line of the file at `path' as an expression and
+ * print the results. Errors are reported with their line number and don't
+ * stop the evaluation of the following lines. Returns the exit code, which is
+ * 1 if the file can't be read or if any line failed.
+ */
+fn evaluate_file(path: &str, configs: &Config) -> i32 {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{}: error: Cannot read expression file `{}': {}",
+                      configs.progname, path, e);
+            return 1;
+        }
+    };
+
+    let mut out = open_output(configs, 1);
+    let mut code = 0;
+    for (i, line) in content.lines().enumerate() {
+        // blank lines and lines with nothing but a comment are skipped
+        let tokens = lex_with_literal_limits(line,
+                                             &configs.grammar,
+                                             configs.max_digits,
+                                             configs.int_width.max_literal());
+        if let Ok(tokens) = &tokens {
+            if tokens.is_empty() {
+                continue;
+            }
+        }
+        // all syntax errors of a line are reported, not only the first one
+        match parse_recovering_with(tokens, &configs.grammar, configs.max_depth) {
+            (Some(ast), _) => {
+                match vm::evaluate_value(&optimize(ast, configs), configs) {
+                    Ok(res) => {
+                        write_result(&mut out, &format_value(&res, configs), configs)
+                    }
+                    Err(e) => {
+                        let msg = format!("{}: {}:{}: error: {}",
+                                          configs.progname,
+                                          path,
+                                          i + 1,
+                                          e);
+                        eprintln!("{}", red(&msg, configs.color_errors));
+                        report_eval_err_pos(&e,
+                                            line,
+                                            &configs.grammar,
+                                            configs.color_errors);
+                        code = 1;
+                    }
+                }
+            }
+            (None, errors) => {
+                for e in errors {
+                    let msg =
+                        format!("{}: {}:{}: error:", configs.progname, path, i + 1);
+                    eprintln!("{}", red(&msg, configs.color_errors));
+                    report_parser_err(e,
+                                      &line.to_string(),
+                                      &configs.grammar,
+                                      configs.color_errors);
+                }
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/*
+ * Fold the constant subtrees of `ast' if `--optimize' is given (see
+ * `ParseNode::simplify'), e.g. `2*3 + x' turns into `6 + x'. With `--debug',
+ * the number of nodes before and after is printed.
+ */
+fn optimize(ast: ParseNode, configs: &Config) -> ParseNode {
+    if !configs.optimize {
+        return ast;
+    }
+    let before = ast.node_count();
+    let ast = ast.simplify();
+    if configs.is_debug {
+        eprintln!("{}: optimized: {} -> {} nodes",
+                  configs.progname,
+                  before,
+                  ast.node_count());
+    }
+    ast
+}
+
+/*
+ * Open where the results are written: the file of `--output', which is
+ * truncated if it exists, or stdout. Exits with `code' if the file can't be
+ * created, before anything is evaluated.
+ */
+fn open_output(configs: &Config, code: i32) -> Box<dyn Write> {
+    match &configs.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("{}: error: Cannot create output file `{}': {}",
+                          configs.progname, path, e);
+                exit(code);
+            }
+        },
+        None => Box::new(io::stdout()),
+    }
+}
+
+/* Write a result as a line of `out', a failed write exits with code 1. */
+fn write_result(out: &mut impl Write, result: &str, configs: &Config) {
+    if let Err(e) = writeln!(out, "{}", result) {
+        eprintln!("{}: error: Cannot write the result: {}",
+                  configs.progname, e);
+        exit(1);
+    }
 }
@@ -1,44 +1,124 @@
 /* parser.rs: The expression parser. Creates an abstract syntax tree. */
+use crate::grammar::{get_terminal, Grammar, MAX_LEVEL};
 use crate::lexer;
+use crate::vm::EvalError;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
 use lexer::*;
 
-#[derive(Debug)]
-enum NonTerminal {
-    Expression, /* precedence 1 */
-    Term,       /* precedence 2 */
-    Factor,     /* precedence 3 */
-    Exponent,   /* precedence 4 (highest) */
+/// The rule of the grammar that a `ParseNode` was derived from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonTerminal {
+    Conditional, /* precedence 0 (lowest), i.e. `c ? a : b' */
+    Disjunction, /* precedence 1, i.e. `||' */
+    Conjunction, /* precedence 2, i.e. `&&' */
+    Bitwise,     /* precedence 3, e.g. `<<' or `^' with `--c-operators' */
+    Expression,  /* precedence 4 */
+    Term,        /* precedence 5 */
+    Factor,      /* precedence 6 */
+    Exponent,    /* precedence 7 (highest) */
 }
 
-#[derive(Debug)]
-pub enum Terminal {
-    Sum,          /* summation */
-    Sub,          /* subtraction */
-    Mod,          /* modulo */
-    Mult,         /* multiplication */
-    Div,          /* divison */
-    Exp,          /* exponentiation */
-    Paren,        /* parenthesis */
-    Literal(i64), /* literals are stored with their associated values */
+impl NonTerminal {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            NonTerminal::Conditional => "Conditional",
+            NonTerminal::Disjunction => "Disjunction",
+            NonTerminal::Conjunction => "Conjunction",
+            NonTerminal::Bitwise => "Bitwise",
+            NonTerminal::Expression => "Expression",
+            NonTerminal::Term => "Term",
+            NonTerminal::Factor => "Factor",
+            NonTerminal::Exponent => "Exponent",
+        }
+    }
+}
 
-    NonTerminal, /* type of non-terminal parse nodes */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminal {
+    Sum,                 /* summation */
+    Sub,                 /* subtraction */
+    Mod,                 /* modulo */
+    Mult,                /* multiplication */
+    Div,                 /* divison */
+    Exp,                 /* exponentiation */
+    Xor,                 /* bitwise exclusive or */
+    Shl,                 /* left shift */
+    Shr,                 /* arithmetic (sign-extending) right shift */
+    Ushr,                /* logical (zero-filling) right shift */
+    And,                 /* logical and, `a && b' */
+    Or,                  /* logical or, `a || b' */
+    Not,                 /* logical negation, `!a' */
+    BitNot,              /* bitwise not, `~a' is `-a-1' */
+    Cond,                /* a conditional, `c ? a : b' is `Cond'(c, `Else'(a, b)) */
+    Else,                /* joins the two branches of a `Cond' */
+    Percent,             /* percentage, `x%' is `x/100' */
+    AddPercent,          /* `a + b%', i.e. `a + a*b/100' */
+    SubPercent,          /* `a - b%', i.e. `a - a*b/100' */
+    Neg,                 /* negation (unary minus) */
+    Abs,                 /* absolute value, `|x|' */
+    Call(String, usize), /* a function and its number of arguments */
+    Arg,                 /* joins two arguments of a call, see `get_args' */
+    Paren,               /* parenthesis */
+    Var(String),         /* a variable or a named constant like `pi' */
+    Literal(i64),        /* literals are stored with their associated values */
 }
 
-#[derive(Debug)]
-enum NodeType {
+/// Whether a `ParseNode` has children (`Branch`) or not (`Leaf`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeType {
     Branch,
     Leaf,
 }
 
-/// An expression is parsed into a `ParseNode`.
-#[derive(Debug)]
+/// An expression is parsed into a `ParseNode`. All of its fields can be read,
+/// so other code can walk the tree, e.g. to count the literals:
+///
+/// ```
+/// use expr_parser::parser::{parse_str, NodeType, ParseNode, Terminal};
+///
+/// fn count_literals(node: &ParseNode) -> usize {
+///     match (node.node_type(), &node.terminal) {
+///         (NodeType::Leaf, Terminal::Literal(_)) => 1,
+///         (NodeType::Leaf, _) => 0,
+///         (NodeType::Branch, _) => {
+///             node.children().map(count_literals).sum()
+///         }
+///     }
+/// }
+///
+/// let ast = parse_str("(1+2)*3").unwrap();
+/// assert_eq!(count_literals(&ast), 3);
+/// ```
+#[derive(Debug, Clone)]
 pub struct ParseNode {
     left_child: Option<Box<ParseNode>>, /* `None' for terminals */
-    right_child: Option<Box<ParseNode>>, /* `None' for terminals & parens */
+    right_child: Option<Box<ParseNode>>, /* `None' for terminals, parens & `-x' */
     ntype: NodeType,                    /* type of this node in the AST */
     pub terminal: Terminal,             /* the terminal type of this node */
     non_terminal: NonTerminal,          /* `IsTerminal' for terminals */
     depth: usize,                       /* depth of this node (leaf=0) */
+    token: Option<usize>,               /* the token of a binary operator */
+}
+
+/*
+ * Two trees are equal if they have the same structure, terminals and
+ * non-terminals. The `depth' is ignored: it follows from the structure, so
+ * it can't differ between equal trees anyway, and comparing it would only
+ * make hand-built trees (e.g. the expected tree in a test) brittle. The
+ * `token' is ignored, too: the `*' of `1*2' and of `x+1*2' are equal
+ * subtrees although they were parsed from different tokens.
+ */
+impl PartialEq for ParseNode {
+    fn eq(&self, other: &ParseNode) -> bool {
+        self.ntype == other.ntype
+        && self.terminal == other.terminal
+        && self.non_terminal == other.non_terminal
+        && self.left_child == other.left_child
+        && self.right_child == other.right_child
+    }
 }
 
 impl ParseNode {
@@ -50,7 +130,8 @@ impl ParseNode {
                     ntype,
                     terminal,
                     non_terminal,
-                    depth }
+                    depth,
+                    token: None }
     }
     pub fn get_lchild(&self) -> &Option<Box<ParseNode>> {
         &self.left_child
@@ -60,55 +141,751 @@ impl ParseNode {
         &self.right_child
     }
 
+    /// The children of the node from left to right, i.e. none for a leaf,
+    /// one for parentheses or a unary operator like `-x` and two for a binary
+    /// operator.
+    ///
+    /// ```
+    /// use expr_parser::parser::parse_str;
+    ///
+    /// let ast = parse_str("(1+2)*-3").unwrap();
+    /// assert_eq!(ast.children().count(), 2);
+    /// // the parentheses and the `-' have one child each
+    /// let counts: Vec<usize> = ast.children().map(|c| c.children().count()).collect();
+    /// assert_eq!(counts, vec![1, 1]);
+    /// ```
+    pub fn children(&self) -> impl Iterator<Item = &ParseNode> {
+        self.left_child
+            .as_deref()
+            .into_iter()
+            .chain(self.right_child.as_deref())
+    }
+
+    /*
+     * The index of the token that a binary operator was parsed from, e.g. to
+     * tell which operator of an expression overflowed. Other nodes don't
+     * have a token.
+     */
+    pub fn get_token(&self) -> Option<usize> {
+        self.token
+    }
+
     pub fn get_long_type(&self) -> String {
         match self.terminal {
             Terminal::Literal(n) => format!("Literal={}", n),
-            Terminal::Sum => format!("Op=PLUS"),
-            Terminal::Sub => format!("Op=MINUS"),
-            Terminal::Mod => format!("Op=MODULP"),
-            Terminal::Mult => format!("Op=MULTIPLICATION"),
-            Terminal::Div => format!("Op=DIVISON"),
-            Terminal::Exp => format!("Op=EXPONENTIATION"),
-            Terminal::Paren => format!("Parentheses"),
-            Terminal::NonTerminal => format!("Non-Terminal"),
+            Terminal::Sum => "Op=PLUS".to_string(),
+            Terminal::Sub => "Op=MINUS".to_string(),
+            Terminal::Mod => "Op=MODULP".to_string(),
+            Terminal::Mult => "Op=MULTIPLICATION".to_string(),
+            Terminal::Div => "Op=DIVISON".to_string(),
+            Terminal::Exp => "Op=EXPONENTIATION".to_string(),
+            Terminal::Xor => "Op=XOR".to_string(),
+            Terminal::Shl => "Op=SHIFT_LEFT".to_string(),
+            Terminal::Shr => "Op=SHIFT_RIGHT".to_string(),
+            Terminal::Ushr => "Op=LOGICAL_SHIFT_RIGHT".to_string(),
+            Terminal::Percent => "Op=PERCENT".to_string(),
+            Terminal::AddPercent => "Op=PLUS_PERCENT".to_string(),
+            Terminal::SubPercent => "Op=MINUS_PERCENT".to_string(),
+            Terminal::And => "Op=AND".to_string(),
+            Terminal::Or => "Op=OR".to_string(),
+            Terminal::Not => "Op=NOT".to_string(),
+            Terminal::BitNot => "Op=BIT_NOT".to_string(),
+            Terminal::Cond => "Op=CONDITIONAL".to_string(),
+            Terminal::Else => "Branches".to_string(),
+            Terminal::Neg => "Op=NEGATION".to_string(),
+            Terminal::Abs => "Op=ABSOLUTE".to_string(),
+            Terminal::Call(ref name, argc) => format!("Call={}/{}", name, argc),
+            Terminal::Arg => "Arguments".to_string(),
+            Terminal::Var(ref name) => format!("Var={}", name),
+            Terminal::Paren => "Parentheses".to_string(),
         }
     }
 
     pub fn get_short_type(&self) -> String {
         match self.terminal {
             Terminal::Literal(n) => format!("{}", n),
-            Terminal::Sum => format!("+"),
-            Terminal::Sub => format!("-"),
-            Terminal::Mod => format!("%"),
-            Terminal::Mult => format!("*"),
-            Terminal::Div => format!("/"),
-            Terminal::Exp => format!("^"),
-            Terminal::Paren => format!("(...)"),
-            Terminal::NonTerminal => format!("@"),
+            Terminal::Sum => "+".to_string(),
+            Terminal::Sub => "-".to_string(),
+            Terminal::Mod => "%".to_string(),
+            Terminal::Mult => "*".to_string(),
+            Terminal::Div => "/".to_string(),
+            Terminal::Exp => "^".to_string(),
+            Terminal::Xor => "xor".to_string(),
+            Terminal::Shl => "<<".to_string(),
+            Terminal::Shr => ">>".to_string(),
+            Terminal::Ushr => ">>>".to_string(),
+            Terminal::Percent => "%".to_string(),
+            Terminal::AddPercent => "+%".to_string(),
+            Terminal::SubPercent => "-%".to_string(),
+            Terminal::And => "&&".to_string(),
+            Terminal::Or => "||".to_string(),
+            Terminal::Not => "!".to_string(),
+            Terminal::BitNot => "~".to_string(),
+            Terminal::Cond => "?".to_string(),
+            Terminal::Else => ":".to_string(),
+            Terminal::Neg => "-".to_string(),
+            Terminal::Abs => "|...|".to_string(),
+            Terminal::Call(ref name, _) => format!("{}(...)", name),
+            Terminal::Arg => ",".to_string(),
+            Terminal::Var(ref name) => name.clone(),
+            Terminal::Paren => "(...)".to_string(),
         }
     }
 
-    #[allow(dead_code)]
+    pub fn node_type(&self) -> NodeType {
+        self.ntype
+    }
+
+    pub fn non_terminal(&self) -> NonTerminal {
+        self.non_terminal
+    }
+
     pub fn get_non_terminal_type(&self) -> String {
-        match self.non_terminal {
-            NonTerminal::Expression => format!("Expression"),
-            NonTerminal::Term => format!("Term"),
-            NonTerminal::Factor => format!("Factor"),
-            NonTerminal::Exponent => format!("Exponent"),
-        }
+        self.non_terminal.get_name().to_string()
     }
 
     pub fn get_depth(&self) -> usize {
         self.depth
     }
+
+    /*
+     * The number of nodes in the tree, e.g. 5 for `2+3*4'. Like the parser,
+     * this walks the tree with an explicit stack, so deeply nested trees
+     * don't exhaust the call stack.
+     */
+    pub fn node_count(&self) -> usize {
+        let mut count = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            count += 1;
+            stack.extend(node.children());
+        }
+        count
+    }
+
+    /*
+     * The number of edges on the longest path to a leaf, e.g. 2 for `2+3*4'
+     * and 0 for a literal. This recomputes `get_depth' from the children.
+     */
+    pub fn height(&self) -> usize {
+        let mut height = 0;
+        let mut stack = vec![(self, 0)];
+        while let Some((node, level)) = stack.pop() {
+            height = height.max(level);
+            for child in node.get_lchild().iter().chain(node.get_rchild()) {
+                stack.push((child, level + 1));
+            }
+        }
+        height
+    }
+
+    /*
+     * The arguments of a function call. A call has no children without
+     * arguments, a single argument as its child and otherwise a chain of
+     * `Arg' nodes: `max(1,2,3)' is `Call' -> `Arg'(1, `Arg'(2, 3)). So the
+     * arguments are evaluated from left to right like any other operands.
+     */
+    pub fn get_args(&self) -> Vec<&ParseNode> {
+        let mut args = vec![];
+        let mut next = self.left_child.as_deref();
+        while let Some(node) = next {
+            match (&node.terminal, &node.left_child, &node.right_child) {
+                (Terminal::Arg, Some(arg), rest) => {
+                    args.push(arg.as_ref());
+                    next = rest.as_deref();
+                }
+                _ => {
+                    args.push(node);
+                    next = None;
+                }
+            }
+        }
+        args
+    }
+
+    /*
+     * Render the tree as LaTeX math, e.g. `\frac{1+2}{3}' for `(1+2)/3'.
+     * Parentheses are kept as `\left( ... \right)' unless the braces of a
+     * fraction or an exponent already group their content.
+     */
+    pub fn to_latex(&self) -> String {
+        match (&self.terminal, &self.left_child, &self.right_child) {
+            (Terminal::Literal(n), _, _) => n.to_string(),
+            (Terminal::Var(name), _, _) if name == "pi" => "\\pi".to_string(),
+            (Terminal::Paren, Some(child), _) => {
+                format!("\\left({}\\right)", child.to_latex())
+            }
+            (Terminal::Neg, Some(child), _) => format!("-{}", child.to_latex()),
+            (Terminal::Not, Some(child), _) => {
+                format!("\\lnot {}", child.to_latex())
+            }
+            (Terminal::BitNot, Some(child), _) => {
+                format!("\\sim {}", child.to_latex())
+            }
+            (Terminal::Percent, Some(child), _) => {
+                format!("{}\\%", child.to_latex())
+            }
+            (Terminal::AddPercent, Some(lhs), Some(rhs)) => {
+                format!("{}+{}\\%", lhs.to_latex(), rhs.to_latex())
+            }
+            (Terminal::SubPercent, Some(lhs), Some(rhs)) => {
+                format!("{}-{}\\%", lhs.to_latex(), rhs.to_latex())
+            }
+            (Terminal::Abs, Some(child), _) => {
+                format!("\\left|{}\\right|", child.to_latex_grouped())
+            }
+            (Terminal::Call(name, _), _, _) => {
+                let args: Vec<String> = self.get_args()
+                                            .iter()
+                                            .map(|arg| arg.to_latex_grouped())
+                                            .collect();
+                format!("\\operatorname{{{}}}\\left({}\\right)",
+                        name,
+                        args.join(", "))
+            }
+            (Terminal::Div, Some(lhs), Some(rhs)) => {
+                format!("\\frac{{{}}}{{{}}}",
+                        lhs.to_latex_grouped(),
+                        rhs.to_latex_grouped())
+            }
+            (Terminal::Exp, Some(lhs), Some(rhs)) => {
+                format!("{}^{{{}}}", lhs.to_latex(), rhs.to_latex_grouped())
+            }
+            (op, Some(lhs), Some(rhs)) => {
+                let symbol = match op {
+                    Terminal::Mult => " \\cdot ".to_string(),
+                    Terminal::Mod => " \\bmod ".to_string(),
+                    Terminal::Xor => " \\oplus ".to_string(),
+                    Terminal::Shl => " \\ll ".to_string(),
+                    Terminal::Shr => " \\gg ".to_string(),
+                    Terminal::Ushr => " \\ggg ".to_string(),
+                    Terminal::And => " \\land ".to_string(),
+                    Terminal::Or => " \\lor ".to_string(),
+                    Terminal::Cond => " ? ".to_string(),
+                    Terminal::Else => " : ".to_string(),
+                    _ => self.get_short_type(),
+                };
+                format!("{}{}{}", lhs.to_latex(), symbol, rhs.to_latex())
+            }
+            _ => self.get_short_type(),
+        }
+    }
+
+    /* The precedence level of this node's non-terminal (higher binds tighter). */
+    pub fn get_precedence(&self) -> u8 {
+        match self.non_terminal {
+            NonTerminal::Conditional => 0,
+            NonTerminal::Disjunction => 1,
+            NonTerminal::Conjunction => 2,
+            NonTerminal::Bitwise => 3,
+            NonTerminal::Expression => 4,
+            NonTerminal::Term => 5,
+            NonTerminal::Factor => 6,
+            NonTerminal::Exponent => 7,
+        }
+    }
+
+    /// A compact view of the tree for debugging (see `--show-precedence`).
+    /// Every node is a line with its symbol, non-terminal, precedence level
+    /// and depth, and children are indented below their parent:
+    ///
+    /// ```
+    /// use expr_parser::parser::parse_str;
+    ///
+    /// let ast = parse_str("2+3*4").unwrap();
+    /// assert_eq!(ast.describe(),
+    ///            "+ (Expression, prec 4, depth 2)\n\
+    ///             \x20 2 (Exponent, prec 7, depth 0)\n\
+    ///             \x20 * (Term, prec 5, depth 1)\n\
+    ///             \x20   3 (Exponent, prec 7, depth 0)\n\
+    ///             \x20   4 (Exponent, prec 7, depth 0)");
+    /// ```
+    pub fn describe(&self) -> String {
+        let mut lines = vec![];
+        let mut stack = vec![(self, 0)];
+        while let Some((node, indent)) = stack.pop() {
+            lines.push(format!("{:indent$}{} ({}, prec {}, depth {})",
+                               "",
+                               node.get_short_type(),
+                               node.non_terminal.get_name(),
+                               node.get_precedence(),
+                               node.depth,
+                               indent = indent));
+            // pushed in reverse, so the left child is printed first
+            let children: Vec<&ParseNode> = node.children().collect();
+            stack.extend(children.into_iter()
+                                 .rev()
+                                 .map(|child| (child, indent + 2)));
+        }
+        lines.join("\n")
+    }
+
+    /*
+     * Reconstruct a canonical infix string from the tree, e.g. `2 + 3 * 4'.
+     * The parentheses of the input are dropped and only inserted again where
+     * precedence or associativity require them, so `((2))+(3*4)' also turns
+     * into `2 + 3 * 4' while `(2+3)*4' stays `(2 + 3) * 4'.
+     */
+    pub fn to_infix_string(&self) -> String {
+        let node = self.strip_parens();
+        match (&node.terminal, &node.left_child, &node.right_child) {
+            (Terminal::Neg, Some(child), None)
+            | (Terminal::Not, Some(child), None)
+            | (Terminal::BitNot, Some(child), None) => {
+                let child = child.strip_parens();
+                if child.get_precedence() < node.get_precedence() {
+                    format!("{}({})", node.get_short_type(), child.to_infix_string())
+                } else {
+                    format!("{}{}", node.get_short_type(), child.to_infix_string())
+                }
+            }
+            (Terminal::Abs, Some(child), None) => {
+                format!("|{}|", child.to_infix_string())
+            }
+            (Terminal::Percent, Some(child), None) => child.to_infix_percent(),
+            (Terminal::Call(name, _), _, _) => {
+                let args: Vec<String> = node.get_args()
+                                            .iter()
+                                            .map(|arg| arg.to_infix_string())
+                                            .collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            (Terminal::AddPercent, Some(lhs), Some(rhs))
+            | (Terminal::SubPercent, Some(lhs), Some(rhs)) => {
+                // like a `+' or `-' whose right operand is a percentage
+                let lhs = lhs.strip_parens();
+                let wrap_lhs = lhs.get_precedence() < node.get_precedence();
+                let symbol = match node.terminal {
+                    Terminal::AddPercent => "+",
+                    _ => "-",
+                };
+                format!("{} {} {}",
+                        lhs.to_infix_operand(wrap_lhs),
+                        symbol,
+                        rhs.to_infix_percent())
+            }
+            (op, Some(lhs), Some(rhs)) => {
+                let (lhs, rhs) = (lhs.strip_parens(), rhs.strip_parens());
+                let right_assoc =
+                    matches!(op, Terminal::Exp | Terminal::Cond | Terminal::Else);
+
+                // an operand of lower precedence always needs parentheses,
+                // one of the same precedence only on the side against which
+                // the operator associates, e.g. `1 - (2 - 3)'
+                let precedence = node.get_precedence();
+                let wrap_lhs = lhs.get_precedence() < precedence
+                               || (right_assoc
+                                   && lhs.get_precedence() == precedence);
+                let wrap_rhs = rhs.get_precedence() < precedence
+                               || (!right_assoc
+                                   && rhs.get_precedence() == precedence);
+                format!("{} {} {}",
+                        lhs.to_infix_operand(wrap_lhs),
+                        node.get_short_type(),
+                        rhs.to_infix_operand(wrap_rhs))
+            }
+            _ => node.get_short_type(),
+        }
+    }
+
+    /*
+     * Render the tree in prefix (Polish) notation, e.g. `(+ 2 (* 3 4))' for
+     * `2+3*4'. Every operator is applied to its operands in a pair of
+     * parentheses, so the input's own parentheses are transparent.
+     */
+    pub fn to_prefix_string(&self) -> String {
+        let node = self.strip_parens();
+        match (&node.terminal, &node.left_child, &node.right_child) {
+            (Terminal::Cond, Some(cond), Some(branches)) => {
+                match (&branches.left_child, &branches.right_child) {
+                    (Some(then), Some(other)) => format!("(if {} {} {})",
+                                                         cond.to_prefix_string(),
+                                                         then.to_prefix_string(),
+                                                         other.to_prefix_string()),
+                    _ => node.get_short_type(),
+                }
+            }
+            (Terminal::Call(name, _), _, _) => {
+                let args: Vec<String> = node.get_args()
+                                            .iter()
+                                            .map(|arg| arg.to_prefix_string())
+                                            .collect();
+                format!("({})",
+                        [name.clone()].iter()
+                                      .chain(&args)
+                                      .cloned()
+                                      .collect::<Vec<_>>()
+                                      .join(" "))
+            }
+            (_, Some(lhs), Some(rhs)) => format!("({} {} {})",
+                                                 node.get_short_type(),
+                                                 lhs.to_prefix_string(),
+                                                 rhs.to_prefix_string()),
+            (Terminal::Abs, Some(child), None) => {
+                format!("(abs {})", child.to_prefix_string())
+            }
+            (_, Some(child), None) => {
+                format!("({} {})", node.get_short_type(), child.to_prefix_string())
+            }
+            _ => node.get_short_type(),
+        }
+    }
+
+    /*
+     * Render the tree in postfix notation (reverse Polish notation), e.g.
+     * `2 3 4 * +' for `2+3*4'. This is the order in which `vm::evaluate' pops
+     * its execution stack. Parentheses are transparent, a negation is written
+     * as `neg' (so it can't be mistaken for a subtraction), an absolute
+     * value as `abs' and a percentage as `percent'. A function call is
+     * written with its number of arguments, e.g. `3 1 2 max:3', and a
+     * conditional `c ? a : b' as `c a b if'.
+     */
+    pub fn to_rpn_string(&self) -> String {
+        let node = self.strip_parens();
+        match (&node.terminal, &node.left_child, &node.right_child) {
+            (Terminal::Else, Some(then), Some(other)) => {
+                format!("{} {}", then.to_rpn_string(), other.to_rpn_string())
+            }
+            (Terminal::Cond, Some(cond), Some(branches)) => {
+                format!("{} {} if", cond.to_rpn_string(), branches.to_rpn_string())
+            }
+            (Terminal::Call(name, argc), _, _) => {
+                let mut args: Vec<String> = node.get_args()
+                                                .iter()
+                                                .map(|arg| arg.to_rpn_string())
+                                                .collect();
+                args.push(format!("{}:{}", name, argc));
+                args.join(" ")
+            }
+            (_, Some(lhs), Some(rhs)) => format!("{} {} {}",
+                                                 lhs.to_rpn_string(),
+                                                 rhs.to_rpn_string(),
+                                                 node.get_short_type()),
+            (Terminal::Neg, Some(child), None) => {
+                format!("{} neg", child.to_rpn_string())
+            }
+            (Terminal::Not, Some(child), None) => {
+                format!("{} not", child.to_rpn_string())
+            }
+            (Terminal::BitNot, Some(child), None) => {
+                format!("{} bitnot", child.to_rpn_string())
+            }
+            (Terminal::Abs, Some(child), None) => {
+                format!("{} abs", child.to_rpn_string())
+            }
+            (Terminal::Percent, Some(child), None) => {
+                format!("{} percent", child.to_rpn_string())
+            }
+            _ => node.get_short_type(),
+        }
+    }
+
+    /* Render this node followed by `%', e.g. `10%' or `(1 + 2)%'. */
+    fn to_infix_percent(&self) -> String {
+        let node = self.strip_parens();
+
+        // only literals and groups (`Exponent') don't need parentheses
+        let wrap = node.non_terminal != NonTerminal::Exponent;
+        format!("{}%", node.to_infix_operand(wrap))
+    }
+
+    fn to_infix_operand(&self, wrap: bool) -> String {
+        if wrap {
+            format!("({})", self.to_infix_string())
+        } else {
+            self.to_infix_string()
+        }
+    }
+
+    /* Skip over any parentheses nodes, which only group their single child. */
+    pub fn strip_parens(&self) -> &ParseNode {
+        match (&self.terminal, &self.left_child) {
+            (Terminal::Paren, Some(child)) => child.strip_parens(),
+            _ => self,
+        }
+    }
+
+    /* Like `to_latex', but without the outermost pair of parentheses. */
+    fn to_latex_grouped(&self) -> String {
+        match (&self.terminal, &self.left_child) {
+            (Terminal::Paren, Some(child)) => child.to_latex_grouped(),
+            _ => self.to_latex(),
+        }
+    }
+
+    /*
+     * Fold every constant subtree into a single literal, e.g. `2+3*4' turns
+     * into `14'. Subtrees that can't be evaluated (like `1/0') are kept, so
+     * their error is still reported when the simplified tree is evaluated.
+     * Operators whose other operand is the identity are replaced by that
+     * operand (see `take_identity_operand'), so `x*1+0' turns into `x'.
+     * NOTE: folding uses the integer arithmetic of `eval', so the simplified
+     * tree must not be evaluated with `--rational'.
+     */
+    pub fn simplify(mut self) -> ParseNode {
+        if let NodeType::Leaf = self.ntype {
+            return self;
+        }
+        self.left_child = self.left_child.map(|child| Box::new(child.simplify()));
+        self.right_child = self.right_child.map(|child| Box::new(child.simplify()));
+
+        match self.eval() {
+            Ok(n) => ParseNode::new(NodeType::Leaf,
+                                    Terminal::Literal(n),
+                                    NonTerminal::Exponent,
+                                    0),
+            Err(_) => {
+                // the children were simplified already, so their depths are
+                // up to date
+                if let Some(operand) = self.take_identity_operand() {
+                    return operand;
+                }
+                self.update_depth();
+                self
+            }
+        }
+    }
+
+    /*
+     * If this node applies an identity, i.e. it is `x+0', `0+x', `x-0', `x*1',
+     * `1*x', `x/1' or `x^1', take the operand `x' out of it. These hold in
+     * every evaluation mode, unlike e.g. `x*0', which would drop the error of
+     * an `x' like `1/0'.
+     */
+    fn take_identity_operand(&mut self) -> Option<ParseNode> {
+        let is_literal = |child: &Option<Box<ParseNode>>, n: i64| {
+            matches!(child.as_ref().map(|child| &child.terminal),
+                     Some(Terminal::Literal(m)) if *m == n)
+        };
+        let (keep_left, keep_right) =
+            match self.terminal {
+                Terminal::Sum => (is_literal(&self.right_child, 0),
+                                  is_literal(&self.left_child, 0)),
+                Terminal::Mult => (is_literal(&self.right_child, 1),
+                                   is_literal(&self.left_child, 1)),
+                Terminal::Sub => (is_literal(&self.right_child, 0), false),
+                Terminal::Div | Terminal::Exp => {
+                    (is_literal(&self.right_child, 1), false)
+                }
+                _ => (false, false),
+            };
+        if keep_left {
+            self.left_child.take().map(|child| *child)
+        } else if keep_right {
+            self.right_child.take().map(|child| *child)
+        } else {
+            None
+        }
+    }
+
+    /// Reset the depth of every node after a transformation of the tree, so
+    /// that leaves are 0 and every other node is one more than its deepest
+    /// child. `simplify` keeps the depths up to date by itself:
+    ///
+    /// ```
+    /// use expr_parser::parser::parse_str;
+    ///
+    /// let ast = parse_str("(1+2)*x").unwrap();
+    /// assert_eq!(ast.get_depth(), 3);
+    /// let mut folded = ast.simplify();
+    /// assert_eq!(folded.get_depth(), 1);
+    /// folded.recompute_depths();
+    /// assert_eq!(folded.get_depth(), folded.height());
+    /// ```
+    pub fn recompute_depths(&mut self) {
+        if let Some(child) = &mut self.left_child {
+            child.recompute_depths();
+        }
+        if let Some(child) = &mut self.right_child {
+            child.recompute_depths();
+        }
+        self.update_depth();
+    }
+
+    /* Set the `depth' of this node from the depths of its children. */
+    fn update_depth(&mut self) {
+        self.depth = self.children()
+                         .map(|child| child.depth + 1)
+                         .max()
+                         .unwrap_or(0);
+    }
+
+    /*
+     * The steps of evaluating the tree for `--explain', e.g. `2 + 3 * 4',
+     * `2 + 12' and `14'. Every step reduces the leftmost operator whose
+     * operands are values to its result, and the tree is recorded with
+     * `to_infix_string' after each step. The left operand of `&&', `||' and
+     * `?' is reduced first and decides if the rest is reduced at all, so
+     * `0 && 1/0' is `0' like in `eval'. The steps end before a step that
+     * fails, evaluating the tree then reports the error. Like `simplify',
+     * this uses the integer arithmetic of `eval'. A variable is replaced by
+     * its value in `vars' when it is the leftmost operand, e.g. `x + 1' and
+     * `3 + 1' with `x=3'.
+     */
+    pub fn explain(&self, vars: &[(String, i64)]) -> Vec<String> {
+        let mut node = self.clone();
+        let mut steps = vec![node.to_infix_string()];
+        while !node.is_value() && node.reduce_step(vars).is_ok() {
+            // a reduced operator is a leaf now, so its parents are shallower
+            node.recompute_depths();
+            let step = node.to_infix_string();
+            // e.g. removing parentheses doesn't change the infix form
+            if steps.last() != Some(&step) {
+                steps.push(step);
+            }
+        }
+        steps
+    }
+
+    /* Reduce the leftmost operator whose operands are values, see `explain'. */
+    fn reduce_step(&mut self, vars: &[(String, i64)]) -> Result<(), EvalError> {
+        if let Terminal::Var(name) = &self.terminal {
+            if let Some((_, n)) = vars.iter().find(|(var, _)| var == name) {
+                *self = ParseNode::new_value(*n);
+                return Ok(());
+            }
+        }
+        if let Some(lhs) = &mut self.left_child {
+            if !lhs.is_reduced() {
+                return lhs.reduce_step(vars);
+            }
+        }
+        // the right operand is skipped if the left one decides the result,
+        // the branches of a conditional are only reduced once one is picked
+        let decided = match (&self.terminal, &self.left_child) {
+            (Terminal::And, Some(lhs)) => lhs.eval()? == 0,
+            (Terminal::Or, Some(lhs)) => lhs.eval()? != 0,
+            (Terminal::Cond, _) => true,
+            _ => false,
+        };
+        if let Some(rhs) = &mut self.right_child {
+            if !decided && !rhs.is_reduced() {
+                return rhs.reduce_step(vars);
+            }
+        }
+
+        // a conditional is replaced by the branch that its condition picks
+        if let Terminal::Cond = self.terminal {
+            let cond = self.left_child.as_ref().map(|cond| cond.eval());
+            let branches = self.right_child.take().map(|branches| *branches);
+            return match (cond, branches) {
+                (Some(cond),
+                 Some(ParseNode { left_child: Some(then),
+                                  right_child: Some(other),
+                                  .. })) => {
+                    *self = if cond? != 0 { *then } else { *other };
+                    Ok(())
+                }
+                _ => Err(EvalError::MalformedTree(String::from("Expected a condition and two branches"))),
+            };
+        }
+        *self = ParseNode::new_value(self.eval()?);
+        Ok(())
+    }
+
+    /*
+     * Check if the node is a value, i.e. a literal or a negated literal. The
+     * arguments of a call are reduced if all of them are values.
+     */
+    fn is_reduced(&self) -> bool {
+        match self.terminal {
+            Terminal::Arg => self.children().all(|child| child.is_reduced()),
+            _ => self.is_value(),
+        }
+    }
+
+    fn is_value(&self) -> bool {
+        match (&self.terminal, &self.left_child) {
+            (Terminal::Literal(_), _) => true,
+            (Terminal::Neg, Some(child)) => {
+                matches!(child.terminal, Terminal::Literal(_))
+            }
+            _ => false,
+        }
+    }
+
+    /*
+     * The result of a step. A negative result is a negated literal, so that
+     * `to_infix_string' puts it in parentheses where needed, e.g. `(-2)^2'.
+     */
+    fn new_value(n: i64) -> ParseNode {
+        let literal = |n| {
+            ParseNode::new(NodeType::Leaf,
+                           Terminal::Literal(n),
+                           NonTerminal::Exponent,
+                           0)
+        };
+        match n.checked_neg() {
+            Some(abs) if n < 0 => ParseNode::new_unary(Terminal::Neg,
+                                                       NonTerminal::Factor,
+                                                       literal(abs)),
+            _ => literal(n),
+        }
+    }
+
+    /* Create a conditional `cond ? then : other', see `Terminal::Cond'. */
+    fn new_conditional(cond: ParseNode, then: ParseNode, other: ParseNode)
+                       -> ParseNode {
+        let branches = ParseNode::new_binary(Terminal::Else,
+                                             NonTerminal::Conditional,
+                                             then,
+                                             other);
+        ParseNode::new_binary(Terminal::Cond,
+                              NonTerminal::Conditional,
+                              cond,
+                              branches)
+    }
+
+    /* Create a branch with an operator and its two operands. */
+    fn new_binary(terminal: Terminal, non_terminal: NonTerminal, lhs: ParseNode,
+                  rhs: ParseNode)
+                  -> ParseNode {
+        let depth = lhs.depth.max(rhs.depth) + 1;
+        let mut node =
+            ParseNode::new(NodeType::Branch, terminal, non_terminal, depth);
+        node.left_child = Some(Box::new(lhs));
+        node.right_child = Some(Box::new(rhs));
+        node
+    }
+
+    /* Create a call of the function `name', see `get_args'. */
+    fn new_call(name: String, mut args: Vec<ParseNode>) -> ParseNode {
+        let argc = args.len();
+        let mut node = ParseNode::new(NodeType::Branch,
+                                      Terminal::Call(name, argc),
+                                      NonTerminal::Exponent,
+                                      0);
+        if let Some(mut chain) = args.pop() {
+            while let Some(arg) = args.pop() {
+                chain = ParseNode::new_binary(Terminal::Arg,
+                                              NonTerminal::Exponent,
+                                              arg,
+                                              chain);
+            }
+            node.depth = chain.depth + 1;
+            node.left_child = Some(Box::new(chain));
+        }
+        node
+    }
+
+    /* Create a branch with a single child, i.e. parentheses or `-x'. */
+    fn new_unary(terminal: Terminal, non_terminal: NonTerminal, child: ParseNode)
+                 -> ParseNode {
+        let depth = child.depth + 1;
+        let mut node =
+            ParseNode::new(NodeType::Branch, terminal, non_terminal, depth);
+        node.left_child = Some(Box::new(child));
+        node
+    }
 }
 
 /// A generic error type that is used by the parser and holds a message and the
 /// token at which the error occured.
+#[derive(Debug)]
 pub struct ParserError {
     pub msg: String,
     pub token_no: usize,
-    pub lexer: Vec<Token>, /* inherited from the lexer, see below */
+    pub lexer: Vec<Token>, /* the tokens before the error, see `report_parser_err' */
 }
 
 impl ParserError {
@@ -119,6 +896,25 @@ impl ParserError {
     }
 }
 
+/*
+ * An assignment `name = ...' or a compound assignment like `name += ...',
+ * whose `op' (e.g. `Sum') combines the old value with the assigned one.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub op: Option<Terminal>,
+}
+
+/* Errors of the lexer are passed on with the tokens up to the error. */
+impl From<LexerError> for ParserError {
+    fn from(e: LexerError) -> ParserError {
+        ParserError { msg: e.msg,
+                      token_no: e.token_no,
+                      lexer: e.tokens }
+    }
+}
+
 struct TokenStream {
     tokens: Vec<Token>,
     cursor: usize,
@@ -126,7 +922,7 @@ struct TokenStream {
 
 /// A `TokenStream` wraps a vector of tokens and a `cursor` which indicates the
 /// current position of the parser. Out-of-bounds errors are not allowed
-/// because the cursor can only be advanced if there are tokens left.
+/// because `get_current` returns `None` once all tokens are consumed.
 impl TokenStream {
     fn new(tokens: Vec<Token>) -> TokenStream {
         TokenStream { tokens, cursor: 0 }
@@ -136,190 +932,1133 @@ impl TokenStream {
         self.cursor
     }
 
-    fn get_current(&self) -> Token {
-        self.tokens[self.cursor].clone()
+    fn get_current(&self) -> Option<Token> {
+        self.tokens.get(self.cursor).cloned()
+    }
+
+    /* The token after the current one. */
+    fn get_next(&self) -> Option<Token> {
+        self.tokens.get(self.cursor + 1).cloned()
+    }
+
+    /*
+     * Turn the current `||' into the two `|' that it was lexed from, e.g. to
+     * close two absolute values in `|2-|3-5||'.
+     */
+    fn split_or(&mut self) {
+        self.tokens[self.cursor] = Token::Bar;
+        self.tokens.insert(self.cursor + 1, Token::Bar);
     }
 
     fn advance(&mut self, step: usize) {
-        if (self.cursor + step) < self.tokens.len() {
-            self.cursor += step;
-        }
+        self.cursor = (self.cursor + step).min(self.tokens.len());
     }
 
     fn is_finished(&self) -> bool {
-        if self.tokens.len() == (self.cursor + 1) {
-            true
-        } else {
-            false
-        }
+        self.cursor == self.tokens.len()
     }
 
     fn get_stream(&self) -> Vec<Token> {
         self.tokens.clone()
     }
+
+    /* The tokens before position `pos', e.g. to point at the token `pos'. */
+    fn get_stream_until(&self, pos: usize) -> Vec<Token> {
+        self.tokens[..pos].to_vec()
+    }
 }
 
+/*
+ * The parser itself doesn't recurse, but evaluating and drawing a tree does,
+ * so trees deeper than this are rejected (see `--max-depth').
+ */
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
 pub fn parse(tokens: Result<Vec<Token>, LexerError>)
              -> Result<ParseNode, ParserError> {
-    if let Ok(tokens) = tokens {
-        let mut stream = TokenStream::new(tokens);
-        let ast = expression(&mut stream, 0)?;
+    parse_with(tokens, &Grammar::default(), DEFAULT_MAX_DEPTH)
+}
 
-        // check if all tokens were consumed
-        if stream.is_finished() {
-            return Ok(ast);
-        } else {
-            return Err(ParserError::new(format!("Expected end of input, found {:?}", stream.get_current()),
-                                  stream.get_position(), stream.get_stream()));
-        }
+/*
+ * Lex and parse `input' in one go. Errors of the lexer are returned as a
+ * `ParserError', too, so `parse_str("1 + @")' fails like `parse_str("1 +")'.
+ */
+pub fn parse_str(input: &str) -> Result<ParseNode, ParserError> {
+    parse(lex(input))
+}
+
+/*
+ * Like `parse', but precedence and associativity are looked up in `grammar'
+ * and the tree may be at most `max_depth' nodes deep.
+ */
+pub fn parse_with(tokens: Result<Vec<Token>, LexerError>, grammar: &Grammar,
+                  max_depth: usize)
+                  -> Result<ParseNode, ParserError> {
+    if let Ok(tokens) = tokens {
+        parse_to_end(&mut TokenStream::new(tokens), grammar, max_depth)
     } else if let Err(e) = tokens {
-        Err(ParserError { msg: e.msg,
-                          token_no: e.token_no,
-                          lexer: e.tokens })
+        Err(ParserError::from(e))
     } else {
         Err(ParserError::new("Unknown error".to_string(), 0, vec![]))
     }
 }
 
-fn expression(mut stream: &mut TokenStream, depth: usize)
-              -> Result<ParseNode, ParserError> {
-    let mut node = ParseNode::new(NodeType::Branch,
-                                  Terminal::NonTerminal,
-                                  NonTerminal::Expression,
-                                  depth);
-    let lchild = term(&mut stream, depth)?;
-    node.left_child = Some(Box::new(lchild));
-    let mut depth = depth + 1;
+/// Like `parse`, but the parsing goes on after an error to find more of
+/// them, e.g. to report every problem of an expression file at once. The
+/// tree is only returned if there are no errors at all.
+///
+/// The recovery is a guess: the tokens from an error up to the next binary
+/// operator (or `,` or `:`) are skipped and the rest is parsed like an
+/// expression of its own. So an error after a skip can be a follow-up of
+/// the first one, e.g. the `)` of a group whose `(` was skipped, and two
+/// errors between the same pair of operators are reported as one. A lexer
+/// error ends the parsing since no tokens follow it.
+///
+/// ```
+/// use expr_parser::lexer::lex;
+/// use expr_parser::parser::parse_recovering;
+///
+/// let (ast, errors) = parse_recovering(lex("(1 +) * (2 3)"));
+/// assert!(ast.is_none());
+/// let positions: Vec<usize> = errors.iter().map(|e| e.token_no).collect();
+/// assert_eq!(positions, vec![3, 7]);
+/// ```
+pub fn parse_recovering(tokens: Result<Vec<Token>, LexerError>)
+                        -> (Option<ParseNode>, Vec<ParserError>) {
+    parse_recovering_with(tokens, &Grammar::default(), DEFAULT_MAX_DEPTH)
+}
+
+/* Like `parse_recovering', but with a `grammar' like `parse_with'. */
+pub fn parse_recovering_with(tokens: Result<Vec<Token>, LexerError>,
+                             grammar: &Grammar, max_depth: usize)
+                             -> (Option<ParseNode>, Vec<ParserError>) {
+    let tokens = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => return (None, vec![ParserError::from(e)]),
+    };
 
-    // one or more term(s) can follow the appropriate token
+    let mut errors: Vec<ParserError> = vec![];
+    let mut start = 0;
     loop {
-        let token = stream.get_current();
-        match token {
-            Token::OpAdd | Token::OpSub | Token::OpMod => {
-                stream.advance(1);
-                let rchild = term(&mut stream, depth)?;
-                if let None = node.right_child {
-                    node.right_child = Some(Box::new(rchild));
-                } else if let Some(ref mut current) = node.right_child {
-                    let mut parent = current;
-                    loop {
-                        if let None = parent.left_child {
-                            parent.left_child = Some(Box::new(rchild));
-                            break;
-                        } else if let Some(ref mut new) = parent.left_child {
-                            parent = new;
-                        }
-                    }
-                }
-                depth += 1;
-            }
+        // the rest is parsed in the entire input, so the positions of its
+        // errors (and the ones in their messages) count all tokens
+        let mut stream = TokenStream::new(tokens.clone());
+        stream.advance(start);
+        let e = match parse_to_end(&mut stream, grammar, max_depth) {
+            Ok(ast) if errors.is_empty() => return (Some(ast), errors),
+            Ok(_) => return (None, errors),
+            Err(e) => e,
+        };
 
-            // cursor is already advanced, break out of loop
-            _ => {
-                break;
-            }
+        let next = tokens.iter()
+                         .enumerate()
+                         .skip(e.token_no)
+                         .find(|(_, token)| {
+                             get_operator_info(token, grammar).is_some()
+                             || matches!(token, Token::Comma | Token::Colon)
+                         })
+                         .map(|(i, _)| i + 1);
+        errors.push(e);
+        match next {
+            Some(next) if next < tokens.len() => start = next,
+            _ => return (None, errors),
         }
     }
-    Ok(node)
 }
 
-fn term(mut stream: &mut TokenStream, depth: usize)
-        -> Result<ParseNode, ParserError> {
-    let mut node = ParseNode::new(NodeType::Branch,
-                                  Terminal::NonTerminal,
-                                  NonTerminal::Term,
-                                  depth);
-    let lchild = factor(&mut stream, depth)?;
-    node.left_child = Some(Box::new(lchild));
-    let mut depth = depth + 1;
+/*
+ * Like `parse_with', but the expression may be preceded by any number of
+ * assignments like in `x = y += 3'. Assignments are right-associative, so
+ * they are returned in the order of the input and must be applied from the
+ * last to the first, i.e. `y' is incremented by 3 and then `x' is assigned.
+ */
+pub fn parse_statement_with(tokens: Result<Vec<Token>, LexerError>,
+                            grammar: &Grammar, max_depth: usize)
+                            -> Result<(Vec<Assignment>, ParseNode), ParserError> {
+    let mut stream = TokenStream::new(tokens?);
+
+    let mut assignments = vec![];
+    while let (Some(Token::Ident(name)), Some(next)) =
+        (stream.get_current(), stream.get_next())
+    {
+        let op = match next {
+            Token::OpAssign => None,
+            Token::OpAddAssign => Some(Terminal::Sum),
+            Token::OpSubAssign => Some(Terminal::Sub),
+            Token::OpMultAssign => Some(Terminal::Mult),
+            Token::OpDivAssign => Some(Terminal::Div),
+            _ => break,
+        };
+        assignments.push(Assignment { name, op });
+        stream.advance(2);
+    }
+
+    let ast = parse_to_end(&mut stream, grammar, max_depth)?;
+    Ok((assignments, ast))
+}
+
+/* Parse an expression and check that it is all that's left of `stream'. */
+fn parse_to_end(stream: &mut TokenStream, grammar: &Grammar, max_depth: usize)
+                -> Result<ParseNode, ParserError> {
+    let ast = parse_expression(stream, grammar, max_depth)?;
+
+    // check if all tokens were consumed
+    if stream.is_finished() {
+        Ok(ast)
+    } else {
+        Err(trailing_tokens(stream, grammar))
+    }
+}
+
+/*
+ * An operator whose operand is still being parsed. `parse_expression' keeps
+ * them on an explicit stack instead of recursing, so deeply nested input
+ * can't overflow the call stack.
+ */
+enum Pending {
+    Paren(usize), /* a `(' that wasn't closed yet, at that token */
+    Bar(usize),   /* a `|' that wasn't closed yet, at that token */
+    Call(String, usize, Vec<ParseNode>), /* a call, its token and the arguments so far */
+    Neg,                                 /* a unary `-' */
+    Not,                                 /* a unary `!' */
+    BitNot,                              /* a unary `~' */
+    Binary(ParseNode, Terminal, u8, usize), /* the LHS, the operator, its level and token */
+    Question(ParseNode, usize), /* the condition of a `?' and its token, before the `:' */
+    Cond(ParseNode, ParseNode), /* the condition and the first branch, after the `:' */
+}
+
+/*
+ * This is a Pratt (precedence climbing) parser that reads the tokens from left
+ * to right. Every operand (a literal, optionally preceded by any number of
+ * `(' and unary `-') is followed by either an operator or the end of a group.
+ * Before a binary operator is pushed, the pending operators that bind at
+ * least as tight are reduced to nodes, so `1-2-3' is `(1-2)-3'. The operators
+ * of a right-associative level don't reduce each other, so `2^3^2' is
+ * `2^(3^2)'. Negation is reduced before any operator of a level lower than
+ * `MAX_LEVEL', so `-2^2' is `-(2^2)' while `2^-1' still parses. The nodes are
+ * the same that a recursive descent parser of the grammar in GRAMMAR.md would
+ * build: binary operators are `Bitwise', `Expression', `Term' or `Factor'
+ * nodes depending on their level and parentheses and literals are `Exponent' nodes.
+ * The bars of an absolute value `|x|' look the same on both sides, so a `|'
+ * opens a group where an operand is expected and closes the innermost group
+ * otherwise. That's why `||-1|-2|' is `|(|-1|)-2|' and not `(||)-1(|-2|)'.
+ * In a conditional `c ? a : b', the `?' opens a group that the `:' closes,
+ * so `a' can be any expression. `b' is a right operand on the lowest level,
+ * which makes conditionals right-associative: `a ? b : c ? d : e' is
+ * `a ? b : (c ? d : e)'.
+ */
+fn parse_expression(stream: &mut TokenStream, grammar: &Grammar, max_depth: usize)
+                    -> Result<ParseNode, ParserError> {
+    let mut pending: Vec<Pending> = vec![];
 
-    // one or more factor(s) can follow the appropriate token
     loop {
-        let token = stream.get_current();
-        match token {
-            Token::OpMult | Token::OpDiv => {
-                stream.advance(1);
-                let rchild = factor(&mut stream, depth)?;
-                if let None = node.right_child {
-                    node.right_child = Some(Box::new(rchild));
-                } else if let Some(ref mut current) = node.right_child {
-                    let mut parent = current;
-                    loop {
-                        if let None = parent.left_child {
-                            parent.left_child = Some(Box::new(rchild));
-                            break;
-                        } else if let Some(ref mut new) = parent.left_child {
-                            parent = new;
+        // parse the next operand, including its prefixes
+        let mut node = loop {
+            // every pending operator ends up above the operand in the tree
+            if pending.len() > max_depth {
+                return Err(too_deep(stream));
+            }
+            match stream.get_current() {
+                Some(Token::LeftParen) => {
+                    pending.push(Pending::Paren(stream.get_position()))
+                }
+                Some(Token::Bar) => {
+                    pending.push(Pending::Bar(stream.get_position()))
+                }
+                Some(Token::OpSub) => pending.push(Pending::Neg),
+                // a unary `+' is a no-op, so `+-5' is `-5'
+                Some(Token::OpAdd) => (),
+                Some(Token::OpNot) => pending.push(Pending::Not),
+                Some(Token::Tilde) => pending.push(Pending::BitNot),
+                // where an operand is expected, `||' opens two absolute values
+                Some(Token::OpOr) => {
+                    pending.push(Pending::Bar(stream.get_position()));
+                    pending.push(Pending::Bar(stream.get_position()));
+                }
+                // an identifier is a function if a `(' follows it and a
+                // variable (or constant) otherwise, which the vm looks up
+                Some(Token::Ident(name)) => {
+                    let pos = stream.get_position();
+                    stream.advance(1);
+                    if stream.get_current() != Some(Token::LeftParen) {
+                        break ParseNode::new(NodeType::Leaf,
+                                             Terminal::Var(name),
+                                             NonTerminal::Exponent,
+                                             0);
+                    }
+                    pending.push(Pending::Call(name, pos, vec![]));
+                }
+                Some(Token::Number(i)) => {
+                    stream.advance(1);
+                    break ParseNode::new(NodeType::Leaf,
+                                         Terminal::Literal(i),
+                                         NonTerminal::Exponent,
+                                         0);
+                }
+                token => {
+                    // a `)' either closes nothing or a group that is empty
+                    if let Some(Token::RightParen) = token {
+                        match pending.last() {
+                            Some(Pending::Paren(opened_at)) => {
+                                return Err(empty_parens(stream, *opened_at))
+                            }
+                            // a call without arguments, e.g. `max()'
+                            Some(Pending::Call(name, _, args))
+                                if args.is_empty() =>
+                            {
+                                let node = ParseNode::new_call(name.clone(), vec![]);
+                                pending.pop();
+                                stream.advance(1);
+                                break node;
+                            }
+                            _ if !is_in_group(&pending) => {
+                                return Err(unmatched_paren(stream))
+                            }
+                            _ => (),
                         }
                     }
+                    let pos = stream.get_position();
+                    return Err(match token {
+                        // e.g. `2 * * 3' or a leading `* 5'
+                        Some(token) if get_terminal(&token).is_some() => {
+                            let msg = format!("Unexpected operator `{}', expected \
+                                               a number or `('",
+                                              describe(&token, grammar));
+                            ParserError::new(msg, pos, stream.get_stream_until(pos))
+                        }
+                        Some(token) => {
+                            let msg = format!("Expected an operand, found `{}'",
+                                              describe(&token, grammar));
+                            ParserError::new(msg, pos, stream.get_stream_until(pos))
+                        }
+                        None => {
+                            ParserError::new("Unexpected end of input".to_string(),
+                                             pos,
+                                             stream.get_stream())
+                        }
+                    });
                 }
-                depth += 1;
             }
+            stream.advance(1);
+        };
 
-            // cursor is already advanced, break out of loop
-            _ => {
+        // reduce the pending operators until the next operator can be pushed
+        // or until the operand is complete
+        loop {
+            // a postfix `%' applies to the operand right before it
+            if is_percent(stream, &pending) {
+                stream.advance(1);
+                node = ParseNode::new_unary(Terminal::Percent,
+                                            NonTerminal::Exponent,
+                                            node);
+                if node.get_depth() > max_depth {
+                    return Err(too_deep(stream));
+                }
+                continue;
+            }
+            // inside of an absolute value, `||' closes it instead
+            if stream.get_current() == Some(Token::OpOr) && is_in_bar(&pending) {
+                stream.split_or();
+            }
+            let op = stream.get_current().and_then(|token| {
+                                             get_operator_info(&token, grammar)
+                                                 .map(|info| (token, info))
+                                         });
+            node = reduce(&mut pending, node, op.as_ref().map(|(_, info)| *info));
+            if node.get_depth() > max_depth {
+                return Err(too_deep(stream));
+            }
+            if let Some((token, info)) = op {
+                match get_terminal(&token).unwrap() {
+                    Terminal::Cond => {
+                        pending.push(Pending::Question(node, stream.get_position()))
+                    }
+                    terminal => pending.push(Pending::Binary(node,
+                                                             terminal,
+                                                             info.precedence,
+                                                             stream.get_position())),
+                }
+                stream.advance(1);
                 break;
             }
+
+            // a `:' completes the first branch of the innermost conditional
+            if let Some(Token::Colon) = stream.get_current() {
+                if let Some(Pending::Question(..)) = pending.last() {
+                    if let Some(Pending::Question(cond, _)) = pending.pop() {
+                        pending.push(Pending::Cond(cond, node));
+                    }
+                    stream.advance(1);
+                    break;
+                }
+                let pos = stream.get_position();
+                let msg = "Unexpected `:' outside of a conditional";
+                return Err(ParserError::new(msg.to_string(),
+                                            pos,
+                                            stream.get_stream_until(pos)));
+            }
+
+            // a `,' completes an argument of the innermost call
+            if let Some(Token::Comma) = stream.get_current() {
+                match pending.last_mut() {
+                    Some(Pending::Call(_, _, args)) => {
+                        args.push(node);
+                        stream.advance(1);
+                        break;
+                    }
+                    _ => {
+                        let pos = stream.get_position();
+                        let msg = "Unexpected `,' outside of a function call";
+                        return Err(ParserError::new(msg.to_string(),
+                                                    pos,
+                                                    stream.get_stream_until(pos)));
+                    }
+                }
+            }
+
+            // the operand is complete, so it either closes a group or it is
+            // the entire expression
+            let group = match pending.pop() {
+                None => {
+                    return match stream.get_current() {
+                        Some(Token::RightParen) => Err(unmatched_paren(stream)),
+                        Some(Token::Bar) => Err(unmatched_bar(stream)),
+                        _ => Ok(node),
+                    }
+                }
+                Some(Pending::Neg)
+                | Some(Pending::Not)
+                | Some(Pending::BitNot)
+                | Some(Pending::Binary(..))
+                | Some(Pending::Cond(..)) => {
+                    unreachable!("`reduce' only stops at groups")
+                }
+                Some(group) => group,
+            };
+            let (opened_at, closing) = match group {
+                Pending::Bar(opened_at) => (opened_at, Token::Bar),
+                Pending::Paren(opened_at) | Pending::Call(_, opened_at, _) => {
+                    (opened_at, Token::RightParen)
+                }
+                Pending::Question(_, opened_at) => (opened_at, Token::Colon),
+                _ => unreachable!(),
+            };
+            match stream.get_current() {
+                Some(token) if token == closing => {
+                    stream.advance(1);
+                    node = match group {
+                        Pending::Call(name, _, mut args) => {
+                            args.push(node);
+                            ParseNode::new_call(name, args)
+                        }
+                        Pending::Bar(_) => {
+                            ParseNode::new_unary(Terminal::Abs,
+                                                 NonTerminal::Exponent,
+                                                 node)
+                        }
+                        _ => ParseNode::new_unary(Terminal::Paren,
+                                                  NonTerminal::Exponent,
+                                                  node),
+                    };
+                }
+                Some(_) => {
+                    return Err(ParserError::new(unexpected(stream,
+                                                           grammar,
+                                                           &format!("`{}'",
+                                                                    closing)),
+                                                stream.get_position(),
+                                                stream.get_stream_until(stream.get_position())));
+                }
+                None => {
+                    let msg = match group {
+                        Pending::Paren(_) => {
+                            format!("Unclosed parenthesis opened at token {}",
+                                    opened_at)
+                        }
+                        Pending::Call(name, _, _) => {
+                            format!("Unclosed call of `{}' opened at token {}",
+                                    name, opened_at)
+                        }
+                        Pending::Question(..) => {
+                            format!("Missing `:' of the `?' at token {}", opened_at)
+                        }
+                        _ => format!("Unclosed `|' opened at token {}", opened_at),
+                    };
+                    return Err(ParserError::new(msg,
+                                                opened_at,
+                                                stream.get_stream_until(opened_at)));
+                }
+            }
         }
     }
-    Ok(node)
 }
 
-fn factor(mut stream: &mut TokenStream, depth: usize)
-          -> Result<ParseNode, ParserError> {
-    let mut node = ParseNode::new(NodeType::Branch,
-                                  Terminal::NonTerminal,
-                                  NonTerminal::Factor,
-                                  depth);
-    let lchild = exponent(&mut stream, depth)?;
-    node.left_child = Some(Box::new(lchild));
-    let depth = depth + 1;
+/*
+ * Pop the pending operators that bind tighter than the operator `next' and
+ * apply them to `node'. Without a `next' operator, everything up to the
+ * innermost open group is reduced.
+ */
+fn reduce(pending: &mut Vec<Pending>, mut node: ParseNode,
+          next: Option<OperatorInfo>)
+          -> ParseNode {
+    loop {
+        let binds_tighter = match (pending.last(), next) {
+            (None, _)
+            | (Some(Pending::Paren(_)), _)
+            | (Some(Pending::Bar(_)), _)
+            | (Some(Pending::Call(..)), _)
+            | (Some(Pending::Question(..)), _) => false,
+            (Some(_), None) => true,
+            (Some(Pending::Neg), Some(next))
+            | (Some(Pending::Not), Some(next))
+            | (Some(Pending::BitNot), Some(next)) => {
+                next.precedence < MAX_LEVEL + FIXED_LEVELS
+            }
+            // the lowest level is right-associative, so no operator ends it
+            (Some(Pending::Cond(..)), Some(_)) => false,
+            (Some(Pending::Binary(_, _, prev, _)), Some(next)) => {
+                *prev > next.precedence
+                || (*prev == next.precedence && !next.right_assoc)
+            }
+        };
+        if !binds_tighter {
+            return node;
+        }
 
-    let token = stream.get_current();
-    match token {
-        Token::OpExp => {
-            stream.advance(1);
-            let rchild = exponent(&mut stream, depth)?;
-            node.right_child = Some(Box::new(rchild));
+        node = match pending.pop() {
+            Some(Pending::Neg) => {
+                ParseNode::new_unary(Terminal::Neg, NonTerminal::Factor, node)
+            }
+            Some(Pending::Not) => {
+                ParseNode::new_unary(Terminal::Not, NonTerminal::Factor, node)
+            }
+            Some(Pending::BitNot) => {
+                ParseNode::new_unary(Terminal::BitNot, NonTerminal::Factor, node)
+            }
+            Some(Pending::Binary(lhs, terminal, level, token)) => {
+                let (terminal, node) = get_percentage_of(terminal, node);
+                let mut node = ParseNode::new_binary(terminal,
+                                                     get_non_terminal(level),
+                                                     lhs,
+                                                     node);
+                node.token = Some(token);
+                node
+            }
+            Some(Pending::Cond(cond, then)) => {
+                ParseNode::new_conditional(cond, then, node)
+            }
+            _ => unreachable!(),
+        };
+    }
+}
+
+/*
+ * Check if the `%' at the current position of `stream' is a percentage sign
+ * instead of a modulo. It is a modulo if an operand follows it, i.e. a
+ * literal, a function call, a `(', a `|' that opens an absolute value or a
+ * prefix like the unary `-' and `+'. Otherwise, e.g. at the end of the input
+ * or before `)' or a binary operator, it is a postfix percentage sign. So
+ * `7%2' and `7%-2' are modulos while `50%' and `(50%)*2' are percentages.
+ */
+fn is_percent(stream: &TokenStream, pending: &[Pending]) -> bool {
+    if stream.get_current() != Some(Token::OpMod) {
+        return false;
+    }
+    match stream.get_next() {
+        Some(Token::Number(_))
+        | Some(Token::LeftParen)
+        | Some(Token::Ident(_))
+        | Some(Token::OpNot)
+        | Some(Token::Tilde)
+        | Some(Token::OpSub)
+        | Some(Token::OpAdd) => false,
+        Some(Token::Bar) => is_in_bar(pending),
+        _ => true,
+    }
+}
+
+/* Check if the innermost open group is an absolute value, i.e. a `|' closes it. */
+fn is_in_bar(pending: &[Pending]) -> bool {
+    let group = pending.iter().rev().find(|p| {
+                                        !matches!(p,
+                                                  Pending::Neg
+                                                  | Pending::Not
+                                                  | Pending::BitNot
+                                                  | Pending::Binary(..)
+                                                  | Pending::Cond(..))
+                                    });
+    matches!(group, Some(Pending::Bar(_)))
+}
+
+/*
+ * A `+' or `-' whose right operand is a percentage adds or subtracts that
+ * percentage of the left operand, so `200+10%' is `220'. The percentage is
+ * dropped from the tree then because `AddPercent' and `SubPercent' take the
+ * number of percents (`10') as their RHS. Other operators stay as they are.
+ */
+fn get_percentage_of(terminal: Terminal, node: ParseNode) -> (Terminal, ParseNode) {
+    let terminal = match (terminal, &node.terminal) {
+        (Terminal::Sum, Terminal::Percent) => Terminal::AddPercent,
+        (Terminal::Sub, Terminal::Percent) => Terminal::SubPercent,
+        (terminal, _) => return (terminal, node),
+    };
+    (terminal, *node.left_child.unwrap())
+}
+
+/* Check if any parenthesis or call is still open, i.e. a `)' would close it. */
+fn is_in_group(pending: &[Pending]) -> bool {
+    pending.iter()
+           .any(|p| matches!(p, Pending::Paren(_) | Pending::Call(..)))
+}
+
+/*
+ * The error for a `)' at the current position of `stream' that closes no
+ * group. Like for unclosed groups, the tokens of the error end right before
+ * the parenthesis, so the indicator that `report_parser_err' prints points
+ * at it.
+ */
+fn unmatched_paren(stream: &TokenStream) -> ParserError {
+    let pos = stream.get_position();
+    ParserError::new(format!("Unmatched closing parenthesis at token {}", pos),
+                     pos,
+                     stream.get_stream_until(pos))
+}
+
+/* The error for a tree that grew deeper than `max_depth' at the current token. */
+fn too_deep(stream: &TokenStream) -> ParserError {
+    let pos = stream.get_position();
+    ParserError::new("Expression nesting too deep".to_string(),
+                     pos,
+                     stream.get_stream_until(pos))
+}
+
+/* Like `unmatched_paren', but for the bars of an absolute value. */
+fn unmatched_bar(stream: &TokenStream) -> ParserError {
+    let pos = stream.get_position();
+    ParserError::new(format!("Unmatched closing `|' at token {}", pos),
+                     pos,
+                     stream.get_stream_until(pos))
+}
+
+/*
+ * Show `token' like it is written in the input for error messages, i.e. an
+ * operator with its symbol in `grammar' (see `Display' of `Token').
+ */
+fn describe(token: &Token, grammar: &Grammar) -> String {
+    match grammar.get_operator(token) {
+        Some(op) => op.symbol.clone(),
+        None => token.to_string(),
+    }
+}
+
+/*
+ * The message for the current token of `stream' where `expected' should have
+ * come. An operand right after a complete operand, like the `3' in `2 3', is
+ * most likely missing an operator in between.
+ */
+fn unexpected(stream: &TokenStream, grammar: &Grammar, expected: &str) -> String {
+    let pos = stream.get_position();
+    let token = stream.get_current().unwrap();
+    if is_missing_operator(stream) {
+        format!("Missing operator between `{}' and `{}'",
+                describe(&stream.tokens[pos - 1], grammar),
+                describe(&token, grammar))
+    } else {
+        format!("Expected {}, found `{}'",
+                expected,
+                describe(&token, grammar))
+    }
+}
+
+/* Check if the current token starts an operand, which follows another one. */
+fn is_missing_operator(stream: &TokenStream) -> bool {
+    stream.get_position() > 0
+    && matches!(stream.get_current(),
+                Some(Token::Number(_) | Token::Ident(_) | Token::LeftParen))
+}
+
+/*
+ * The error for the tokens that are left after a complete expression, which
+ * names the first of them. Its tokens end right before it, so the indicator
+ * of `report_parser_err' points at it. If it starts another operand, like
+ * the `2' in `1 2', an operator is most likely missing.
+ */
+fn trailing_tokens(stream: &TokenStream, grammar: &Grammar) -> ParserError {
+    let pos = stream.get_position();
+    let token = describe(&stream.get_current().unwrap(), grammar);
+    let msg = if is_missing_operator(stream) {
+        format!("Trailing `{}' after the end of the expression, an operator is \
+                 missing after `{}'",
+                token,
+                describe(&stream.tokens[pos - 1], grammar))
+    } else {
+        format!("Trailing `{}' after the end of the expression", token)
+    };
+    ParserError::new(msg, pos, stream.get_stream_until(pos))
+}
+
+/* The error for a `)' that directly follows the `(' at `opened_at'. */
+fn empty_parens(stream: &TokenStream, opened_at: usize) -> ParserError {
+    ParserError::new("Empty parentheses are not allowed".to_string(),
+                     opened_at,
+                     stream.get_stream_until(opened_at))
+}
+
+/*
+ * The name of the non-terminal of a precedence level of the grammar, e.g.
+ * `Term' for 2. The levels above `MAX_LEVEL' have no binary operators, they
+ * are the operands.
+ */
+pub fn get_level_name(level: u8) -> &'static str {
+    if level > MAX_LEVEL {
+        NonTerminal::Exponent.get_name()
+    } else {
+        get_non_terminal(level + FIXED_LEVELS).get_name()
+    }
+}
+
+/* How the parser reduces a binary operator, see `reduce'. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OperatorInfo {
+    precedence: u8,    /* a level of the parser, higher binds tighter */
+    right_assoc: bool, /* `2^3^2' is `2^(3^2)' */
+}
+
+/*
+ * The binary operators that are fixed like the unary operators, i.e. they
+ * aren't part of the `Grammar'. The conditional `?', `||' and `&&' bind
+ * weaker than all operators of the grammar, so the parser's levels 0 to 2
+ * are theirs and the levels of the grammar follow from 3 on.
+ */
+const FIXED_OPERATORS: [(Token, OperatorInfo); 3] =
+    [(Token::Question,
+      OperatorInfo { precedence: 0,
+                     right_assoc: true }),
+     (Token::OpOr,
+      OperatorInfo { precedence: 1,
+                     right_assoc: false }),
+     (Token::OpAnd,
+      OperatorInfo { precedence: 2,
+                     right_assoc: false })];
+
+const FIXED_LEVELS: u8 = FIXED_OPERATORS.len() as u8;
+
+/*
+ * Look up how a binary operator's token is parsed, in `FIXED_OPERATORS' or
+ * in the operator table of the `grammar'. So a new operator only needs an
+ * entry in either table (and a terminal, see `get_terminal').
+ */
+fn get_operator_info(token: &Token, grammar: &Grammar) -> Option<OperatorInfo> {
+    match FIXED_OPERATORS.iter().find(|(fixed, _)| fixed == token) {
+        Some((_, info)) => Some(*info),
+        None => {
+            grammar.get_operator(token)
+                   .map(|op| OperatorInfo { precedence: op.level + FIXED_LEVELS,
+                                            right_assoc: op.right_assoc })
         }
-        _ => { /* don't do anything */ }
     }
-    Ok(node)
 }
 
-fn exponent(stream: &mut TokenStream, depth: usize)
-            -> Result<ParseNode, ParserError> {
-    let mut node = ParseNode::new(NodeType::Branch,
-                                  Terminal::NonTerminal,
-                                  NonTerminal::Exponent,
-                                  depth);
+/* The non-terminal of the binary operators of a level of the parser. */
+fn get_non_terminal(level: u8) -> NonTerminal {
+    match level {
+        0 => NonTerminal::Conditional,
+        1 => NonTerminal::Disjunction,
+        2 => NonTerminal::Conjunction,
+        3 => NonTerminal::Bitwise,
+        4 => NonTerminal::Expression,
+        5 => NonTerminal::Term,
+        _ => NonTerminal::Factor,
+    }
+}
 
-    let token = stream.get_current();
-    match token {
-        Token::LeftParen => {
-            stream.advance(1);
-            node.terminal = Terminal::Paren;
-            let lchild = expression(stream, depth)?; /* parse rest of parenthesized expression */
-            node.left_child = Some(Box::new(lchild));
-            if let Token::RightParen = stream.get_current() {
-                stream.advance(1);
-            } else {
-                return Err(ParserError::new(format!("Expected `)', found {:?}",
-                                                    stream.get_current()),
-                                            stream.get_position(),
-                                            stream.get_stream()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::evaluate;
+
+    fn parse_ok(input: &str) -> ParseNode {
+        match parse_str(input) {
+            Ok(ast) => ast,
+            Err(err) => panic!("`{}' doesn't parse: {}", input, err.msg),
+        }
+    }
+
+    fn prefix(input: &str) -> String {
+        parse_ok(input).to_prefix_string()
+    }
+
+    #[test]
+    fn operators_bind_by_their_precedence() {
+        assert_eq!(prefix("2+3*4"), "(+ 2 (* 3 4))");
+        assert_eq!(prefix("2*3+4"), "(+ (* 2 3) 4)");
+        assert_eq!(prefix("7%3*2"), "(* (% 7 3) 2)");
+        assert_eq!(prefix("1^2*3"), "(* (^ 1 2) 3)");
+        assert_eq!(prefix("1+2<<3"), "(<< (+ 1 2) 3)");
+        assert_eq!(prefix("1&&2||3"), "(|| (&& 1 2) 3)");
+        assert_eq!(prefix("1||0&&1"), "(|| 1 (&& 0 1))");
+        assert_eq!(prefix("(2+3)*4"), "(* (+ 2 3) 4)");
+        assert_eq!(prefix("|1-2|*3"), "(* (abs (- 1 2)) 3)");
+        assert_eq!(prefix("max(1,2+3)"), "(max 1 (+ 2 3))");
+    }
+
+    #[test]
+    fn mixed_precedence_levels_nest() {
+        assert_eq!(prefix("1-2-3"), "(- (- 1 2) 3)");
+        assert_eq!(prefix("2^3^2"), "(^ 2 (^ 3 2))");
+        assert_eq!(prefix("1<<2+1"), "(<< 1 (+ 2 1))");
+        assert_eq!(prefix("1 && 0 || !2"), "(|| (&& 1 0) (! 2))");
+        assert_eq!(prefix("1 ? 2 : 0 ? 3 : 4"), "(if 1 2 (if 0 3 4))");
+        assert_eq!(prefix("200+10%"), "(+% 200 10)");
+    }
+
+    #[test]
+    fn recovering_reports_every_error() {
+        let (ast, errors) = parse_recovering(lex("(1 +) * (2 3)"));
+        assert!(ast.is_none());
+        let errors: Vec<(usize, &str)> = errors.iter()
+                                               .map(|e| (e.token_no, e.msg.as_str()))
+                                               .collect();
+        assert_eq!(errors,
+                   vec![(3, "Expected an operand, found `)'"),
+                        (7, "Missing operator between `2' and `3'")]);
+        let (ast, errors) = parse_recovering(lex("(1 + 2) * 3"));
+        assert!(ast.is_some());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn trees_are_compared_by_their_structure() {
+        assert_eq!(parse_ok("1 + 2*3"), parse_ok("1+2*3"));
+        assert_ne!(parse_ok("1+2*3"), parse_ok("(1+2)*3"));
+        assert_ne!(parse_ok("1+2"), parse_ok("1-2"));
+        assert_ne!(parse_ok("1+2"), parse_ok("2+1"));
+        assert_ne!(parse_ok("1"), parse_ok("(1)"));
+        assert_eq!(parse_ok("(2+3)*4").simplify(), parse_ok("5*4").simplify());
+    }
+
+    #[test]
+    fn equal_trees_may_differ_in_depth_and_token() {
+        // the `*' is token 1 of `1*2', but token 3 of `x+1*2'
+        let sum = parse_ok("x+1*2");
+        let product = sum.children().nth(1).unwrap();
+        assert_eq!(product, &parse_ok("1*2"));
+        assert_ne!(product.token, parse_ok("1*2").token);
+
+        let mut moved = parse_ok("1*2");
+        moved.depth = 7;
+        moved.token = None;
+        assert_eq!(moved, parse_ok("1*2"));
+    }
+
+    #[test]
+    fn explain_reduces_the_leftmost_operator() {
+        let steps = |input, vars: &[(String, i64)]| parse_ok(input).explain(vars);
+        assert_eq!(steps("2+3*4", &[]), ["2 + 3 * 4", "2 + 12", "14"]);
+        assert_eq!(steps("(1+2)*(3+4)", &[]),
+                   ["(1 + 2) * (3 + 4)", "3 * (3 + 4)", "3 * 7", "21"]);
+        assert_eq!(steps("x + 1", &[("x".to_string(), 3)]),
+                   ["x + 1", "3 + 1", "4"]);
+        assert_eq!(steps("0 && 1/0", &[]), ["0 && 1 / 0", "0"]);
+        // the step that fails is left out
+        assert_eq!(steps("2 + 1/0", &[]), ["2 + 1 / 0"]);
+    }
+
+    #[test]
+    fn a_unary_plus_is_dropped() {
+        assert_eq!(parse_ok("+5"), parse_ok("5"));
+        assert_eq!(parse_ok("+-5"), parse_ok("-5"));
+        assert_eq!(parse_ok("3 - +2"), parse_ok("3 - 2"));
+        assert_eq!(prefix("+-5"), "(- 5)");
+        assert_eq!(evaluate(&parse_ok("3 - +2")).unwrap(), 1);
+    }
+
+    #[test]
+    fn bars_open_and_close_absolute_values() {
+        assert_eq!(prefix("|-5|"), "(abs (- 5))");
+        // the `||' opens two absolute values, not a disjunction
+        assert_eq!(prefix("||-1|-2|"), "(abs (- (abs (- 1)) 2))");
+        assert_eq!(prefix("|1-|2||"), "(abs (- 1 (abs 2)))");
+        assert_eq!(evaluate(&parse_ok("|-5|")).unwrap(), 5);
+        assert_eq!(evaluate(&parse_ok("||-1|-2|")).unwrap(), 1);
+    }
+
+    #[test]
+    fn rpn_writes_the_operators_after_their_operands() {
+        let rpn = |input| parse_ok(input).to_rpn_string();
+        assert_eq!(rpn("2+3*4"), "2 3 4 * +");
+        assert_eq!(rpn("(2+3)*4"), "2 3 + 4 *");
+        assert_eq!(rpn("1-2-3"), "1 2 - 3 -");
+        assert_eq!(rpn("2^3^2"), "2 3 2 ^ ^");
+        assert_eq!(rpn("-2*3"), "2 neg 3 *");
+        assert_eq!(rpn("7"), "7");
+    }
+
+    #[test]
+    fn latex_writes_fractions_and_products() {
+        let latex = |input| parse_ok(input).to_latex();
+        assert_eq!(latex("1/2"), "\\frac{1}{2}");
+        assert_eq!(latex("(1+2)/(3*4)"), "\\frac{1+2}{3 \\cdot 4}");
+        assert_eq!(latex("2*3"), "2 \\cdot 3");
+        assert_eq!(latex("2*(3+4)"), "2 \\cdot \\left(3+4\\right)");
+    }
+
+    #[test]
+    fn latex_groups_exponents_with_braces() {
+        let latex = |input| parse_ok(input).to_latex();
+        assert_eq!(latex("2^10"), "2^{10}");
+        assert_eq!(latex("2^(1+2)"), "2^{1+2}");
+        assert_eq!(latex("2^3^2"), "2^{3^{2}}");
+        assert_eq!(latex("(1+2)^2"), "\\left(1+2\\right)^{2}");
+        assert_eq!(latex("-2^2"), "-2^{2}");
+        assert_eq!(latex("|(1-2)|*pi"), "\\left|1-2\\right| \\cdot \\pi");
+    }
+
+    #[test]
+    fn modulo_binds_like_multiplication() {
+        assert_eq!(prefix("17 % 5 % 3"), "(% (% 17 5) 3)");
+        assert_eq!(prefix("7 % 4 * 2"), "(* (% 7 4) 2)");
+        assert_eq!(prefix("1 + 7 % 3"), "(+ 1 (% 7 3))");
+        assert_eq!(prefix("1 + 7 % 3 * 2"), "(+ 1 (* (% 7 3) 2))");
+    }
+
+    #[test]
+    fn prefixes_bind_weaker_than_exponents() {
+        assert_eq!(prefix("-2^2"), "(- (^ 2 2))");
+        assert_eq!(prefix("-2*3"), "(* (- 2) 3)");
+        assert_eq!(prefix("2*-3"), "(* 2 (- 3))");
+        assert_eq!(prefix("~1+2"), "(+ (~ 1) 2)");
+        assert_eq!(prefix("!1+1"), "(+ (! 1) 1)");
+    }
+
+    #[test]
+    fn only_exponents_and_conditionals_are_right_associative() {
+        assert_eq!(prefix("2-3-4"), "(- (- 2 3) 4)");
+        assert_eq!(prefix("8/4/2"), "(/ (/ 8 4) 2)");
+        assert_eq!(prefix("2^3^2"), "(^ 2 (^ 3 2))");
+        assert_eq!(prefix("1?2:0?3:4"), "(if 1 2 (if 0 3 4))");
+    }
+
+    #[test]
+    fn malformed_input_is_reported_at_its_token() {
+        assert_eq!(parse_err("1+"), ("Unexpected end of input".to_string(), 2));
+        assert_eq!(parse_err("max(1,"),
+                   ("Unexpected end of input".to_string(), 4));
+        assert_eq!(parse_err("*5"),
+                   ("Unexpected operator `*', expected a number or `('".to_string(), 0));
+        assert_eq!(parse_err("1 ? 2"),
+                   ("Missing `:' of the `?' at token 1".to_string(), 1));
+        assert_eq!(parse_err("|1"),
+                   ("Unclosed `|' opened at token 0".to_string(), 0));
+        assert_eq!(parse_err(")"),
+                   ("Unmatched closing parenthesis at token 0".to_string(), 0));
+    }
+
+    #[test]
+    fn trees_are_measured_by_nodes_and_height() {
+        let ast = parse_ok("2+3*4");
+        assert_eq!(ast.node_count(), 5);
+        assert_eq!(ast.height(), 2);
+        assert_eq!(ast.height(), ast.get_depth());
+
+        assert_eq!(parse_ok("7").node_count(), 1);
+        assert_eq!(parse_ok("7").height(), 0);
+        // parentheses are nodes, too
+        assert_eq!(parse_ok("(2+3)*4").node_count(), 6);
+        assert_eq!(parse_ok("(2+3)*4").height(), 3);
+        assert_eq!(parse_ok("-(-1)").height(), 3);
+        assert_eq!(parse_ok(&["1"; 10].join("+")).height(), 9);
+    }
+
+    #[test]
+    fn errors_show_tokens_as_they_are_written() {
+        assert_eq!(parse_err("(1 =").0, "Expected `)', found `='");
+        assert_eq!(parse_err("(1+)").0, "Expected an operand, found `)'");
+        assert!(parse_err("1 max").0
+                                  .starts_with("Trailing `max' after the end"));
+        let grammar = Grammar::c_operators();
+        match parse_with(lex_with("**2", &grammar), &grammar, DEFAULT_MAX_DEPTH) {
+            Ok(_) => panic!("`**2' parses"),
+            Err(err) => {
+                assert_eq!(err.msg,
+                           "Unexpected operator `**', expected a number or `('")
             }
         }
-        Token::Number(i) => {
-            stream.advance(1);
-            node.ntype = NodeType::Leaf;
-            node.terminal = Terminal::Literal(i);
+    }
+
+    #[test]
+    fn infix_strings_parse_to_the_same_result() {
+        let inputs = ["((2))+(3*4)",
+                      "(2+3)*4",
+                      "2-(3-4)",
+                      "2^3^2",
+                      "(2^3)^2",
+                      "-(2+3)*-4",
+                      "|-5| - 7 % (3 - 1)",
+                      "1 ? 10 / (6 - 4) : 0"];
+        for input in inputs.iter() {
+            let ast = parse_ok(input);
+            let infix = ast.to_infix_string();
+            let reparsed = parse_ok(&infix);
+            assert_eq!(evaluate(&ast).ok(), evaluate(&reparsed).ok(), "{}", infix);
+            assert_eq!(reparsed.to_infix_string(), infix);
         }
-        _ => {
-            return Err(ParserError::new("Unexpected end of input".to_string(),
-                                        stream.get_position(),
-                                        stream.get_stream()));
+        assert_eq!(parse_ok("((2))+(3*4)").to_infix_string(), "2 + 3 * 4");
+        assert_eq!(parse_ok("(2+3)*4").to_infix_string(), "(2 + 3) * 4");
+    }
+
+    fn parse_err(input: &str) -> (String, usize) {
+        match parse_str(input) {
+            Ok(_) => panic!("`{}' parses", input),
+            Err(err) => (err.msg, err.token_no),
         }
     }
-    Ok(node)
+
+    #[test]
+    fn unbalanced_parentheses_point_at_the_culprit() {
+        assert_eq!(parse_err("(1+2"),
+                   ("Unclosed parenthesis opened at token 0".to_string(), 0));
+        assert_eq!(parse_err("1+(2*(3)"),
+                   ("Unclosed parenthesis opened at token 2".to_string(), 2));
+        assert_eq!(parse_err("1+2)"),
+                   ("Unmatched closing parenthesis at token 3".to_string(), 3));
+        assert_eq!(parse_err("(223^11)+2429-542)-11"),
+                   ("Unmatched closing parenthesis at token 9".to_string(), 9));
+    }
+
+    #[test]
+    fn empty_parentheses_are_rejected() {
+        let msg = || "Empty parentheses are not allowed".to_string();
+        assert_eq!(parse_err("()"), (msg(), 0));
+        assert_eq!(parse_err("(())"), (msg(), 1));
+        assert_eq!(parse_err("223^(())-11"), (msg(), 3));
+    }
+
+    #[test]
+    fn a_percent_sign_has_no_operand() {
+        let is_modulo = |input: &str| {
+            let ast = parse_ok(input);
+            let node = ast.strip_parens();
+            assert!(matches!(node.terminal, Terminal::Mod | Terminal::Percent));
+            matches!(node.terminal, Terminal::Mod)
+        };
+        for input in
+            ["7 % 2", "7 % -2", "7 % +2", "7%(2)", "7%|2|", "7%!0", "7%~0"].iter()
+        {
+            assert!(is_modulo(input), "{}", input);
+        }
+        assert!(!is_modulo("50%"));
+        assert!(!is_modulo("(50%)"));
+        assert!(matches!(parse_ok("|50%|").children().next().unwrap().terminal,
+                         Terminal::Percent));
+        assert!(matches!(parse_ok("200 + 10%").terminal, Terminal::AddPercent));
+        assert!(matches!(parse_ok("200 - 10%").terminal, Terminal::SubPercent));
+        assert!(matches!(parse_ok("2 * 50%").children().nth(1).unwrap().terminal,
+                         Terminal::Percent));
+    }
+
+    #[test]
+    fn simplify_folds_constants_and_identities() {
+        let folded = parse_ok("2+3*4").simplify();
+        assert!(matches!(folded.terminal, Terminal::Literal(14)));
+        assert_eq!(folded.node_count(), 1);
+        assert_eq!(folded.get_depth(), 0);
+
+        let simplified = |input: &str| parse_ok(input).simplify().to_infix_string();
+        assert_eq!(simplified("2*3 + x"), "6 + x");
+        assert_eq!(simplified("x*1+0"), "x");
+        assert_eq!(simplified("0 + 1*x"), "x");
+        assert_eq!(simplified("x^1/1 - 0"), "x");
+        assert_eq!(simplified("(y+0) * (3-2)"), "y");
+        assert_eq!(simplified("0 - x"), "0 - x");
+        assert_eq!(simplified("x*0"), "x * 0");
+        // errors aren't folded away, so they are still reported
+        assert_eq!(simplified("1/0 + 2*3"), "1 / 0 + 6");
+        assert_eq!(simplified("1/0 * 1"), "1 / 0");
+    }
+
+    #[test]
+    fn depths_are_recomputed_after_a_rewrite() {
+        let depths = |ast: &ParseNode| {
+            ast.describe()
+               .lines()
+               .map(|line| line.trim_start().to_string())
+               .collect::<Vec<String>>()
+        };
+        let mut ast = parse_ok("(1+2)*x");
+        assert_eq!(depths(&ast),
+                   vec!["* (Term, prec 5, depth 3)",
+                        "(...) (Exponent, prec 7, depth 2)",
+                        "+ (Expression, prec 4, depth 1)",
+                        "1 (Exponent, prec 7, depth 0)",
+                        "2 (Exponent, prec 7, depth 0)",
+                        "x (Exponent, prec 7, depth 0)"]);
+        assert_eq!(depths(&ast.clone().simplify()),
+                   vec!["* (Term, prec 5, depth 1)",
+                        "3 (Exponent, prec 7, depth 0)",
+                        "x (Exponent, prec 7, depth 0)"]);
+
+        // replacing a subtree by hand leaves the depths above it behind
+        ast.left_child = Some(Box::new(parse_ok("3")));
+        assert_eq!(ast.get_depth(), 3);
+        ast.recompute_depths();
+        assert_eq!(depths(&ast),
+                   vec!["* (Term, prec 5, depth 1)",
+                        "3 (Exponent, prec 7, depth 0)",
+                        "x (Exponent, prec 7, depth 0)"]);
+    }
+
+    #[test]
+    fn simplify_cuts_nodes_but_not_the_result() {
+        let ast = parse_ok("2*3 + x");
+        let folded = ast.clone().simplify();
+        assert_eq!(ast.node_count(), 5);
+        assert_eq!(folded.node_count(), 3);
+        let mut env = alloc::collections::BTreeMap::new();
+        env.insert(String::from("x"), 4);
+        let envs = [env];
+        assert_eq!(crate::vm::evaluate_many(&ast, &envs), vec![Ok(10)]);
+        assert_eq!(crate::vm::evaluate_many(&folded, &envs), vec![Ok(10)]);
+    }
+
+    fn nested(depth: usize) -> String {
+        format!("{}1{}", "(".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn deeply_nested_parentheses_parse_without_recursion() {
+        let tokens = lex(&nested(5000));
+        match parse_with(tokens, &Grammar::default(), 5001) {
+            Ok(ast) => {
+                assert!(matches!(ast.terminal, Terminal::Paren));
+                assert!(matches!(ast.strip_parens().terminal, Terminal::Literal(1)));
+            }
+            Err(err) => panic!("{}", err.msg),
+        }
+    }
+
+    #[test]
+    fn too_deep_nesting_is_a_friendly_error() {
+        match parse_with(lex(&nested(2000)), &Grammar::default(), 1000) {
+            Ok(_) => panic!("a tree of depth 2000 exceeds the limit"),
+            Err(err) => {
+                assert_eq!(err.msg, "Expression nesting too deep");
+                // reported as soon as the limit is exceeded, not at the end
+                assert_eq!(err.token_no, 1001);
+            }
+        }
+        assert!(parse_with(lex(&nested(500)), &Grammar::default(), 1000).is_ok());
+    }
+
+    #[test]
+    fn adjacent_operators_and_operands_are_errors() {
+        let unexpected = "Unexpected operator `*', expected a number or `('";
+        assert_eq!(parse_err("2 * * 3"), (unexpected.to_string(), 2));
+        assert_eq!(parse_err("* 5"), (unexpected.to_string(), 0));
+        let trailing = "Trailing `3' after the end of the expression, an operator \
+                        is missing after `2'";
+        assert_eq!(parse_err("2 3"), (trailing.to_string(), 1));
+        assert_eq!(parse_err("(2 3)"),
+                   ("Missing operator between `2' and `3'".to_string(), 2));
+    }
 }
@@ -0,0 +1,199 @@
+/* rational.rs: Exact fractions for the rational evaluation mode. */
+use crate::vm::EvalError;
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+use core::fmt;
+
+/*
+ * A fraction `num/den' which is always reduced and has a positive `den', so
+ * two equal values have equal fields. Integers have a `den' of 1.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rational {
+    pub num: i64,
+    pub den: i64,
+}
+
+impl Rational {
+    /* Create the reduced fraction `num/den' or fail if `den' is zero. */
+    pub fn new(num: i64, den: i64) -> Result<Rational, EvalError> {
+        Rational::from_wide(num as i128, den as i128)
+    }
+
+    pub fn from_integer(n: i64) -> Rational {
+        Rational { num: n, den: 1 }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.den == 1
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+
+    /*
+     * The operators compute with `i128' intermediates (products of two `i64'
+     * always fit) and only fail if the reduced result doesn't fit an `i64'.
+     */
+    pub fn checked_add(self, rhs: Rational) -> Result<Rational, EvalError> {
+        let (a, b, c, d) = self.widen(rhs);
+        Rational::from_wide(a * d + c * b, b * d)
+    }
+
+    pub fn checked_sub(self, rhs: Rational) -> Result<Rational, EvalError> {
+        let (a, b, c, d) = self.widen(rhs);
+        Rational::from_wide(a * d - c * b, b * d)
+    }
+
+    pub fn checked_mul(self, rhs: Rational) -> Result<Rational, EvalError> {
+        let (a, b, c, d) = self.widen(rhs);
+        Rational::from_wide(a * c, b * d)
+    }
+
+    pub fn checked_div(self, rhs: Rational) -> Result<Rational, EvalError> {
+        let (a, b, c, d) = self.widen(rhs);
+        Rational::from_wide(a * d, b * c)
+    }
+
+    /*
+     * The remainder of a truncated division, like `%' on integers: both
+     * operands are written with the common denominator `b*d', so the result
+     * is the remainder of their numerators over `b*d'.
+     */
+    pub fn checked_rem(self, rhs: Rational) -> Result<Rational, EvalError> {
+        let (a, b, c, d) = self.widen(rhs);
+        if c == 0 {
+            return Err(EvalError::ModuloByZero);
+        }
+        Rational::from_wide((a * d) % (b * c), b * d)
+    }
+
+    /* Raise to an integer power. A negative exponent inverts the base. */
+    pub fn checked_pow(self, exp: Rational) -> Result<Rational, EvalError> {
+        if !exp.is_integer() {
+            return Err(EvalError::FractionalExponent(exp));
+        }
+        let n = match u32::try_from(exp.num.unsigned_abs()) {
+            Ok(n) => n,
+            Err(_) => return Err(EvalError::ExponentTooLarge(exp.num.into())),
+        };
+        let num = self.num.checked_pow(n).ok_or(EvalError::Overflow)?;
+        let den = self.den.checked_pow(n).ok_or(EvalError::Overflow)?;
+        if exp.num < 0 {
+            Rational::new(den, num)
+        } else {
+            Ok(Rational { num, den })
+        }
+    }
+
+    /* The bitwise exclusive or, which is only defined for integers. */
+    pub fn checked_xor(self, rhs: Rational) -> Result<Rational, EvalError> {
+        Ok(Rational::from_integer(self.to_integer()? ^ rhs.to_integer()?))
+    }
+
+    /* The value as an integer for bitwise operators, or an error if it isn't. */
+    pub fn to_integer(self) -> Result<i64, EvalError> {
+        if self.is_integer() {
+            Ok(self.num)
+        } else {
+            Err(EvalError::FractionalOperand(self))
+        }
+    }
+
+    pub fn checked_neg(self) -> Result<Rational, EvalError> {
+        let num = self.num.checked_neg().ok_or(EvalError::Overflow)?;
+        Ok(Rational { num, den: self.den })
+    }
+
+    pub fn checked_abs(self) -> Result<Rational, EvalError> {
+        let num = self.num.checked_abs().ok_or(EvalError::Overflow)?;
+        Ok(Rational { num, den: self.den })
+    }
+
+    fn widen(self, rhs: Rational) -> (i128, i128, i128, i128) {
+        (self.num as i128, self.den as i128, rhs.num as i128, rhs.den as i128)
+    }
+
+    /* Reduce `num/den', move the sign to `num' and narrow it to `i64's. */
+    fn from_wide(num: i128, den: i128) -> Result<Rational, EvalError> {
+        if den == 0 {
+            return Err(EvalError::DivisionByZero);
+        }
+        let divisor = gcd(num.abs(), den.abs()) * den.signum();
+        let num = i64::try_from(num / divisor).map_err(|_| EvalError::Overflow)?;
+        let den = i64::try_from(den / divisor).map_err(|_| EvalError::Overflow)?;
+        Ok(Rational { num, den })
+    }
+}
+
+/* Fractions are compared by their numerators over a common denominator. */
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Rational) -> Option<Ordering> {
+        let (a, b, c, d) = self.widen(*other);
+        (a * d).partial_cmp(&(c * b))
+    }
+}
+
+/* Integers are printed as such, all other values as `num/den', e.g. `7/2'. */
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(num: i64, den: i64) -> Rational {
+        Rational::new(num, den).unwrap()
+    }
+
+    #[test]
+    fn fractions_are_exact_and_reduced() {
+        assert_eq!(ratio(1, 3).checked_add(ratio(1, 6)).unwrap(), ratio(1, 2));
+        assert_eq!(ratio(1, 2), Rational { num: 1, den: 2 });
+        assert_eq!(ratio(2, -4), Rational { num: -1, den: 2 });
+        assert_eq!(ratio(7, 1).checked_div(ratio(2, 1)).unwrap(), ratio(7, 2));
+        assert!(matches!(Rational::new(1, 0), Err(EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn remainders_truncate_like_integers() {
+        let int = Rational::from_integer;
+        assert_eq!(int(7).checked_rem(int(3)).unwrap(), int(1));
+        assert_eq!(int(-7).checked_rem(int(3)).unwrap(), int(-1));
+        assert_eq!(ratio(7, 2).checked_rem(int(1)).unwrap(), ratio(1, 2));
+        assert_eq!(ratio(5, 6).checked_rem(ratio(1, 4)).unwrap(), ratio(1, 12));
+        assert!(matches!(int(7).checked_rem(int(0)), Err(EvalError::ModuloByZero)));
+    }
+
+    #[test]
+    fn powers_invert_for_negative_exponents() {
+        let int = Rational::from_integer;
+        assert_eq!(int(2).checked_pow(int(-1)).unwrap(), ratio(1, 2));
+        assert_eq!(ratio(2, 3).checked_pow(int(-2)).unwrap(), ratio(9, 4));
+        assert_eq!(ratio(-1, 2).checked_pow(int(3)).unwrap(), ratio(-1, 8));
+        assert_eq!(int(0).checked_pow(int(0)).unwrap(), int(1));
+        assert_eq!(int(0).checked_pow(int(5)).unwrap(), int(0));
+        assert!(matches!(int(0).checked_pow(int(-1)),
+                         Err(EvalError::DivisionByZero)));
+        assert!(matches!(int(2).checked_pow(ratio(1, 2)),
+                         Err(EvalError::FractionalExponent(_))));
+        assert!(matches!(int(2).checked_pow(int(64)), Err(EvalError::Overflow)));
+    }
+}
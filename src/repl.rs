@@ -0,0 +1,320 @@
+/* repl.rs: The interactive mode, a read-eval-print loop on an `Interpreter'. */
+use crate::rational::Rational;
+use crate::utils::{
+    format_value, format_verbose, red, report_eval_err_pos, report_parser_err,
+    Config,
+};
+use crate::vm::{to_rational, Interpreter, InterpreterError, Statement, Value};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+
+/* Stores keywords that are interpreted alongside the expressions. */
+struct Keywords {
+    quit: Vec<String>,        /* "quit", "q" */
+    total: Vec<String>,       /* ":total" */
+    clear_total: Vec<String>, /* ":clear-total" */
+    clear: Vec<String>,       /* "clear" */
+    vars: Vec<String>,        /* "vars" */
+    help: Vec<String>,        /* "help" */
+}
+
+/* Run the interactive mode, including interpreter loop & lexing & parsing. */
+pub fn run(configs: &Config) {
+    let keywords = init();
+    eprintln!("{}: Exit with ctrl+c, ctrl+d or by typing `quit' or `q' (`help' \
+               lists all keywords).",
+              configs.progname);
+
+    // the line editor recalls previous inputs, including those of earlier
+    // sessions (there is no history file on the first run, which is fine)
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("{}: error: Cannot start the line editor: {}",
+                      configs.progname, e);
+            return;
+        }
+    };
+    let history = get_history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    // the running total of all results if `--accumulate' was given, which
+    // is exact in either mode because integers are rationals, too
+    let mut total = Rational::from_integer(0);
+
+    // keeps the variables that were assigned in this session
+    let mut interpreter = Interpreter::from_config(configs);
+
+    // the read-eval-print loop
+    loop {
+        let input = match prompt_and_read(&mut editor, &configs.prompt) {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}: error: Cannot read input: {}", configs.progname, e);
+                break;
+            }
+        };
+
+        // check if the input is a keyword
+        if matches_any(&input, &keywords.quit) {
+            break;
+        }
+        if matches_any(&input, &keywords.total) {
+            if configs.accumulate {
+                eprintln!("\ttotal: {}",
+                          format_value(&Value::Rational(total), configs));
+            } else {
+                eprintln!("{}: error: Start with `--accumulate' to keep a running total",
+                          configs.progname);
+            }
+            continue;
+        }
+        if matches_any(&input, &keywords.clear_total) {
+            total = Rational::from_integer(0);
+            continue;
+        }
+        if matches_any(&input, &keywords.clear) {
+            if !interpreter.get_variables().is_empty() {
+                interpreter.clear_variables();
+                eprintln!("\tCleared all variables.");
+            }
+            continue;
+        }
+        if matches_any(&input, &keywords.vars) {
+            print_variables(interpreter.get_variables(), configs);
+            continue;
+        }
+        if matches_any(&input, &keywords.help) {
+            eprintln!("{}", HELP);
+            continue;
+        }
+
+        // with `--verbose', the parsed expression is shown before its result
+        // (an input that doesn't parse is reported by `execute' below)
+        if configs.verbose {
+            if let Ok((_, ast)) = interpreter.parse(&input) {
+                for line in format_verbose(&ast) {
+                    eprintln!("\t{}", line);
+                }
+            }
+        }
+
+        // an empty line or a line with nothing but a comment is skipped and
+        // an assignment prints the new values instead of a result, so it
+        // doesn't count towards the running total either
+        match interpreter.execute(&input) {
+            Ok(Statement::Empty) => (),
+            Ok(Statement::Assignment(assigned)) => {
+                for (name, value) in assigned {
+                    eprintln!("\t{} = {}", name, format_value(&value, configs));
+                }
+            }
+            Ok(Statement::Expression(res)) if configs.accumulate => {
+                match to_rational(res).and_then(|res| total.checked_add(res)) {
+                    Ok(sum) => {
+                        total = sum;
+                        eprintln!("\t{}\t(total: {})",
+                                  format_value(&res, configs),
+                                  format_value(&Value::Rational(total), configs));
+                    }
+                    Err(_) => {
+                        eprintln!("{}: error: vm: Running total overflowed, it stays at {}",
+                                  configs.progname,
+                                  format_value(&Value::Rational(total), configs));
+                    }
+                }
+            }
+            Ok(Statement::Expression(res)) => {
+                eprintln!("\t{}", format_value(&res, configs));
+            }
+            Err(InterpreterError::Parser(e)) => {
+                report_parser_err(e, &input, &configs.grammar, configs.color_errors)
+            }
+            Err(InterpreterError::Eval(e)) => {
+                let msg = format!("{}: error: {}", configs.progname, e);
+                eprintln!("{}", red(&msg, configs.color_errors));
+                report_eval_err_pos(&e,
+                                    &input,
+                                    &configs.grammar,
+                                    configs.color_errors);
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("{}: error: Cannot save the history to {}: {}",
+                      configs.progname,
+                      path.display(),
+                      e);
+        }
+    }
+}
+
+/* Initialize data that is used by the REPL. */
+fn init() -> Keywords {
+    Keywords { quit: vec![String::from("quit"), String::from("q")],
+               total: vec![String::from(":total")],
+               clear_total: vec![String::from(":clear-total")],
+               clear: vec![String::from("clear")],
+               vars: vec![String::from("vars")],
+               help: vec![String::from("help")] }
+}
+
+/* The reference that the `help' keyword prints. */
+const HELP: &str = "\tOperators:
+\t  + - * / % mod  addition, subtraction, multiplication, division, modulo
+\t  ^              exponentiation (right-associative, `2^3^2' is `2^(3^2)')
+\t  << >> >>>      left, arithmetic right and logical right shift
+\t  -x +x          negation and the no-op unary plus
+\t  ~x             bitwise not (`~5' is `-6')
+\t  && || !x       logical and, or and not (nonzero is true, results are 1 or 0)
+\t  c ? a : b      `a' if `c' is nonzero, `b' otherwise (the other isn't evaluated)
+\t  ( )            grouping
+\t  |x|            absolute value
+\t  x%             percentage (`200+10%' is `220')
+\tFunctions:
+\t  min(a, ...)    the smallest argument
+\t  max(a, ...)    the largest argument
+\t  clamp(x, l, u) `x' limited to the range from `l' to `u'
+\t  abs(x), neg(x) the absolute value `|x|' and the negation `-x'
+\t  gcd(a, b)      the greatest common divisor (`gcd(0, 0)' is 0)
+\t  lcm(a, b)      the least common multiple
+\t  rand(lo, hi)   a random integer from `lo' to `hi' (with `--allow-rand')
+\tAssignments:
+\t  x = y = 3      assign 3 to `x' and `y'
+\t  x += 2         likewise `-=', `*=' and `/='
+\tConstants:
+\t  pi, e          approximated by fractions (use `--rational')
+\tKeywords (in any case, e.g. `QUIT'):
+\t  quit, q        exit (or press ctrl+c, ctrl+d)
+\t  :total         print the running total (with `--accumulate')
+\t  :clear-total   reset the running total to 0
+\t  vars           list the defined variables
+\t  clear          remove all variables
+\t  help           print this help
+\tEnd a line with `\\' to continue the input on the next line.
+\tSee GRAMMAR.md for precedence, `--c-operators' and `--grammar'.";
+
+/* List the variables of the REPL, which are sorted by their names. */
+fn print_variables(variables: &BTreeMap<String, Value>, configs: &Config) {
+    if variables.is_empty() {
+        eprintln!("\tNo variables defined.");
+        return;
+    }
+    for (name, value) in variables {
+        eprintln!("\t{} = {}", name, format_value(value, configs));
+    }
+}
+
+/* Check if `s' is one of the keywords in `s_vec', which are lower case. */
+fn matches_any(s: &str, s_vec: &Vec<String>) -> bool {
+    // keywords are case-insensitive, so `QUIT' and `Q' exit, too
+    let s = s.to_lowercase();
+    for elem in s_vec {
+        if s == *elem {
+            return true;
+        }
+    }
+    false
+}
+
+/* The prompt for the next line of an input that is continued with `\'. */
+const CONTINUATION_PROMPT: &str = "... ";
+
+/*
+ * Print a prompt, read a line with the line editor and return with white space
+ * trimmed off. A line that ends with `\' is continued on the next one (see
+ * `append_line'), which is read after `CONTINUATION_PROMPT'. Non-empty inputs
+ * are added to the history as a single entry. Ctrl+c and ctrl+d (or a closed
+ * `stdin') are reported as `ReadlineError::Interrupted' and `Eof'.
+ */
+fn prompt_and_read(editor: &mut DefaultEditor, ps1: &str)
+                   -> Result<String, ReadlineError> {
+    let mut input = String::new();
+    let mut prompt = ps1;
+    while append_line(&mut input, editor.readline(prompt)?.trim()) {
+        prompt = CONTINUATION_PROMPT;
+    }
+    let input = input.trim().to_string();
+    if !input.is_empty() {
+        let _ = editor.add_history_entry(input.as_str());
+    }
+    Ok(input)
+}
+
+/*
+ * Append a trimmed `line' to the `input' that was read so far and return
+ * whether the line is continued, i.e. whether it ends with a `\'. The `\' is
+ * replaced with a space, so `1 +\' and `2' are `1 + 2' and tokens at the end
+ * and the start of two lines aren't joined (`12\' and `3' are `12 3'). A `\'
+ * anywhere else is part of the input like any other character, including the
+ * one at the end of a `#' comment, which would swallow the next line.
+ */
+fn append_line(input: &mut String, line: &str) -> bool {
+    match line.strip_suffix('\\').filter(|rest| !rest.contains('#')) {
+        Some(rest) => {
+            input.push_str(rest);
+            input.push(' ');
+            true
+        }
+        None => {
+            input.push_str(line);
+            false
+        }
+    }
+}
+
+/* The history is kept in `~/.expr_parser_history' if `$HOME' is known. */
+fn get_history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".expr_parser_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        let keywords = init();
+        for input in ["quit", "QUIT", "Quit", "q", "Q"].iter() {
+            assert!(matches_any(input, &keywords.quit), "{}", input);
+        }
+        assert!(matches_any("HELP", &keywords.help));
+        assert!(matches_any(":Total", &keywords.total));
+        assert!(!matches_any("quit now", &keywords.quit));
+        assert!(!matches_any("qq", &keywords.quit));
+        assert!(!matches_any("q", &keywords.vars));
+    }
+
+    #[test]
+    fn a_trailing_backslash_continues_the_line() {
+        let mut input = String::new();
+        assert!(append_line(&mut input, "1 +\\"));
+        assert!(!append_line(&mut input, "2"));
+        assert_eq!(input, "1 + 2");
+    }
+
+    #[test]
+    fn a_backslash_in_a_comment_is_part_of_it() {
+        let mut input = String::new();
+        assert!(!append_line(&mut input, "1 + 2 # see C:\\"));
+        assert_eq!(input, "1 + 2 # see C:\\");
+        let mut input = String::new();
+        assert!(!append_line(&mut input, "3 # a \\ in between"));
+        assert_eq!(input, "3 # a \\ in between");
+    }
+
+    #[test]
+    fn a_plain_line_is_appended_as_it_is() {
+        let mut input = String::from("1 + ");
+        assert!(!append_line(&mut input, "2 \\ 3"));
+        assert_eq!(input, "1 + 2 \\ 3");
+    }
+}
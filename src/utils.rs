@@ -2,41 +2,141 @@
 extern crate clap;
 
 use crate::draw;
-use crate::lexer::Token;
-use crate::parser::{ParseNode, ParserError};
-use clap::{App, Arg};
+use crate::draw::GraphOptions;
+use crate::grammar::{load_grammar, Grammar, MAX_LEVEL, MIN_LEVEL};
+use crate::lexer::{lex, lex_spanned_with, LexerError, Token};
+use crate::parser::{get_level_name, ParseNode, ParserError, DEFAULT_MAX_DEPTH};
+use crate::vm::{is_constant, is_physics_constant, EvalError, IntWidth, Value};
+use clap::{App, Arg, ArgMatches};
+use std::io::{stderr, IsTerminal};
 use std::process::exit;
+use std::time::Duration;
 
 const VERSION: &str = "0.0.2";
 const AUTHOR: &str = "Daniel Schuette <d.schuette@online.de>";
+
+/* The environment variable with the expression to evaluate if `-e' is missing. */
+pub const INPUT_VAR: &str = "EXPR_PARSER_INPUT";
 const ABOUT: &str = "Parse simple arithmetic expressions. Without any flags or options, an interactive session is started.";
 const PROGNAME: &str = "expr_parser";
 
+/*
+ * How results are printed, see `format_result'. `--radix' picks the base,
+ * `--sci' and `--grouping' are styles of decimal results.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputStyle {
+    Decimal,    /* `1000000' */
+    Hex,        /* `0xF4240' */
+    Binary,     /* `0b11110100001001000000' */
+    Octal,      /* `0o3641100' */
+    Scientific, /* `1e6' */
+    Grouped,    /* `1,000,000' */
+}
+
 pub struct Config {
     pub expression: String,
+    pub has_expression: bool, /* `-e' was given, even if it is empty */
     pub is_debug: bool,
+    pub dump_ast: bool,
+    pub show_precedence: bool, /* print `ParseNode::describe' on stderr */
+    pub is_explain: bool,
+    pub is_timed: bool,
     pub make_graph: bool,
     pub graph_file: String,
+    pub file: Option<String>, /* a file of expressions, one per line */
+    pub output: Option<String>, /* the file of `--output' instead of stdout */
+    pub cluster_parens: bool,
+    pub color_graph: bool,
+    pub color_errors: bool, /* see `--color', `auto' is resolved already */
+    pub no_parens: bool,    /* leave out the parentheses nodes of the graph */
+    pub rankdir: Option<String>, /* e.g. `LR' for a left-to-right graph */
+    pub no_pdf: bool,
+    pub accumulate: bool,
+    pub verbose: bool, /* print how the REPL parsed every input */
+    pub latex: bool,
+    pub prefix: bool,
+    pub rpn: bool,
+    pub strict_eof: bool,
+    pub bool_exit: bool,
+    pub style: OutputStyle, /* see `--radix', `--sci' and `--grouping' */
+    pub rational: bool,
+    pub wrapping: bool, /* wrap around on overflow instead of failing */
+    pub int_width: IntWidth,
+    pub optimize: bool, /* fold constant subtrees before the evaluation */
+    pub precision: Option<usize>,
+    pub max_depth: usize,
+    pub max_digits: Option<usize>, /* see `--limit-literal-digits' */
+    pub c_operators: bool,
+    pub grammar: Grammar,
+    pub prompt: String,
+    pub info: bool,
+    pub allow_rand: bool,
+    pub physics: bool, /* define the constants `c' and `g' */
+    pub vars: Vec<(String, i64)>, /* the variables of `--var', in order */
     pub progname: String,
 }
 
+impl Config {
+    /*
+     * Parse the arguments in `args' instead of the ones of the process, the
+     * first one being the program name like in `std::env::args'.
+     */
+    pub fn from_args<I, T>(args: I) -> Config
+        where I: IntoIterator<Item = T>,
+              T: Into<std::ffi::OsString> + Clone
+    {
+        build_config(&build_app().get_matches_from(args))
+    }
+}
+
 /* Parse CLi arguments and return them, wrapped in a `Config' struct. */
 pub fn get_configs() -> Config {
-    // define cli arguments using clap
-    let cli_args =
-        App::new("Expression Parser").version(VERSION)
+    build_config(&build_app().get_matches())
+}
+
+/* Define the CLi arguments using clap. */
+pub fn build_app() -> App<'static, 'static> {
+    App::new("Expression Parser").version(VERSION)
                               .author(AUTHOR)
                               .about(ABOUT)
                               .arg(Arg::with_name("EXPR").short("e")
                                                          .long("expression")
                                                          .help("The expression to evaluate")
                                                          .takes_value(true)
+                                                         .env(INPUT_VAR)
+                                                         .required(false))
+                              .arg(Arg::with_name("FILE").long("file")
+                                                         .help("File with one expression per line to evaluate")
+                                                         .takes_value(true)
                                                          .required(false))
+                              .arg(Arg::with_name("OUTPUT").long("output")
+                                                           .help("Write the results of `-e' or `--file' to this file instead of stdout")
+                                                           .takes_value(true)
+                                                           .value_name("FILE")
+                                                           .required(false))
                               .arg(Arg::with_name("DEBUG").short("d")
                                                           .long("debug")
                                                           .help("Debug mode (off by default)")
                                                           .takes_value(false)
                                                           .required(false))
+                              .arg(Arg::with_name("DUMP_AST").long("dump-ast")
+                                                             .help("Print only the parsed AST on stderr (like `--debug' does)")
+                                                             .takes_value(false)
+                                                             .required(false))
+                              .arg(Arg::with_name("SHOW_PRECEDENCE").long("show-precedence")
+                                                                    .help("Print the AST with the precedence level of every node on stderr")
+                                                                    .takes_value(false)
+                                                                    .required(false))
+                              .arg(Arg::with_name("EXPLAIN").long("explain")
+                                                            .help("Print every step of the evaluation on stderr, e.g. `2 + 12' for `2+3*4'")
+                                                            .takes_value(false)
+                                                            .conflicts_with("RATIONAL")
+                                                            .required(false))
+                              .arg(Arg::with_name("TIME").long("time")
+                                                         .help("Report the durations of lexing, parsing and evaluation on stderr")
+                                                         .takes_value(false)
+                                                         .required(false))
                               .arg(Arg::with_name("GRAPH").short("g")
                                                           .long("graph")
                                                           .help("Create an AST graph")
@@ -44,72 +144,521 @@ pub fn get_configs() -> Config {
                                                           .required(false))
                               .arg(Arg::with_name("G_FILE").short("f")
                                                            .long("graph_file")
-                                                           .help("File to save the graph to")
+                                                           .help("File to save the graph to (`.gv', `.pdf', `.svg' or `.png')")
+                                                           .takes_value(true)
+                                                           .required(false))
+                              .arg(Arg::with_name("CLUSTER_PARENS").long("graph-cluster-parens")
+                                                                   .help("Box parenthesized groups in the AST graph")
+                                                                   .takes_value(false)
+                                                                   .required(false))
+                              .arg(Arg::with_name("COLOR_GRAPH").long("graph-color")
+                                                                .help("Color the nodes of the AST graph by their precedence level")
+                                                                .takes_value(false)
+                                                                .required(false))
+                              .arg(Arg::with_name("NO_PARENS").long("no-parens")
+                                                              .help("Leave out the parentheses nodes of the AST graph")
+                                                              .takes_value(false)
+                                                              .conflicts_with("CLUSTER_PARENS")
+                                                              .required(false))
+                              .arg(Arg::with_name("RANKDIR").long("rankdir")
+                                                            .help("Direction of the AST graph, e.g. `LR' for left to right (top down by default)")
+                                                            .takes_value(true)
+                                                            .value_name("DIR")
+                                                            .possible_values(&["TB", "LR", "BT", "RL"])
+                                                            .required(false))
+                              .arg(Arg::with_name("NO_PDF").long("no-pdf")
+                                                           .help("Only write the graph description, don't run `dot'")
+                                                           .takes_value(false)
+                                                           .required(false))
+                              .arg(Arg::with_name("ACCUMULATE").long("accumulate")
+                                                               .help("Keep a running total of all results in an interactive session")
+                                                               .takes_value(false)
+                                                               .required(false))
+                              .arg(Arg::with_name("VERBOSE").long("verbose")
+                                                            .help("Print every input of an interactive session in infix notation and in RPN before its result")
+                                                            .takes_value(false)
+                                                            .required(false))
+                              .arg(Arg::with_name("LATEX").long("latex")
+                                                          .help("Print the expression as LaTeX math instead of evaluating it")
+                                                          .takes_value(false)
+                                                          .required(false))
+                              .arg(Arg::with_name("PREFIX").long("prefix")
+                                                           .help("Print the expression in prefix notation instead of evaluating it")
+                                                           .takes_value(false)
+                                                           .required(false))
+                              .arg(Arg::with_name("RPN").long("rpn")
+                                                        .help("Print the expression in postfix notation instead of evaluating it")
+                                                        .takes_value(false)
+                                                        .required(false))
+                              .arg(Arg::with_name("STRICT_EOF").long("strict-eof")
+                                                               .help("Reject anything but a single newline after the expression")
+                                                               .takes_value(false)
+                                                               .required(false))
+                              .arg(Arg::with_name("BOOL_EXIT").long("result-as-bool-exit")
+                                                              .alias("bool-exit")
+                                                              .help("Exit with 0 if the result is nonzero and with 1 if it is zero (errors exit with 2)")
+                                                              .takes_value(false)
+                                                              .required(false))
+                              .arg(Arg::with_name("RADIX").long("radix")
+                                                          .help("Base of the printed results")
+                                                          .takes_value(true)
+                                                          .possible_values(&["dec", "hex", "bin", "oct"])
+                                                          .default_value("dec")
+                                                          .required(false))
+                              .arg(Arg::with_name("COLOR").long("color")
+                                                          .help("Print errors and their indicator in red (`auto' does if stderr is a terminal)")
+                                                          .takes_value(true)
+                                                          .possible_values(&["auto", "always", "never"])
+                                                          .default_value("auto")
+                                                          .required(false))
+                              .arg(Arg::with_name("GROUPING").long("grouping")
+                                                             .help("Print decimal results with thousands separators, e.g. `1,000,000'")
+                                                             .takes_value(false)
+                                                             .required(false))
+                              .arg(Arg::with_name("SCI").long("sci")
+                                                        .help("Print decimal results in scientific notation, e.g. `1.5e9' (loses precision beyond 2^53)")
+                                                        .takes_value(false)
+                                                        .conflicts_with("GROUPING")
+                                                        .required(false))
+                              .arg(Arg::with_name("RATIONAL").long("rational")
+                                                             .help("Evaluate with exact fractions instead of truncating integer division")
+                                                             .takes_value(false)
+                                                             .required(false))
+                              .arg(Arg::with_name("WRAPPING").long("wrapping")
+                                                             .help("Wrap around on integer overflow, e.g. `9223372036854775807+1' is `-9223372036854775808'")
+                                                             .takes_value(false)
+                                                             .conflicts_with_all(&["RATIONAL", "EXPLAIN"])
+                                                             .required(false))
+                              .arg(Arg::with_name("INT_WIDTH").long("int-width")
+                                                              .help("Compute with integers of this many bits (64 by default)")
+                                                              .takes_value(true)
+                                                              .value_name("BITS")
+                                                              .possible_values(&["32", "64", "128"])
+                                                              .conflicts_with_all(&["RATIONAL", "EXPLAIN"])
+                                                              .required(false))
+                              .arg(Arg::with_name("STRICT_OVERFLOW").long("strict-overflow")
+                                                                    .help("Report integer overflows as errors (the default)")
+                                                                    .takes_value(false)
+                                                                    .conflicts_with("WRAPPING")
+                                                                    .required(false))
+                              .arg(Arg::with_name("OPTIMIZE").long("optimize")
+                                                             .help("Fold constant subexpressions like `2*3' before the evaluation")
+                                                             .takes_value(false)
+                                                             .conflicts_with_all(&["RATIONAL", "INT_WIDTH"])
+                                                             .required(false))
+                              .arg(Arg::with_name("PRECISION").long("precision")
+                                                              .help("Print fractional results of `--rational' as decimals with this many places")
+                                                              .takes_value(true)
+                                                              .required(false))
+                              .arg(Arg::with_name("MAX_DEPTH").long("max-depth")
+                                                              .help("Reject expressions that are nested deeper than this (1000 by default)")
+                                                              .takes_value(true)
+                                                              .required(false))
+                              .arg(Arg::with_name("MAX_DIGITS").long("limit-literal-digits")
+                                                               .help("Reject literals with more than this many digits")
+                                                               .takes_value(true)
+                                                               .value_name("N")
+                                                               .required(false))
+                              .arg(Arg::with_name("C_OPERATORS").long("c-operators")
+                                                                .help("Use C operators, i.e. `^' is a bitwise xor and `**' is the exponentiation")
+                                                                .takes_value(false)
+                                                                .required(false))
+                              .arg(Arg::with_name("GRAMMAR").long("grammar")
+                                                            .help("File that redefines operator symbols, precedence and associativity")
+                                                            .takes_value(true)
+                                                            .required(false))
+                              .arg(Arg::with_name("PROMPT").long("prompt")
+                                                           .help("Prompt of the interactive mode")
                                                            .takes_value(true)
+                                                           .default_value("> ")
                                                            .required(false))
-                              .get_matches();
+                              .arg(Arg::with_name("ALLOW_RAND").long("allow-rand")
+                                                               .help("Allow the function `rand(lo, hi)', whose results are not reproducible")
+                                                               .takes_value(false)
+                                                               .required(false))
+                              .arg(Arg::with_name("PHYSICS").long("physics")
+                                                            .help("Define the physical constants `c' (speed of light) and `g' (standard gravity)")
+                                                            .takes_value(false)
+                                                            .required(false))
+                              .arg(Arg::with_name("VAR").long("var")
+                                                        .help("Define a variable for the expressions, e.g. `--var x=3' (repeatable)")
+                                                        .takes_value(true)
+                                                        .multiple(true)
+                                                        .number_of_values(1)
+                                                        .value_name("NAME=VALUE")
+                                                        .required(false))
+                              .arg(Arg::with_name("INFO").long("info")
+                                                         .help("Print the version and the operator precedence levels and exit")
+                                                         .takes_value(false)
+                                                         .required(false))
+}
 
+/*
+ * Build a `Config' from the parsed arguments `cli_args'. Invalid values are
+ * reported and exit the process with status 1.
+ */
+pub fn build_config(cli_args: &ArgMatches) -> Config {
     // extract arguments and return config struct for main to use
+    // `-e' wins over `EXPR_PARSER_INPUT', which clap reads if `-e' is missing;
+    // invalid UTF-8 is replaced by `U+FFFD', which the lexer then rejects
+    // like any other unexpected character
     let expression = if cli_args.is_present("EXPR") {
-        cli_args.value_of("EXPR").unwrap().to_string()
+        cli_args.value_of_lossy("EXPR").unwrap().to_string()
     } else {
         String::from("")
     };
 
-    let is_debug = if cli_args.is_present("DEBUG") {
-        true
+    // an empty `EXPR_PARSER_INPUT' counts as missing, an empty `-e' doesn't
+    let has_expression =
+        cli_args.occurrences_of("EXPR") > 0 || !expression.is_empty();
+
+    let is_debug = cli_args.is_present("DEBUG");
+
+    let dump_ast = cli_args.is_present("DUMP_AST");
+
+    let show_precedence = cli_args.is_present("SHOW_PRECEDENCE");
+
+    let is_explain = cli_args.is_present("EXPLAIN");
+
+    let is_timed = cli_args.is_present("TIME");
+
+    let make_graph = cli_args.is_present("GRAPH");
+
+    let file = cli_args.value_of("FILE").map(|path| path.to_string());
+
+    let output = cli_args.value_of("OUTPUT").map(|path| path.to_string());
+
+    let graph_file = if cli_args.is_present("G_FILE") {
+        cli_args.value_of("G_FILE").unwrap().to_string()
     } else {
-        false
+        String::from("")
+    };
+
+    let cluster_parens = cli_args.is_present("CLUSTER_PARENS");
+
+    let color_graph = cli_args.is_present("COLOR_GRAPH");
+
+    let color_errors = match cli_args.value_of("COLOR") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => stderr().is_terminal(),
+    };
+
+    let no_parens = cli_args.is_present("NO_PARENS");
+
+    let rankdir = cli_args.value_of("RANKDIR").map(|dir| dir.to_string());
+
+    let no_pdf = cli_args.is_present("NO_PDF");
+
+    let accumulate = cli_args.is_present("ACCUMULATE");
+
+    let verbose = cli_args.is_present("VERBOSE");
+
+    let latex = cli_args.is_present("LATEX");
+
+    let prefix = cli_args.is_present("PREFIX");
+
+    let rpn = cli_args.is_present("RPN");
+
+    let strict_eof = cli_args.is_present("STRICT_EOF");
+
+    let bool_exit = cli_args.is_present("BOOL_EXIT");
+
+    // clap only accepts the possible values listed above and `--sci' and
+    // `--grouping' conflict, so these are all combinations of the three
+    let style = match cli_args.value_of("RADIX") {
+        Some("hex") => OutputStyle::Hex,
+        Some("bin") => OutputStyle::Binary,
+        Some("oct") => OutputStyle::Octal,
+        _ if cli_args.is_present("SCI") => OutputStyle::Scientific,
+        _ if cli_args.is_present("GROUPING") => OutputStyle::Grouped,
+        _ => OutputStyle::Decimal,
+    };
+
+    let rational = cli_args.is_present("RATIONAL");
+
+    let wrapping = cli_args.is_present("WRAPPING");
+
+    let int_width = match cli_args.value_of("INT_WIDTH") {
+        Some("32") => IntWidth::I32,
+        Some("128") => IntWidth::I128,
+        _ => IntWidth::I64,
     };
 
-    let make_graph = if cli_args.is_present("GRAPH") {
-        true
+    let optimize = cli_args.is_present("OPTIMIZE");
+
+    let precision = cli_args.value_of("PRECISION").map(|precision| {
+                        precision.parse::<usize>().unwrap_or_else(|_| {
+                            eprintln!("{}: error: Invalid precision `{}'",
+                                      PROGNAME, precision);
+                            exit(1);
+                        })
+                    });
+
+    let max_depth = match cli_args.value_of("MAX_DEPTH") {
+        Some(depth) => depth.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("{}: error: Invalid maximum depth `{}'", PROGNAME, depth);
+            exit(1);
+        }),
+        None => DEFAULT_MAX_DEPTH,
+    };
+
+    let max_digits = cli_args.value_of("MAX_DIGITS").map(|digits| {
+                         digits.parse::<usize>().unwrap_or_else(|_| {
+                             eprintln!("{}: error: Invalid number of digits `{}'",
+                                       PROGNAME, digits);
+                             exit(1);
+                         })
+                     });
+
+    let c_operators = cli_args.is_present("C_OPERATORS");
+    let base = if c_operators {
+        Grammar::c_operators()
     } else {
-        false
+        Grammar::default()
     };
 
-    let graph_file = if cli_args.is_present("G_FILE") {
-        cli_args.value_of("G_FILE").unwrap().to_string()
+    // a grammar file is loaded right away, so conflicts in it are reported
+    // before any expression is read
+    let grammar = if cli_args.is_present("GRAMMAR") {
+        match load_grammar(cli_args.value_of("GRAMMAR").unwrap(), base) {
+            Ok(grammar) => grammar,
+            Err(e) => {
+                eprintln!("{}: error: {}", PROGNAME, e);
+                exit(1);
+            }
+        }
     } else {
-        String::from("")
+        base
     };
 
+    let prompt = cli_args.value_of("PROMPT").unwrap().to_string();
+
+    let info = cli_args.is_present("INFO");
+
+    let allow_rand = cli_args.is_present("ALLOW_RAND");
+
+    let physics = cli_args.is_present("PHYSICS");
+
+    // malformed variables are reported before any expression is evaluated
+    let vars = cli_args.values_of("VAR")
+                       .into_iter()
+                       .flatten()
+                       .map(|var| {
+                           parse_var(var, physics).unwrap_or_else(|e| {
+                                                      eprintln!("{}: error: {}",
+                                                                PROGNAME, e);
+                                                      exit(1);
+                                                  })
+                       })
+                       .collect();
+
     Config { expression,
+             has_expression,
              is_debug,
+             dump_ast,
+             show_precedence,
+             is_explain,
+             is_timed,
              make_graph,
              graph_file,
+             file,
+             output,
+             cluster_parens,
+             color_graph,
+             color_errors,
+             no_parens,
+             rankdir,
+             no_pdf,
+             accumulate,
+             verbose,
+             latex,
+             prefix,
+             rpn,
+             strict_eof,
+             bool_exit,
+             style,
+             rational,
+             wrapping,
+             int_width,
+             optimize,
+             precision,
+             max_depth,
+             max_digits,
+             c_operators,
+             grammar,
+             prompt,
+             info,
+             allow_rand,
+             physics,
+             vars,
              progname: PROGNAME.to_string() }
 }
 
 /*
- * Prints a helpful error msg, based on the `ParserError' and the user `input'.
+ * Parse a variable definition `name=value' of `--var'. The name must be an
+ * identifier that isn't a constant (including the ones of `--physics' if
+ * `physics' is set) and the value an integer literal, which may be negative,
+ * e.g. `x=-3'.
+ */
+fn parse_var(var: &str, physics: bool) -> Result<(String, i64), String> {
+    let (name, value) = match var.split_once('=') {
+        Some((name, value)) => (name.trim(), value.trim()),
+        None => return Err(format!("Expected `name=value', found `{}'", var)),
+    };
+    match lex(name) {
+        Ok(tokens) if tokens == vec![Token::Ident(name.to_string())] => (),
+        Err(e) => {
+            return Err(format!("Invalid variable name `{}': {}", name, e.msg))
+        }
+        Ok(_) => return Err(format!("Invalid variable name `{}'", name)),
+    }
+    if is_constant(name) || (physics && is_physics_constant(name)) {
+        return Err(format!("Cannot assign to the constant `{}'", name));
+    }
+    match value.parse::<i64>() {
+        Ok(value) => Ok((name.to_string(), value)),
+        Err(_) => Err(format!("Value `{}' of `{}' is not an integer", value, name)),
+    }
+}
+
+/*
+ * Prints a helpful error msg, based on the `ParserError' and the user `input',
+ * which is lexed again with the `grammar' to place the indicator.
  */
-pub fn report_parser_err(err: ParserError, input: &String) {
+pub fn report_parser_err(err: ParserError, input: &String, grammar: &Grammar,
+                         color: bool) {
     // report the error back to the user
-    eprintln!("Token {}: {}.", err.token_no, err.msg);
+    eprintln!("{}",
+              red(&format!("Token {}: {}.", err.token_no, err.msg), color));
     eprintln!("\t{}", input);
 
-    // print an indicator where in the input the error happened
-    if err.lexer.len() != 0 {
-        let indicator = "-".repeat(get_position(err.lexer));
-        eprintln!("\t{}^", indicator);
-    } else {
-        let input_len = input.to_string().len();
-        if input_len > 1 {
-            let indicator = "-".repeat(input_len - 1);
-            eprintln!("\t{}^", indicator);
-        } else {
-            eprintln!("\t^");
-        }
-    }
+    // print an indicator where in the input the error happened, i.e. under
+    // the token that follows the tokens of the error
+    let column = get_column(&err.lexer, input, grammar);
+    eprintln!("\t{}", red(&format!("{}^", "-".repeat(column)), color));
 }
 
 /* Wraps `report_parser_err' and exits with the indicated status code. */
-pub fn exit_with_err(err: ParserError, input: &String, code: i32) {
-    report_parser_err(err, &input);
+pub fn exit_with_err(err: ParserError, input: &String, grammar: &Grammar,
+                     code: i32, color: bool) {
+    report_parser_err(err, input, grammar, color);
+    exit(code);
+}
+
+/*
+ * Like `report_parser_err', but for errors of the lexer. These know the byte
+ * offset of the offending character, so the indicator is placed exactly even
+ * if the `input' contains whitespace or multi-byte characters.
+ */
+pub fn report_lexer_err(err: &LexerError, input: &str, color: bool) {
+    eprintln!("{}",
+              red(&format!("Token {}: {}.", err.token_no, err.msg), color));
+    eprintln!("\t{}", input);
+
+    let column = input[..err.offset].chars().count();
+    eprintln!("\t{}", red(&format!("{}^", "-".repeat(column)), color));
+}
+
+/* Wraps `report_lexer_err' and exits with the indicated status code. */
+pub fn exit_with_lexer_err(err: &LexerError, input: &str, code: i32, color: bool)
+                           -> ! {
+    report_lexer_err(err, input, color);
     exit(code);
 }
 
+/*
+ * Print the `input' with an indicator under the operator that an `EvalError'
+ * is tied to (see `EvalError::get_token'). Most errors aren't tied to an
+ * operator, then nothing is printed.
+ */
+pub fn report_eval_err_pos(err: &EvalError, input: &str, grammar: &Grammar,
+                           color: bool) {
+    let spans = match (err.get_token(), lex_spanned_with(input, grammar)) {
+        (Some(token), Ok(spans)) => spans.get(token).cloned(),
+        _ => None,
+    };
+    if let Some((_, span)) = spans {
+        eprintln!("\t{}", input);
+        let column = input[..span.start].chars().count();
+        eprintln!("\t{}", red(&format!("{}^", "-".repeat(column)), color));
+    }
+}
+
+/*
+ * Wrap `text' in the ANSI escape sequences of red if `color' is set (see
+ * `--color'), otherwise it is returned as it is.
+ */
+pub fn red(text: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[31m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The lines that `--verbose` prints before a result in the interactive
+/// mode, which show how the input was parsed: the expression of `ast` in
+/// infix notation (with the parentheses that the precedence implies) and in
+/// reverse Polish notation.
+///
+/// ```
+/// use expr_parser::parser::parse_str;
+/// use expr_parser::utils::format_verbose;
+///
+/// let ast = parse_str("2+3*4").unwrap();
+/// assert_eq!(format_verbose(&ast), vec!["infix: 2 + 3 * 4", "rpn:   2 3 4 * +"]);
+/// ```
+pub fn format_verbose(ast: &ParseNode) -> Vec<String> {
+    vec![format!("infix: {}", ast.to_infix_string()),
+         format!("rpn:   {}", ast.to_rpn_string()),]
+}
+
+/*
+ * Render tokens as a table of their indices and names, e.g.
+ * `[0] Number(12)  [1] OpAdd  [2] Number(3)' for `12+3'. The indices are the
+ * ones that `ParserError's and `LexerError's count.
+ */
+pub fn format_tokens(tokens: &[Token]) -> String {
+    tokens.iter()
+          .enumerate()
+          .map(|(i, token)| format!("[{}] {:?}", i, token))
+          .collect::<Vec<String>>()
+          .join("  ")
+}
+
+/*
+ * The column of `input' right after the `tokens' that precede an error,
+ * which skips whitespace and comments like the lexer does. The parser splits
+ * a `||' into two `|' to close two absolute values, so a `||' of the input
+ * can stand for two of the `tokens'. If `input' can't be lexed, the column
+ * is estimated from the lengths of the `tokens'.
+ */
+fn get_column(tokens: &[Token], input: &str, grammar: &Grammar) -> usize {
+    let spans = match lex_spanned_with(input, grammar) {
+        Ok(spans) => spans,
+        Err(_) => return get_position(tokens.to_vec()),
+    };
+    let column = |offset: usize| input[..offset].chars().count();
+    let mut consumed = 0;
+    let mut end = 0;
+    for (token, span) in spans {
+        if consumed == tokens.len() {
+            return column(span.start);
+        }
+        let split =
+            token == Token::OpOr && tokens.get(consumed) == Some(&Token::Bar);
+        if split && consumed + 1 == tokens.len() {
+            // only the first `|' of the `||' precedes the error
+            return column(span.start + 1);
+        }
+        consumed += if split { 2 } else { 1 };
+        end = span.end;
+    }
+    // no token follows, so the error is at the end of the input
+    column(end)
+}
+
 fn get_position(vec: Vec<Token>) -> usize {
     let mut pos = 0;
     for token in vec {
@@ -125,11 +674,324 @@ fn get_position(vec: Vec<Token>) -> usize {
     pos
 }
 
-/* A thin wrapper around `create_graph' from the `draw' crate. */
-pub fn draw(ast: &ParseNode, path: &str, pdf: bool) {
-    let res = draw::create_graph(&ast, path, pdf);
+/*
+ * A thin wrapper around `create_graph' from the `draw' crate. The graph is
+ * written to the `graph_file' of `configs' and laid out as configured.
+ */
+pub fn draw(ast: &ParseNode, configs: &Config, render: bool) {
+    let options = GraphOptions { cluster_parens: configs.cluster_parens,
+                                 color_levels: configs.color_graph,
+                                 no_parens: configs.no_parens,
+                                 rankdir: configs.rankdir.clone() };
+    let res = draw::create_graph(ast, &configs.graph_file, render, &options);
     match res {
-        Ok(_) => eprintln!("Successfully wrote graph data to file."),
+        Ok(path) => eprintln!("Successfully wrote graph data to {}.", path),
         Err(e) => eprintln!("Failed to create graph: {}.", e),
     }
 }
+
+/*
+ * Print the version and the precedence levels of the operators in the
+ * grammar of `configs' (including `--c-operators' and `--grammar'), from the
+ * weakest to the tightest binding level. Empty levels are skipped.
+ */
+pub fn print_info(configs: &Config) {
+    println!("{} {}", PROGNAME, VERSION);
+    println!("Precedence levels (weakest first):");
+    println!("  -  {:<11}  {:<16}  right-associative",
+             "Conditional", "? :");
+    println!("  -  {:<11}  {:<16}  left-associative", "Disjunction", "||");
+    println!("  -  {:<11}  {:<16}  left-associative", "Conjunction", "&&");
+    for level in MIN_LEVEL..=MAX_LEVEL {
+        let operators: Vec<_> = configs.grammar
+                                       .get_operators()
+                                       .iter()
+                                       .filter(|op| op.level == level)
+                                       .collect();
+        if operators.is_empty() {
+            continue;
+        }
+        let symbols: Vec<&str> =
+            operators.iter().map(|op| op.symbol.as_str()).collect();
+        let assoc = if operators[0].right_assoc {
+            "right"
+        } else {
+            "left"
+        };
+        println!("  {}  {:<11}  {:<16}  {}-associative",
+                 level,
+                 get_level_name(level),
+                 symbols.join(" "),
+                 assoc);
+    }
+    println!("  {}  {:<11}  {:<16}  operands",
+             MAX_LEVEL + 1,
+             get_level_name(MAX_LEVEL + 1),
+             "x% (x) |x| f(x)");
+    println!("A unary `-' or `!' binds tighter than level {} and weaker than level {}.",
+             MAX_LEVEL - 1,
+             MAX_LEVEL);
+}
+
+/// Render a result in the given `style`. This is the only place where
+/// results are formatted, so every output mode prints them the same way:
+///
+/// ```
+/// use expr_parser::utils::{format_result, OutputStyle};
+///
+/// assert_eq!(format_result(1000000, OutputStyle::Decimal), "1000000");
+/// assert_eq!(format_result(1000000, OutputStyle::Hex), "0xF4240");
+/// assert_eq!(format_result(1000000, OutputStyle::Binary), "0b11110100001001000000");
+/// assert_eq!(format_result(1000000, OutputStyle::Octal), "0o3641100");
+/// assert_eq!(format_result(1000000, OutputStyle::Scientific), "1e6");
+/// assert_eq!(format_result(1000000, OutputStyle::Grouped), "1,000,000");
+/// assert_eq!(format_result(-255, OutputStyle::Hex), "-0xFF");
+/// ```
+///
+/// Negative numbers keep their sign in front of the prefix (`-0xFF`) instead
+/// of being printed in two's complement, so every output in another base can
+/// be read back as an expression of the same value. A scientific result is
+/// `n` as an `f64`, so integers beyond 2^53 are rounded:
+/// `9007199254740993` is `9.007199254740992e15`.
+pub fn format_result(n: i128, style: OutputStyle) -> String {
+    let sign = if n < 0 { "-" } else { "" };
+    let abs = n.unsigned_abs();
+    match style {
+        OutputStyle::Decimal => n.to_string(),
+        OutputStyle::Hex => format!("{}0x{:X}", sign, abs),
+        OutputStyle::Binary => format!("{}0b{:b}", sign, abs),
+        OutputStyle::Octal => format!("{}0o{:o}", sign, abs),
+        OutputStyle::Scientific => format!("{:e}", n as f64),
+        OutputStyle::Grouped => group_digits(&n.to_string()),
+    }
+}
+
+/*
+ * If `--time' was given, print how long each phase took, e.g. `lex: 12µs,
+ * parse: 30µs, eval: 5µs'. This goes to `stderr' to keep `stdout' clean.
+ */
+pub fn report_times(configs: &Config, times: &[(&str, Duration)]) {
+    if !configs.is_timed {
+        return;
+    }
+    let phases: Vec<String> =
+        times.iter()
+             .map(|(phase, time)| format!("{}: {}µs", phase, time.as_micros()))
+             .collect();
+    eprintln!("{}: {}", configs.progname, phases.join(", "));
+}
+
+/*
+ * Like `format_result' in the `style' of `configs', but fractions are
+ * printed as `num/den', e.g. `7/2'. With a `precision', decimal fractions
+ * are printed with that many decimal places instead, e.g. `0.3333' for `1/3'
+ * with a `precision' of 4. Only integers are printed in scientific notation,
+ * fractions are decimal then.
+ */
+pub fn format_value(value: &Value, configs: &Config) -> String {
+    let (r, style) = match (value, configs.style) {
+        (Value::Integer(n), style) => return format_result(*n, style),
+        (Value::Rational(r), style) if r.is_integer() => {
+            return format_result(r.num.into(), style)
+        }
+        (Value::Rational(r), OutputStyle::Scientific) => (r, OutputStyle::Decimal),
+        (Value::Rational(r), style) => (r, style),
+    };
+    match (style, configs.precision) {
+        (OutputStyle::Decimal, Some(precision)) => {
+            format!("{:.*}", precision, r.num as f64 / r.den as f64)
+        }
+        (OutputStyle::Grouped, Some(precision)) => {
+            group_digits(&format!("{:.*}", precision, r.num as f64 / r.den as f64))
+        }
+        _ => format!("{}/{}",
+                     format_result(r.num.into(), style),
+                     format_result(r.den.into(), style)),
+    }
+}
+
+/*
+ * Insert a `,' between every three digits of the integer part of a decimal
+ * number from the right, e.g. `-12,345' for `-12345' and `1,234.5' for
+ * `1234.5'. Numbers of up to three digits are returned as they are.
+ */
+pub fn group_digits(decimal: &str) -> String {
+    let (sign, rest) = match decimal.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", decimal),
+    };
+    let end = rest.find(|c: char| !c.is_ascii_digit())
+                  .unwrap_or(rest.len());
+    let (digits, tail) = rest.split_at(end);
+
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    format!("{}{}{}", sign, grouped, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rational::Rational;
+
+    fn configs(args: &[&str]) -> Config {
+        Config::from_args(std::iter::once("expr_parser").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn configs_are_built_from_the_arguments() {
+        let matches =
+            build_app().get_matches_from(vec!["expr_parser", "-e", "1+2", "-d"]);
+        let debug = build_config(&matches);
+        assert_eq!(debug.expression, "1+2");
+        assert!(debug.has_expression);
+        assert!(debug.is_debug);
+        assert!(!debug.make_graph);
+        assert!(!debug.rational);
+        assert_eq!(debug.file, None);
+        assert_eq!(debug.style, OutputStyle::Decimal);
+        assert_eq!(debug.max_depth, DEFAULT_MAX_DEPTH);
+        assert_eq!(debug.progname, "expr_parser");
+
+        let rational = configs(&["--rational", "--precision", "3", "--var", "x=4"]);
+        assert!(!rational.has_expression);
+        assert!(!rational.is_debug);
+        assert!(rational.rational);
+        assert_eq!(rational.precision, Some(3));
+        assert_eq!(rational.vars, vec![("x".to_string(), 4)]);
+    }
+
+    #[test]
+    fn results_are_printed_in_every_radix() {
+        let radixes = [("dec", OutputStyle::Decimal, "255", "-255"),
+                       ("hex", OutputStyle::Hex, "0xFF", "-0xFF"),
+                       ("bin", OutputStyle::Binary, "0b11111111", "-0b11111111"),
+                       ("oct", OutputStyle::Octal, "0o377", "-0o377")];
+        for (radix, style, positive, negative) in radixes.iter() {
+            assert_eq!(configs(&["--radix", radix]).style, *style);
+            assert_eq!(format_result(255, *style), *positive);
+            assert_eq!(format_result(-255, *style), *negative);
+        }
+        assert_eq!(configs(&[]).style, OutputStyle::Decimal);
+        assert_eq!(format_result(0, OutputStyle::Binary), "0b0");
+        assert_eq!(format_result(i64::MIN.into(), OutputStyle::Hex),
+                   "-0x8000000000000000");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn invalid_utf8_in_an_expression_is_replaced() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let args = vec![OsString::from("expr_parser"),
+                        OsString::from("-e"),
+                        OsString::from_vec(vec![b'1', b'+', 0xFF])];
+        assert_eq!(Config::from_args(args).expression, "1+\u{FFFD}");
+    }
+
+    #[test]
+    fn fractions_are_printed_with_the_precision() {
+        let third = Value::Rational(Rational { num: 1, den: 3 });
+        let half = Value::Rational(Rational { num: 7, den: 2 });
+        let two = Value::Rational(Rational::from_integer(2));
+        assert_eq!(format_value(&third, &configs(&["--rational"])), "1/3");
+        assert_eq!(format_value(&half, &configs(&["--rational"])), "7/2");
+        assert_eq!(format_value(&two, &configs(&["--rational"])), "2");
+        let precise = configs(&["--rational", "--precision", "4"]);
+        assert_eq!(format_value(&third, &precise), "0.3333");
+        assert_eq!(format_value(&half, &precise), "3.5000");
+    }
+
+    #[test]
+    fn sci_writes_the_exponent() {
+        let sci = |n| format_result(n, OutputStyle::Scientific);
+        assert_eq!(sci(1000000000), "1e9");
+        assert_eq!(sci(-1500), "-1.5e3");
+        assert_eq!(sci(0), "0e0");
+        // beyond 2^53, the `f64' is rounded
+        assert_eq!(sci(9007199254740993), "9.007199254740992e15");
+        assert_eq!(configs(&["--sci"]).style, OutputStyle::Scientific);
+        assert_eq!(configs(&[]).style, OutputStyle::Decimal);
+    }
+
+    #[test]
+    fn digits_are_grouped_by_three() {
+        let grouped = |n| format_result(n, OutputStyle::Grouped);
+        assert_eq!(grouped(1000000), "1,000,000");
+        assert_eq!(grouped(-12345), "-12,345");
+        assert_eq!(grouped(999), "999");
+        assert_eq!(group_digits("1234.5"), "1,234.5");
+        assert_eq!(configs(&["--grouping"]).style, OutputStyle::Grouped);
+    }
+
+    #[test]
+    fn only_colored_errors_are_red() {
+        assert_eq!(red("Token 2", true), "\x1b[31mToken 2\x1b[0m");
+        assert_eq!(red("Token 2", false), "Token 2");
+        assert!(configs(&["--color=always"]).color_errors);
+        assert!(!configs(&["--color=never"]).color_errors);
+    }
+
+    #[test]
+    fn trailing_tokens_are_shown_after_the_expression() {
+        let column_of = |input: &str| match crate::parser::parse_str(input) {
+            Ok(_) => panic!("`{}' parses", input),
+            Err(err) => {
+                (err.msg, get_column(&err.lexer, input, &Grammar::default()))
+            }
+        };
+        let (msg, column) = column_of("1 2");
+        assert_eq!(msg,
+                   "Trailing `2' after the end of the expression, an operator is \
+                    missing after `1'");
+        assert_eq!(column, 2);
+        assert_eq!(column_of("12  +  3   45").1, 11);
+    }
+
+    #[test]
+    fn tokens_are_formatted_with_their_indices() {
+        assert_eq!(format_tokens(&lex("12+3").unwrap()),
+                   "[0] Number(12)  [1] OpAdd  [2] Number(3)");
+        assert_eq!(format_tokens(&lex("max(x)").unwrap()),
+                   "[0] Ident(\"max\")  [1] LeftParen  [2] Ident(\"x\")  [3] \
+                    RightParen");
+        assert_eq!(format_tokens(&[]), "");
+    }
+
+    #[test]
+    fn variables_of_the_command_line_are_defined() {
+        let configs = configs(&["--var", "x=3", "--var", "y=4"]);
+        let ast = crate::parser::parse_str("x*y+1").unwrap();
+        assert!(matches!(crate::vm::evaluate_value(&ast, &configs),
+                         Ok(Value::Integer(13))));
+        assert_eq!(parse_var("x", false).unwrap_err(),
+                   "Expected `name=value', found `x'");
+        assert_eq!(parse_var("x=1.5", false).unwrap_err(),
+                   "Value `1.5' of `x' is not an integer");
+        assert!(parse_var("pi=3", false).is_err());
+    }
+
+    #[test]
+    fn verbose_shows_infix_and_rpn() {
+        let verbose =
+            |input| format_verbose(&crate::parser::parse_str(input).unwrap());
+        assert_eq!(verbose("2+3*4"),
+                   vec!["infix: 2 + 3 * 4", "rpn:   2 3 4 * +"]);
+        assert_eq!(verbose("(2+3)*4"),
+                   vec!["infix: (2 + 3) * 4", "rpn:   2 3 + 4 *"]);
+        assert_eq!(verbose("x"), vec!["infix: x", "rpn:   x"]);
+    }
+
+    #[test]
+    fn mod_can_not_be_a_variable() {
+        assert_eq!(parse_var("x=-3", false), Ok(("x".to_string(), -3)));
+        assert!(parse_var("mod=3", false).unwrap_err()
+                                         .contains("`mod' is reserved"));
+    }
+}
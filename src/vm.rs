@@ -1,147 +1,1960 @@
 /* vm.rs: The virtual machine which executes the syntax tree. */
-use crate::lexer::lex;
-use crate::parser::{parse, ParseNode, Terminal};
-use crate::utils::{report_parser_err, Config};
-use std::io::{stdin, stdout, Write};
+use crate::grammar::Grammar;
+use crate::lexer::lex_with_literal_limits;
+use crate::parser::{
+    parse_statement_with, Assignment, ParseNode, ParserError, Terminal,
+    DEFAULT_MAX_DEPTH,
+};
+use crate::rational::Rational;
+#[cfg(feature = "std")]
+use crate::utils::Config;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::convert::TryFrom;
+use core::fmt;
+use core::num::Wrapping;
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hasher};
 
-/* Stores keywords that are interpreted alongside the expressions. */
-struct Keywords {
-    quit: Vec<String>, /* "quit", "q" */
+/* Errors that can occur while an AST is evaluated. */
+#[derive(Debug, PartialEq)]
+pub enum EvalError {
+    DivisionByZero,
+    ModuloByZero,
+    Overflow,                        /* the result doesn't fit the width */
+    OverflowAt(&'static str, usize), /* likewise, of the operator at that token */
+    NegativeExponent(i128),          /* would have a fractional result */
+    InexactPercent(i128),            /* `n/100' of a percentage isn't whole */
+    ExponentTooLarge(i128),          /* doesn't fit an `u32' */
+    FractionalExponent(Rational),    /* would have an irrational result */
+    FractionalOperand(Rational),     /* of a bitwise operator */
+    InvalidShift(i128, u32),         /* not from 0 to the width minus 1 */
+    OutOfRange(i128, u32),           /* doesn't fit the width, see `IntWidth' */
+    UnknownFunction(String),
+    UnknownVariable(String),
+    AssignToConstant(String),
+    WrongArgCount(String, &'static str, usize), /* the expected and actual count */
+    FractionalArgument(String, Rational), /* of a function that needs integers */
+    InvalidBounds(String, String),        /* a lower bound above the upper one */
+    RandDisabled,                         /* `rand' without `--allow-rand' */
+    StepLimitExceeded,                    /* see `evaluate_with_limit' */
+    MalformedTree(String),                /* the AST is not a valid expression */
 }
 
-/* Run the virtual machine, including interpreter loop & lexing & parsing. */
-pub fn run(configs: &Config) {
-    let keywords = init();
-    eprintln!("{}: Exit with ctrl+c or by typing `quit' or `q'.",
-              configs.progname);
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "vm: Divison by 0"),
+            EvalError::ModuloByZero => write!(f, "vm: Modulo by zero"),
+            EvalError::Overflow => write!(f, "vm: Integer overflow"),
+            EvalError::OverflowAt(verb, token) => {
+                write!(f, "vm: Integer overflow while {} at token {}", verb, token)
+            }
+            EvalError::NegativeExponent(n) => {
+                write!(f, "vm: Negative exponent {} has no integer result", n)
+            }
+            EvalError::InexactPercent(n) => {
+                write!(f,
+                       "vm: Percentage {}/100 has no integer result (use \
+                        `--rational' for fractions)",
+                       n)
+            }
+            EvalError::ExponentTooLarge(n) => {
+                write!(f, "vm: Exponent {} is too large", n)
+            }
+            EvalError::FractionalExponent(r) => {
+                write!(f, "vm: Exponent {} is not an integer", r)
+            }
+            EvalError::FractionalOperand(r) => {
+                write!(f,
+                       "vm: Operand {} of a bitwise operator is not an integer",
+                       r)
+            }
+            EvalError::InvalidShift(n, width) => {
+                write!(f,
+                       "vm: Cannot shift by {} bits, only by 0 to {}",
+                       n,
+                       width - 1)
+            }
+            EvalError::OutOfRange(n, width) => {
+                write!(f, "vm: {} doesn't fit a {} bit integer", n, width)
+            }
+            EvalError::UnknownFunction(name) => {
+                write!(f, "vm: Unknown function `{}'", name)
+            }
+            EvalError::UnknownVariable(name) => {
+                write!(f, "vm: Unknown variable `{}'", name)
+            }
+            EvalError::AssignToConstant(name) => {
+                write!(f, "vm: Cannot assign to the constant `{}'", name)
+            }
+            EvalError::RandDisabled => {
+                write!(f, "vm: `rand' is only available with `--allow-rand'")
+            }
+            EvalError::FractionalArgument(name, r) => {
+                write!(f, "vm: Argument {} of `{}' is not an integer", r, name)
+            }
+            EvalError::WrongArgCount(name, expected, found) => {
+                write!(f, "vm: `{}' expects {}, found {}", name, expected, found)
+            }
+            EvalError::InvalidBounds(lower, upper) => {
+                write!(f,
+                       "vm: Lower bound {} is greater than upper bound {}",
+                       lower, upper)
+            }
+            EvalError::StepLimitExceeded => {
+                write!(f, "vm: Evaluation exceeded its limit of steps")
+            }
+            EvalError::MalformedTree(msg) => write!(f, "vm: {}", msg),
+        }
+    }
+}
+
+impl EvalError {
+    /* The token of the operator that caused the error, if it is known. */
+    pub fn get_token(&self) -> Option<usize> {
+        match self {
+            EvalError::OverflowAt(_, token) => Some(*token),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * The width of the integers that an expression is evaluated with (see
+ * `--int-width'). Every width is computed with `i128's and checked against
+ * its own bounds (see `apply_in'), so variables and results must fit it.
+ * Literals are checked by the lexer (see `Lexer::set_max_literal'), they are
+ * `i64's for every width.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntWidth {
+    I32,
+    I64,
+    I128,
+}
+
+impl IntWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            IntWidth::I32 => 32,
+            IntWidth::I64 => 64,
+            IntWidth::I128 => 128,
+        }
+    }
+
+    /* The greatest literal that fits the width, e.g. `2147483647' for 32 bits. */
+    pub fn max_literal(self) -> i64 {
+        match self {
+            IntWidth::I32 => i64::from(i32::MAX),
+            IntWidth::I64 | IntWidth::I128 => i64::MAX,
+        }
+    }
+
+    /* `n' modulo 2^bits, i.e. the bits above the width are cut off. */
+    fn wrap(self, n: i128) -> i128 {
+        match self {
+            IntWidth::I32 => i128::from(n as i32),
+            IntWidth::I64 => i128::from(n as i64),
+            IntWidth::I128 => n,
+        }
+    }
+
+    fn fits(self, n: i128) -> bool {
+        self.wrap(n) == n
+    }
+
+    /* Fail if `n' doesn't fit the width, e.g. a `--var' of `--int-width 32'. */
+    fn check(self, n: i128) -> Result<i128, EvalError> {
+        if self.fits(n) {
+            Ok(n)
+        } else {
+            Err(EvalError::OutOfRange(n, self.bits()))
+        }
+    }
+}
 
-    // the read-eval-print loop
-    loop {
-        let input = prompt_and_read("> ");
+/* The result of an evaluation, depending on the mode (see `--rational'). */
+#[derive(Debug, Clone, Copy)]
+pub enum Value {
+    Integer(i128), /* of any width, see `IntWidth' */
+    Rational(Rational),
+}
 
-        // check if the input is a keyword
-        if matches_any(&input, &keywords.quit) {
-            return; /* the only exit condition */
+impl Value {
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Value::Integer(n) => *n == 0,
+            Value::Rational(r) => r.is_zero(),
         }
+    }
+}
+
+/*
+ * The number types that an AST can be evaluated with. Integers truncate on
+ * division while rationals stay exact, otherwise they need the same checks.
+ * Integers (of variables and of the results of functions) fail if they don't
+ * fit.
+ */
+trait Arithmetic: Sized + Copy + PartialOrd + fmt::Display {
+    fn from_integer(n: i128) -> Result<Self, EvalError>;
+    fn from_bool(b: bool) -> Self;
+    fn from_fraction(num: i64, den: i64) -> Result<Self, EvalError>;
+    fn to_integer(self, function: &str) -> Result<i64, EvalError>;
+    fn apply(op: &Terminal, lhs: Self, rhs: Self) -> Result<Self, EvalError>;
+    fn negate(self) -> Result<Self, EvalError>;
+    fn abs(self) -> Result<Self, EvalError>;
+    fn percent(self) -> Result<Self, EvalError>;
+    fn bit_not(self) -> Result<Self, EvalError>;
+}
+
+impl Arithmetic for i64 {
+    fn from_integer(n: i128) -> Result<i64, EvalError> {
+        IntWidth::I64.check(n).map(|n| n as i64)
+    }
+
+    fn from_bool(b: bool) -> i64 {
+        b as i64
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<i64, EvalError> {
+        apply(&Terminal::Div, num, den)
+    }
+
+    fn to_integer(self, _: &str) -> Result<i64, EvalError> {
+        Ok(self)
+    }
+
+    fn apply(op: &Terminal, lhs: i64, rhs: i64) -> Result<i64, EvalError> {
+        apply(op, lhs, rhs)
+    }
+
+    fn negate(self) -> Result<i64, EvalError> {
+        negate(self)
+    }
+
+    fn abs(self) -> Result<i64, EvalError> {
+        abs(self)
+    }
 
-        // lex and parse the input
-        let tokens = lex(&input);
-        let res = parse(tokens);
+    fn percent(self) -> Result<i64, EvalError> {
+        Ok(hundredth(i128::from(self))? as i64)
+    }
 
-        // TODO: clean this code up
-        if let Ok(ast) = res {
-            let res = evaluate(&ast);
-            if let Ok(res) = res {
-                eprintln!("\t{}", res);
-            } else if let Err(e) = res {
-                eprintln!("{}: error: {}", configs.progname, e);
+    fn bit_not(self) -> Result<i64, EvalError> {
+        Ok(!self)
+    }
+}
+
+impl Arithmetic for Rational {
+    fn from_integer(n: i128) -> Result<Rational, EvalError> {
+        i64::from_integer(n).map(Rational::from_integer)
+    }
+
+    fn from_bool(b: bool) -> Rational {
+        Rational::from_integer(b as i64)
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<Rational, EvalError> {
+        Rational::new(num, den)
+    }
+
+    fn to_integer(self, function: &str) -> Result<i64, EvalError> {
+        if self.is_integer() {
+            Ok(self.num)
+        } else {
+            Err(EvalError::FractionalArgument(function.to_string(), self))
+        }
+    }
+
+    fn apply(op: &Terminal, lhs: Rational, rhs: Rational)
+             -> Result<Rational, EvalError> {
+        match op {
+            Terminal::Sum => lhs.checked_add(rhs),
+            Terminal::Sub => lhs.checked_sub(rhs),
+            Terminal::Mod => lhs.checked_rem(rhs),
+            Terminal::Mult => lhs.checked_mul(rhs),
+            Terminal::Div => lhs.checked_div(rhs),
+            Terminal::Exp => lhs.checked_pow(rhs),
+            Terminal::Xor => lhs.checked_xor(rhs),
+            Terminal::Shl | Terminal::Shr | Terminal::Ushr => {
+                let res = apply(op, lhs.to_integer()?, rhs.to_integer()?)?;
+                Ok(Rational::from_integer(res))
             }
-        } else if let Err(e) = res {
-            report_parser_err(e, &input);
+            Terminal::AddPercent => {
+                lhs.checked_add(lhs.checked_mul(rhs)?.percent()?)
+            }
+            Terminal::SubPercent => {
+                lhs.checked_sub(lhs.checked_mul(rhs)?.percent()?)
+            }
+            _ => Err(EvalError::MalformedTree(String::from("Expected an operator"))),
         }
     }
+
+    fn negate(self) -> Result<Rational, EvalError> {
+        self.checked_neg()
+    }
+
+    fn abs(self) -> Result<Rational, EvalError> {
+        self.checked_abs()
+    }
+
+    fn percent(self) -> Result<Rational, EvalError> {
+        self.checked_div(Rational::from_integer(100))
+    }
+
+    fn bit_not(self) -> Result<Rational, EvalError> {
+        Ok(Rational::from_integer(!self.to_integer()?))
+    }
+}
+
+/*
+ * Integers of `--wrapping', whose arithmetic wraps around at the bounds of an
+ * `i64' instead of failing, i.e. it is modulo 2^64.
+ */
+impl Arithmetic for Wrapping<i64> {
+    fn from_integer(n: i128) -> Result<Wrapping<i64>, EvalError> {
+        i64::from_integer(n).map(Wrapping)
+    }
+
+    fn from_bool(b: bool) -> Wrapping<i64> {
+        Wrapping(b as i64)
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<Wrapping<i64>, EvalError> {
+        let res =
+            apply_wrapping_in(&Terminal::Div, num.into(), den.into(), IntWidth::I64);
+        res.map(|n| Wrapping(n as i64))
+    }
+
+    fn to_integer(self, _: &str) -> Result<i64, EvalError> {
+        Ok(self.0)
+    }
+
+    fn apply(op: &Terminal, lhs: Wrapping<i64>, rhs: Wrapping<i64>)
+             -> Result<Wrapping<i64>, EvalError> {
+        let res = apply_wrapping_in(op, lhs.0.into(), rhs.0.into(), IntWidth::I64);
+        res.map(|n| Wrapping(n as i64))
+    }
+
+    fn negate(self) -> Result<Wrapping<i64>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_neg()))
+    }
+
+    fn abs(self) -> Result<Wrapping<i64>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_abs()))
+    }
+
+    fn percent(self) -> Result<Wrapping<i64>, EvalError> {
+        self.0.percent().map(Wrapping)
+    }
+
+    fn bit_not(self) -> Result<Wrapping<i64>, EvalError> {
+        Ok(!self)
+    }
+}
+
+/*
+ * Integers of `--int-width 32'. They are computed like every width (see
+ * `apply_in') and fail if a result doesn't fit 32 bits.
+ */
+impl Arithmetic for i32 {
+    fn from_integer(n: i128) -> Result<i32, EvalError> {
+        IntWidth::I32.check(n).map(|n| n as i32)
+    }
+
+    fn from_bool(b: bool) -> i32 {
+        b as i32
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<i32, EvalError> {
+        i32::from_integer(apply(&Terminal::Div, num, den)?.into())
+    }
+
+    fn to_integer(self, _: &str) -> Result<i64, EvalError> {
+        Ok(i64::from(self))
+    }
+
+    fn apply(op: &Terminal, lhs: i32, rhs: i32) -> Result<i32, EvalError> {
+        apply_in(op, lhs.into(), rhs.into(), IntWidth::I32).map(|n| n as i32)
+    }
+
+    fn negate(self) -> Result<i32, EvalError> {
+        self.checked_neg().ok_or(EvalError::Overflow)
+    }
+
+    fn abs(self) -> Result<i32, EvalError> {
+        self.checked_abs().ok_or(EvalError::Overflow)
+    }
+
+    fn percent(self) -> Result<i32, EvalError> {
+        Ok(hundredth(i128::from(self))? as i32)
+    }
+
+    fn bit_not(self) -> Result<i32, EvalError> {
+        Ok(!self)
+    }
+}
+
+/* Integers of `--int-width 32' with `--wrapping', i.e. modulo 2^32. */
+impl Arithmetic for Wrapping<i32> {
+    fn from_integer(n: i128) -> Result<Wrapping<i32>, EvalError> {
+        i32::from_integer(n).map(Wrapping)
+    }
+
+    fn from_bool(b: bool) -> Wrapping<i32> {
+        Wrapping(b as i32)
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<Wrapping<i32>, EvalError> {
+        i32::from_fraction(num, den).map(Wrapping)
+    }
+
+    fn to_integer(self, _: &str) -> Result<i64, EvalError> {
+        Ok(i64::from(self.0))
+    }
+
+    fn apply(op: &Terminal, lhs: Wrapping<i32>, rhs: Wrapping<i32>)
+             -> Result<Wrapping<i32>, EvalError> {
+        let res = apply_wrapping_in(op, lhs.0.into(), rhs.0.into(), IntWidth::I32);
+        res.map(|n| Wrapping(n as i32))
+    }
+
+    fn negate(self) -> Result<Wrapping<i32>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_neg()))
+    }
+
+    fn abs(self) -> Result<Wrapping<i32>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_abs()))
+    }
+
+    fn percent(self) -> Result<Wrapping<i32>, EvalError> {
+        self.0.percent().map(Wrapping)
+    }
+
+    fn bit_not(self) -> Result<Wrapping<i32>, EvalError> {
+        Ok(!self)
+    }
+}
+
+/*
+ * Integers of `--int-width 128'. Literals and the arguments of functions
+ * like `gcd' are still `i64's, but results may be as large as, e.g.,
+ * `2^100'.
+ */
+impl Arithmetic for i128 {
+    fn from_integer(n: i128) -> Result<i128, EvalError> {
+        Ok(n)
+    }
+
+    fn from_bool(b: bool) -> i128 {
+        b as i128
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<i128, EvalError> {
+        apply_in(&Terminal::Div, num.into(), den.into(), IntWidth::I128)
+    }
+
+    fn to_integer(self, _: &str) -> Result<i64, EvalError> {
+        i64::try_from(self).map_err(|_| EvalError::OutOfRange(self, 64))
+    }
+
+    fn apply(op: &Terminal, lhs: i128, rhs: i128) -> Result<i128, EvalError> {
+        apply_in(op, lhs, rhs, IntWidth::I128)
+    }
+
+    fn negate(self) -> Result<i128, EvalError> {
+        self.checked_neg().ok_or(EvalError::Overflow)
+    }
+
+    fn abs(self) -> Result<i128, EvalError> {
+        self.checked_abs().ok_or(EvalError::Overflow)
+    }
+
+    fn percent(self) -> Result<i128, EvalError> {
+        hundredth(self)
+    }
+
+    fn bit_not(self) -> Result<i128, EvalError> {
+        Ok(!self)
+    }
+}
+
+/* Integers of `--int-width 128' with `--wrapping', i.e. modulo 2^128. */
+impl Arithmetic for Wrapping<i128> {
+    fn from_integer(n: i128) -> Result<Wrapping<i128>, EvalError> {
+        Ok(Wrapping(n))
+    }
+
+    fn from_bool(b: bool) -> Wrapping<i128> {
+        Wrapping(b as i128)
+    }
+
+    fn from_fraction(num: i64, den: i64) -> Result<Wrapping<i128>, EvalError> {
+        i128::from_fraction(num, den).map(Wrapping)
+    }
+
+    fn to_integer(self, function: &str) -> Result<i64, EvalError> {
+        self.0.to_integer(function)
+    }
+
+    fn apply(op: &Terminal, lhs: Wrapping<i128>, rhs: Wrapping<i128>)
+             -> Result<Wrapping<i128>, EvalError> {
+        apply_wrapping_in(op, lhs.0, rhs.0, IntWidth::I128).map(Wrapping)
+    }
+
+    fn negate(self) -> Result<Wrapping<i128>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_neg()))
+    }
+
+    fn abs(self) -> Result<Wrapping<i128>, EvalError> {
+        Ok(Wrapping(self.0.wrapping_abs()))
+    }
+
+    fn percent(self) -> Result<Wrapping<i128>, EvalError> {
+        hundredth(self.0).map(Wrapping)
+    }
+
+    fn bit_not(self) -> Result<Wrapping<i128>, EvalError> {
+        Ok(!self)
+    }
+}
+
+/*
+ * A calculator that keeps the variables of its statements between calls of
+ * `eval', so it can be embedded where the interactive mode (`repl::run')
+ * can't. It parses and evaluates like `run' with the settings of the
+ * `Config' that it was created from (by default, those of an empty command
+ * line). Without `std', only the defaults are available.
+ */
+pub struct Interpreter {
+    env: BTreeMap<String, Value>, /* the variables, see `assign' */
+    grammar: Grammar,
+    max_depth: usize,
+    rational: bool,
+    wrapping: bool,
+    int_width: IntWidth,
+    allow_rand: bool,
+    physics: bool,             /* see `PHYSICS' */
+    max_digits: Option<usize>, /* see `Lexer::set_max_digits' */
+}
+
+/* What a statement of an `Interpreter' did. */
+pub enum Statement {
+    Empty,                            /* a blank line or just a comment */
+    Expression(Value),                /* the result of an expression */
+    Assignment(Vec<(String, Value)>), /* the assigned variables, see `assign' */
+}
+
+/* Errors of an `Interpreter': the input is malformed or can't be evaluated. */
+pub enum InterpreterError {
+    Parser(ParserError),
+    Eval(EvalError),
+}
+
+impl From<ParserError> for InterpreterError {
+    fn from(e: ParserError) -> InterpreterError {
+        InterpreterError::Parser(e)
+    }
+}
+
+impl From<EvalError> for InterpreterError {
+    fn from(e: EvalError) -> InterpreterError {
+        InterpreterError::Eval(e)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter { env: BTreeMap::new(),
+                      grammar: Grammar::default(),
+                      max_depth: DEFAULT_MAX_DEPTH,
+                      rational: false,
+                      wrapping: false,
+                      int_width: IntWidth::I64,
+                      allow_rand: false,
+                      physics: false,
+                      max_digits: None }
+    }
+
+    /* An interpreter with the grammar and evaluation mode of `configs'. */
+    #[cfg(feature = "std")]
+    pub fn from_config(configs: &Config) -> Interpreter {
+        Interpreter { env: get_vars(configs),
+                      grammar: configs.grammar.clone(),
+                      max_depth: configs.max_depth,
+                      rational: configs.rational,
+                      wrapping: configs.wrapping,
+                      int_width: configs.int_width,
+                      allow_rand: configs.allow_rand,
+                      physics: configs.physics,
+                      max_digits: configs.max_digits }
+    }
+
+    /*
+     * Evaluate a statement and return its result or `None' if it only
+     * assigned variables (or was empty), e.g. `x=5' and then `x+1' is 6.
+     */
+    pub fn eval(&mut self, input: &str) -> Result<Option<Value>, InterpreterError> {
+        match self.execute(input)? {
+            Statement::Expression(value) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /*
+     * Parse a statement like `execute' does, but without evaluating it, e.g.
+     * to show how the input is read (see `--verbose').
+     */
+    pub fn parse(&self, input: &str)
+                 -> Result<(Vec<Assignment>, ParseNode), ParserError> {
+        parse_statement_with(lex_with_literal_limits(input,
+                                                     &self.grammar,
+                                                     self.max_digits,
+                                                     self.int_width.max_literal()),
+                             &self.grammar,
+                             self.max_depth)
+    }
+
+    /* Like `eval', but the assigned variables are returned, too. */
+    pub fn execute(&mut self, input: &str) -> Result<Statement, InterpreterError> {
+        let tokens = lex_with_literal_limits(input,
+                                             &self.grammar,
+                                             self.max_digits,
+                                             self.int_width.max_literal());
+        if let Ok(tokens) = &tokens {
+            if tokens.is_empty() {
+                return Ok(Statement::Empty);
+            }
+        }
+        let (assignments, ast) =
+            parse_statement_with(tokens, &self.grammar, self.max_depth)?;
+        let value = evaluate_in_mode(&ast,
+                                     self.rational,
+                                     self.wrapping,
+                                     self.int_width,
+                                     self.allow_rand,
+                                     &Physics { variables: &self.env,
+                                                enabled: self.physics })?;
+        // like the `CONSTANTS', the `PHYSICS' constants can't be shadowed
+        let shadowed =
+            assignments.iter()
+                       .find(|assignment| {
+                           self.physics && is_physics_constant(&assignment.name)
+                       });
+        if let Some(assignment) = shadowed {
+            return Err(EvalError::AssignToConstant(assignment.name.clone()).into());
+        }
+        if assignments.is_empty() {
+            Ok(Statement::Expression(value))
+        } else {
+            Ok(Statement::Assignment(assign(&assignments,
+                                            value,
+                                            &mut self.env,
+                                            self.wrapping,
+                                            self.int_width)?))
+        }
+    }
+
+    pub fn get_variables(&self) -> &BTreeMap<String, Value> {
+        &self.env
+    }
+
+    pub fn clear_variables(&mut self) {
+        self.env.clear();
+    }
 }
 
 /* Evaluate an expression, represented by an abstract syntax tree. */
-// TODO: fix bugs! Simple addition works but nothing else.
-pub fn evaluate(node: &ParseNode) -> Result<i64, String> {
-    let mut stack: Vec<&Terminal> = vec![];
-    build_exec_stack(&node, &mut stack);
-
-    let mut result: i64 = 0;
-    if let Terminal::Literal(n) = stack.pop().unwrap() {
-        result += n; /* first item on stack _must_ be a literal */
-    }
-
-    // pop off the rest of the stack
-    loop {
-        let next = stack.pop();
-        match next {
-            None => break, /* here, the stack is empty */
-            Some(val) => {
-                match val {
-                    Terminal::Literal(n) => {
-                        // stack cannot be empty here, so unwrapping is save
-                        let op = stack.pop().unwrap();
-                        match op {
-                            Terminal::Sum => {
-                                result += n;
-                            }
-                            Terminal::Sub => {
-                                result -= n;
-                            }
-                            Terminal::Mod => {
-                                result %= n;
-                            }
-                            Terminal::Mult => {
-                                result *= n;
-                            }
-                            Terminal::Div => {
-                                if *n == 0 {
-                                    return Err(String::from("vm: Divison by 0"));
-                                }
-                                result /= n;
-                            }
-                            Terminal::Exp => {
-                                result = result.pow(*n as u32);
-                            }
-                            Terminal::Paren => continue, /* parens are ignored */
-                            Terminal::NonTerminal => continue, /* FIXME: non-terminals are ignored */
-                            Terminal::Literal(n) => {
-                                return Err(String::from(
-                                        format!("vm: Unexpected integer literal {}", n)
-                                        ));
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err(String::from("vm: Expected integer literal"));
-                    }
+pub fn evaluate(node: &ParseNode) -> Result<i64, EvalError> {
+    evaluate_as::<i64>(node, false, &BTreeMap::<String, Value>::new())
+}
+
+/// Like `evaluate`, but fail with `EvalError::StepLimitExceeded` if the
+/// evaluation takes more than `max_steps` steps. Every node of the tree that
+/// is evaluated is a step, so the work for untrusted input is bounded even if
+/// its tree is valid but enormous.
+///
+/// ```
+/// use expr_parser::parser::parse_str;
+/// use expr_parser::vm::{evaluate_with_limit, EvalError};
+///
+/// let input = vec!["1"; 1000].join("+");
+/// let ast = parse_str(&input).unwrap();
+/// assert_eq!(evaluate_with_limit(&ast, 100), Err(EvalError::StepLimitExceeded));
+/// assert_eq!(evaluate_with_limit(&ast, 10000), Ok(1000));
+/// ```
+pub fn evaluate_with_limit(node: &ParseNode, max_steps: usize)
+                           -> Result<i64, EvalError> {
+    let mut steps = max_steps;
+    evaluate_counted::<i64>(node,
+                            false,
+                            &BTreeMap::<String, Value>::new(),
+                            &mut steps)
+}
+
+/* Evaluate an expression exactly, i.e. `7/2' is not truncated to `3'. */
+pub fn evaluate_rational(node: &ParseNode) -> Result<Rational, EvalError> {
+    evaluate_as::<Rational>(node, false, &BTreeMap::<String, Value>::new())
+}
+
+/// Evaluate the same expression in each of the environments `envs`, e.g.
+/// for a plot of `x^2` at every `x`. The result for an environment is the
+/// same as `evaluate` with its variables, but the tree is only prepared once
+/// and the buffers of the evaluation are reused between the environments.
+/// An environment is anything that looks up integers by name (see
+/// `Environment`), e.g. a `BTreeMap` or, with `std`, a `HashMap`.
+///
+/// ```
+/// use expr_parser::parser::parse_str;
+/// use expr_parser::vm::evaluate_many;
+/// use std::collections::HashMap;
+///
+/// let ast = parse_str("x*x+1").unwrap();
+/// let envs: Vec<HashMap<String, i64>> =
+///     (0..3).map(|x| HashMap::from([("x".to_string(), x)])).collect();
+/// let results: Vec<i64> =
+///     evaluate_many(&ast, &envs).into_iter().flatten().collect();
+/// assert_eq!(results, vec![1, 2, 5]);
+/// ```
+pub fn evaluate_many<E: Environment>(node: &ParseNode, envs: &[E])
+                                     -> Vec<Result<i64, EvalError>> {
+    let mut stack: Vec<&ParseNode> = vec![];
+    build_exec_stack(node, &mut stack);
+    let mut operands: Vec<i64> = vec![];
+    envs.iter()
+        .map(|env| {
+            let mut steps = usize::MAX;
+            run_exec_stack(&stack, &mut operands, false, &Integers(env), &mut steps)
+        })
+        .collect()
+}
+
+/*
+ * Evaluate an expression in the mode that was configured. Only this allows
+ * `rand' (with `--allow-rand'), the other functions are reproducible.
+ */
+#[cfg(feature = "std")]
+pub fn evaluate_value(node: &ParseNode, configs: &Config)
+                      -> Result<Value, EvalError> {
+    evaluate_in(node, configs, &get_vars(configs))
+}
+
+/* The variables of `--var', which are defined in every expression. */
+#[cfg(feature = "std")]
+fn get_vars(configs: &Config) -> BTreeMap<String, Value> {
+    configs.vars
+           .iter()
+           .map(|(name, n)| (name.clone(), Value::Integer(i128::from(*n))))
+           .collect()
+}
+
+/* Like `evaluate_value', but identifiers can also name the `variables'. */
+#[cfg(feature = "std")]
+pub fn evaluate_in(node: &ParseNode, configs: &Config,
+                   variables: &BTreeMap<String, Value>)
+                   -> Result<Value, EvalError> {
+    evaluate_in_mode(node,
+                     configs.rational,
+                     configs.wrapping,
+                     configs.int_width,
+                     configs.allow_rand,
+                     &Physics { variables,
+                                enabled: configs.physics })
+}
+
+/*
+ * Evaluate with exact fractions if `rational' is set and with integers of
+ * the `width' otherwise, which either `wrap' around or fail on an overflow.
+ */
+fn evaluate_in_mode(node: &ParseNode, rational: bool, wrap: bool,
+                    width: IntWidth, allow_rand: bool, variables: &impl Variables)
+                    -> Result<Value, EvalError> {
+    match (rational, wrap, width) {
+        (true, _, _) => {
+            evaluate_as::<Rational>(node, allow_rand, variables).map(Value::Rational)
+        }
+        (false, false, IntWidth::I64) => {
+            let res = evaluate_as::<i64>(node, allow_rand, variables);
+            res.map(|n| Value::Integer(i128::from(n)))
+        }
+        (false, true, IntWidth::I64) => {
+            let res = evaluate_as::<Wrapping<i64>>(node, allow_rand, variables);
+            res.map(|n| Value::Integer(i128::from(n.0)))
+        }
+        (false, false, IntWidth::I32) => {
+            let res = evaluate_as::<i32>(node, allow_rand, variables);
+            res.map(|n| Value::Integer(i128::from(n)))
+        }
+        (false, true, IntWidth::I32) => {
+            let res = evaluate_as::<Wrapping<i32>>(node, allow_rand, variables);
+            res.map(|n| Value::Integer(i128::from(n.0)))
+        }
+        (false, false, IntWidth::I128) => {
+            evaluate_as::<i128>(node, allow_rand, variables).map(Value::Integer)
+        }
+        (false, true, IntWidth::I128) => {
+            let res = evaluate_as::<Wrapping<i128>>(node, allow_rand, variables);
+            res.map(|n| Value::Integer(n.0))
+        }
+    }
+}
+
+/*
+ * Apply the `assignments' of a statement (see `parse_statement_with') to the
+ * `value' of its expression, from the last assignment to the first. So in
+ * `x = y += 3', `y' is incremented by 3 and `x' gets the new value of `y'.
+ * Compound assignments need a defined variable and no variable can shadow
+ * one of the `CONSTANTS'. Either all variables are assigned or none (if an
+ * assignment fails). Returns the assigned values in the order of the input.
+ * Compound assignments of integers compute with the `width' and wrap around
+ * like `--wrapping' if `wrapping' is set.
+ */
+pub fn assign(assignments: &[Assignment], value: Value,
+              variables: &mut BTreeMap<String, Value>, wrapping: bool,
+              width: IntWidth)
+              -> Result<Vec<(String, Value)>, EvalError> {
+    let mut assigned: Vec<(String, Value)> = vec![];
+    let mut value = value;
+    for assignment in assignments.iter().rev() {
+        let name = &assignment.name;
+        if is_constant(name) {
+            return Err(EvalError::AssignToConstant(name.clone()));
+        }
+        if let Some(op) = &assignment.op {
+            // a variable that is assigned twice, like in `x += x = 1', has
+            // the value of its later assignment
+            let old =
+                assigned.iter()
+                        .find(|(other, _)| other == name)
+                        .map(|(_, old)| *old)
+                        .or_else(|| variables.get(name).copied())
+                        .ok_or_else(|| EvalError::UnknownVariable(name.clone()))?;
+            value = apply_value(op, old, value, wrapping, width)?;
+        }
+        assigned.insert(0, (name.clone(), value));
+    }
+
+    for (name, value) in assigned.iter().rev() {
+        variables.insert(name.clone(), *value);
+    }
+    Ok(assigned)
+}
+
+/* Apply `op' to two values, which are rationals unless both are integers. */
+fn apply_value(op: &Terminal, lhs: Value, rhs: Value, wrapping: bool,
+               width: IntWidth)
+               -> Result<Value, EvalError> {
+    match (lhs, rhs) {
+        (Value::Integer(lhs), Value::Integer(rhs)) => {
+            let (lhs, rhs) = (width.check(lhs)?, width.check(rhs)?);
+            let res = if wrapping {
+                apply_wrapping_in(op, lhs, rhs, width)
+            } else {
+                apply_in(op, lhs, rhs, width)
+            };
+            res.map(Value::Integer)
+        }
+        (lhs, rhs) => {
+            let res = Rational::apply(op, to_rational(lhs)?, to_rational(rhs)?);
+            res.map(Value::Rational)
+        }
+    }
+}
+
+/* A value as a fraction, which fails for an integer beyond 64 bits. */
+pub fn to_rational(value: Value) -> Result<Rational, EvalError> {
+    match value {
+        Value::Integer(n) => i64::from_integer(n).map(Rational::from_integer),
+        Value::Rational(r) => Ok(r),
+    }
+}
+
+fn evaluate_as<T: Arithmetic>(node: &ParseNode, allow_rand: bool,
+                              variables: &impl Variables)
+                              -> Result<T, EvalError> {
+    // no tree has as many nodes, so the evaluation is unbounded
+    let mut steps = usize::MAX;
+    evaluate_counted(node, allow_rand, variables, &mut steps)
+}
+
+/* Like `evaluate_as', but every evaluated node takes one of the `steps'. */
+fn evaluate_counted<T: Arithmetic>(node: &ParseNode, allow_rand: bool,
+                                   variables: &impl Variables, steps: &mut usize)
+                                   -> Result<T, EvalError> {
+    let mut stack: Vec<&ParseNode> = vec![];
+    build_exec_stack(node, &mut stack);
+    run_exec_stack(&stack, &mut vec![], allow_rand, variables, steps)
+}
+
+/*
+ * Evaluate the execution `stack' of a tree (see `build_exec_stack'). The
+ * `stack' isn't consumed and the `operands' are only cleared, so evaluating
+ * the same tree again (see `evaluate_many') needs no new allocations.
+ */
+fn run_exec_stack<T: Arithmetic>(stack: &[&ParseNode], operands: &mut Vec<T>,
+                                 allow_rand: bool, variables: &impl Variables,
+                                 steps: &mut usize)
+                                 -> Result<T, EvalError> {
+    // the execution stack yields the nodes in postfix order from its top, so
+    // the operands of an operator are always on the operand stack already
+    operands.clear();
+    for node in stack.iter().rev() {
+        *steps = steps.checked_sub(1).ok_or(EvalError::StepLimitExceeded)?;
+        match &node.terminal {
+            Terminal::Literal(n) => operands.push(T::from_integer(i128::from(*n))?),
+            Terminal::Var(name) => operands.push(lookup(name, variables)?),
+            Terminal::Paren => continue, /* parens are ignored */
+            Terminal::Neg => {
+                let n = pop_operand(operands)?;
+                operands.push(n.negate()?);
+            }
+            Terminal::Abs => {
+                let n = pop_operand(operands)?;
+                operands.push(n.abs()?);
+            }
+            Terminal::Percent => {
+                let n = pop_operand(operands)?;
+                operands.push(n.percent()?);
+            }
+            Terminal::Not => {
+                let n = pop_operand(operands)?;
+                operands.push(T::from_bool(!is_true(n)));
+            }
+            Terminal::BitNot => {
+                let n = pop_operand(operands)?;
+                operands.push(n.bit_not()?);
+            }
+            // the operands of `&&' and `||' aren't on the execution stack, the
+            // rhs is only evaluated if the lhs doesn't decide the result
+            Terminal::And | Terminal::Or => {
+                let (lhs, rhs) = match (node.get_lchild(), node.get_rchild()) {
+                    (Some(lhs), Some(rhs)) => (lhs, rhs),
+                    _ => return Err(EvalError::MalformedTree(String::from("Expected two operands"))),
+                };
+                let lhs = is_true(evaluate_counted::<T>(lhs, allow_rand,
+                                                        variables, steps)?);
+                let res = if lhs == (node.terminal == Terminal::Or) {
+                    lhs
+                } else {
+                    is_true(evaluate_counted::<T>(rhs, allow_rand, variables,
+                                                  steps)?)
+                };
+                operands.push(T::from_bool(res));
+            }
+            // likewise, only the branch that the condition picks is evaluated
+            Terminal::Cond => {
+                let (cond, then, other) = get_branches(node)?;
+                let branch = if is_true(evaluate_counted::<T>(cond, allow_rand,
+                                                              variables, steps)?)
+                {
+                    then
+                } else {
+                    other
+                };
+                operands.push(evaluate_counted(branch, allow_rand, variables,
+                                               steps)?);
+            }
+            Terminal::Arg => continue, /* the arguments are operands, too */
+            Terminal::Call(name, argc) => {
+                if operands.len() < *argc {
+                    return Err(EvalError::MalformedTree(String::from("Expected integer literal")));
                 }
+                let args = operands.split_off(operands.len() - argc);
+                operands.push(call(name, &args, allow_rand)?);
+            }
+            op => {
+                let rhs = pop_operand(operands)?;
+                let lhs = pop_operand(operands)?;
+                operands.push(T::apply(op, lhs, rhs).map_err(|e| {
+                                                        at_operator(e, node)
+                                                    })?);
             }
         }
     }
+
+    // a well-formed tree leaves exactly one value, the result
+    let result = pop_operand(operands)?;
+    if let Some(n) = operands.pop() {
+        return Err(EvalError::MalformedTree(format!("Unexpected integer literal {}",
+                                                    n)));
+    }
     Ok(result)
 }
 
+impl ParseNode {
+    /*
+     * Evaluate the tree by recursing over the children of every node instead
+     * of building an execution stack like `evaluate' does. Both apply the
+     * same checks because they share the arithmetic below.
+     */
+    pub fn eval(&self) -> Result<i64, EvalError> {
+        match (&self.terminal, self.get_lchild(), self.get_rchild()) {
+            (Terminal::Literal(n), None, None) => Ok(*n),
+            (Terminal::Var(name), None, None) => constant(name),
+            (Terminal::Paren, Some(child), None) => child.eval(),
+            (Terminal::Neg, Some(child), None) => negate(child.eval()?),
+            (Terminal::Abs, Some(child), None) => abs(child.eval()?),
+            (Terminal::Percent, Some(child), None) => child.eval()?.percent(),
+            (Terminal::Not, Some(child), None) => Ok((child.eval()? == 0) as i64),
+            (Terminal::BitNot, Some(child), None) => Ok(!child.eval()?),
+            (Terminal::And, Some(lhs), Some(rhs)) => {
+                Ok((lhs.eval()? != 0 && rhs.eval()? != 0) as i64)
+            }
+            (Terminal::Or, Some(lhs), Some(rhs)) => {
+                Ok((lhs.eval()? != 0 || rhs.eval()? != 0) as i64)
+            }
+            (Terminal::Cond, _, _) => {
+                let (cond, then, other) = get_branches(self)?;
+                if cond.eval()? != 0 {
+                    then.eval()
+                } else {
+                    other.eval()
+                }
+            }
+            (Terminal::Call(name, _), _, _) => {
+                let args = self.get_args()
+                               .iter()
+                               .map(|arg| arg.eval())
+                               .collect::<Result<Vec<i64>, EvalError>>()?;
+                call(name, &args, false)
+            }
+            (op, Some(lhs), Some(rhs)) => {
+                apply(op, lhs.eval()?, rhs.eval()?).map_err(|e| at_operator(e, self))
+            }
+            _ => Err(EvalError::MalformedTree(format!("Unexpected node {}",
+                                                      self.get_long_type()))),
+        }
+    }
+}
+
+/*
+ * Tie an overflow to the binary operator `node' that caused it, so the error
+ * tells which operator of the expression overflowed, e.g. `while multiplying
+ * at token 1' for `9223372036854775807 * 2'.
+ */
+fn at_operator(err: EvalError, node: &ParseNode) -> EvalError {
+    match (err, node.get_token()) {
+        (EvalError::Overflow, Some(token)) => {
+            EvalError::OverflowAt(get_verb(&node.terminal), token)
+        }
+        (err, _) => err,
+    }
+}
+
+fn get_verb(op: &Terminal) -> &'static str {
+    match op {
+        Terminal::Sum => "adding",
+        Terminal::Sub => "subtracting",
+        Terminal::Mod => "taking the modulo",
+        Terminal::Mult => "multiplying",
+        Terminal::Div => "dividing",
+        Terminal::Exp => "exponentiating",
+        Terminal::AddPercent => "adding a percentage",
+        Terminal::SubPercent => "subtracting a percentage",
+        _ => "applying an operator",
+    }
+}
+
+/* Apply the binary operator `op' to two `i64's, see `apply_in'. */
+fn apply(op: &Terminal, lhs: i64, rhs: i64) -> Result<i64, EvalError> {
+    apply_in(op, lhs.into(), rhs.into(), IntWidth::I64).map(|n| n as i64)
+}
+
 /*
- * Traverse parse tree recursively and push terminals onto the execution stack.
+ * Apply the binary operator `op' to its two operands, which are integers of
+ * the `width'. They are computed as `i128's and a result that doesn't fit
+ * the width is an overflow, e.g. `2147483647 + 1' with `--int-width 32'.
+ * Only the shifts work on the bits of the width (see `shift').
  */
-fn build_exec_stack<'a>(node: &'a ParseNode, mut stack: &mut Vec<&'a Terminal>) {
-    stack.push(&node.terminal);
+fn apply_in(op: &Terminal, lhs: i128, rhs: i128, width: IntWidth)
+            -> Result<i128, EvalError> {
+    let res = match op {
+        Terminal::Sum => lhs.checked_add(rhs),
+        Terminal::Sub => lhs.checked_sub(rhs),
+        Terminal::Mod => {
+            if rhs == 0 {
+                return Err(EvalError::ModuloByZero);
+            }
+            // like the division, `-2^63 % -1' overflows with 64 bits
+            lhs.checked_div(rhs)
+               .filter(|&quotient| width.fits(quotient))
+               .and(lhs.checked_rem(rhs))
+        }
+        Terminal::Mult => lhs.checked_mul(rhs),
+        Terminal::Div => {
+            if rhs == 0 {
+                return Err(EvalError::DivisionByZero);
+            }
+            lhs.checked_div(rhs)
+        }
+        Terminal::Exp => {
+            // results are integers, so negative exponents (which would yield
+            // fractions) are rejected instead of being cast to huge `u32's
+            if rhs < 0 {
+                return Err(EvalError::NegativeExponent(rhs));
+            }
+            match u32::try_from(rhs) {
+                Ok(exp) => lhs.checked_pow(exp),
+                Err(_) => return Err(EvalError::ExponentTooLarge(rhs)),
+            }
+        }
+        Terminal::Xor => Some(lhs ^ rhs),
+        Terminal::Shl | Terminal::Shr | Terminal::Ushr => {
+            return shift(op, lhs, rhs, width)
+        }
+        Terminal::AddPercent => {
+            let p = lhs.checked_mul(rhs).ok_or(EvalError::Overflow)?;
+            lhs.checked_add(hundredth(p)?)
+        }
+        Terminal::SubPercent => {
+            let p = lhs.checked_mul(rhs).ok_or(EvalError::Overflow)?;
+            lhs.checked_sub(hundredth(p)?)
+        }
+        _ => {
+            return Err(EvalError::MalformedTree(String::from("Expected an operator")))
+        }
+    };
+    res.filter(|&n| width.fits(n)).ok_or(EvalError::Overflow)
+}
+
+/*
+ * Shift `lhs' of the `width' by `rhs' bits, e.g. `1<<31' is `-2147483648'
+ * with `--int-width 32'. Bits that are shifted out are lost, only the shift
+ * itself is checked: it must be from 0 to the width minus 1.
+ */
+fn shift(op: &Terminal, lhs: i128, rhs: i128, width: IntWidth)
+         -> Result<i128, EvalError> {
+    let bits = u32::try_from(rhs).ok()
+                                 .filter(|&bits| bits < width.bits())
+                                 .ok_or(EvalError::InvalidShift(rhs, width.bits()))?;
+    let res = match op {
+        Terminal::Shl => lhs << bits,
+        Terminal::Shr => lhs >> bits,
+        // a logical shift fills in zeros from the top bit of the width
+        _ => ((lhs as u128 & u128::MAX >> (128 - width.bits())) >> bits) as i128,
+    };
+    Ok(width.wrap(res))
+}
+
+/*
+ * A percentage `n%' as an integer, i.e. `n/100'. Unlike a division, it isn't
+ * truncated: `50%' would silently be `0', so a percentage that isn't a whole
+ * number is an error (`--rational' computes it exactly). This applies to the
+ * percentage of `200+10%', too, which is `200*10/100'.
+ */
+fn hundredth(n: i128) -> Result<i128, EvalError> {
+    if n % 100 == 0 {
+        Ok(n / 100)
+    } else {
+        Err(EvalError::InexactPercent(n))
+    }
+}
+
+/*
+ * Like `apply_in', but the result wraps around at the bounds of the `width'
+ * instead of overflowing, e.g. `9223372036854775807 + 1' is
+ * `-9223372036854775808'. Division and modulo by zero, negative exponents
+ * and invalid shifts are still errors.
+ */
+fn apply_wrapping_in(op: &Terminal, lhs: i128, rhs: i128, width: IntWidth)
+                     -> Result<i128, EvalError> {
+    let res = match op {
+        Terminal::Sum => lhs.wrapping_add(rhs),
+        Terminal::Sub => lhs.wrapping_sub(rhs),
+        Terminal::Mod if rhs != 0 => lhs.wrapping_rem(rhs),
+        Terminal::Mult => lhs.wrapping_mul(rhs),
+        Terminal::Div if rhs != 0 => lhs.wrapping_div(rhs),
+        Terminal::Exp if rhs >= 0 => match u32::try_from(rhs) {
+            Ok(exp) => lhs.wrapping_pow(exp),
+            Err(_) => return Err(EvalError::ExponentTooLarge(rhs)),
+        },
+        Terminal::AddPercent => {
+            lhs.wrapping_add(hundredth(width.wrap(lhs.wrapping_mul(rhs)))?)
+        }
+        Terminal::SubPercent => {
+            lhs.wrapping_sub(hundredth(width.wrap(lhs.wrapping_mul(rhs)))?)
+        }
+        // the other operators can't overflow or fail like in `apply_in'
+        _ => return apply_in(op, lhs, rhs, width),
+    };
+    Ok(width.wrap(res))
+}
+
+/*
+ * Call the function `name' with its evaluated arguments. `min' and `max'
+ * take any positive number of arguments, `clamp(x, lower, upper)' limits `x'
+ * to the range from `lower' to `upper'. `abs(x)' is `|x|' and `neg(x)' is
+ * `-x'. `gcd(a, b)' and `lcm(a, b)' take integers. `rand(lo, hi)' is a random integer from `lo' to `hi'
+ * (inclusive), but only if `allow_rand' is set.
+ */
+fn call<T: Arithmetic>(name: &str, args: &[T], allow_rand: bool)
+                       -> Result<T, EvalError> {
+    let arg_count =
+        |expected| EvalError::WrongArgCount(name.to_string(), expected, args.len());
+    match (name, args) {
+        ("min", [first, rest @ ..]) => {
+            Ok(rest.iter()
+                   .fold(*first, |min, &arg| if arg < min { arg } else { min }))
+        }
+        ("max", [first, rest @ ..]) => {
+            Ok(rest.iter()
+                   .fold(*first, |max, &arg| if arg > max { arg } else { max }))
+        }
+        ("min", []) | ("max", []) => Err(arg_count("at least 1 argument")),
+        ("clamp", [_, lower, upper]) if lower > upper => {
+            Err(EvalError::InvalidBounds(lower.to_string(), upper.to_string()))
+        }
+        ("clamp", [x, lower, _]) if x < lower => Ok(*lower),
+        ("clamp", [x, _, upper]) if x > upper => Ok(*upper),
+        ("clamp", [x, _, _]) => Ok(*x),
+        ("clamp", _) => Err(arg_count("3 arguments")),
+        ("abs", [x]) => x.abs(),
+        ("neg", [x]) => x.negate(),
+        ("abs", _) | ("neg", _) => Err(arg_count("1 argument")),
+        ("gcd", [a, b]) => {
+            T::from_integer(gcd(a.to_integer(name)?, b.to_integer(name)?)?.into())
+        }
+        ("lcm", [a, b]) => {
+            T::from_integer(lcm(a.to_integer(name)?, b.to_integer(name)?)?.into())
+        }
+        ("gcd", _) | ("lcm", _) => Err(arg_count("2 arguments")),
+        ("rand", _) if !allow_rand => Err(EvalError::RandDisabled),
+        ("rand", [lower, upper]) if lower > upper => {
+            Err(EvalError::InvalidBounds(lower.to_string(), upper.to_string()))
+        }
+        ("rand", [lower, upper]) => {
+            let lower = lower.to_integer(name)?;
+            let upper = upper.to_integer(name)?;
+            T::from_integer(random_in(lower, upper)?.into())
+        }
+        ("rand", _) => Err(arg_count("2 arguments")),
+        _ => Err(EvalError::UnknownFunction(name.to_string())),
+    }
+}
+
+/*
+ * The greatest common divisor of `a' and `b' by the Euclidean algorithm. The
+ * signs don't matter, e.g. `gcd(-12, 18)' is 6, and `gcd(0, 0)' is 0. It
+ * only overflows for `gcd(i64::MIN, 0)' or `gcd(i64::MIN, i64::MIN)'.
+ */
+fn gcd(a: i64, b: i64) -> Result<i64, EvalError> {
+    i64::try_from(gcd_unsigned(a.unsigned_abs(), b.unsigned_abs()))
+        .map_err(|_| EvalError::Overflow)
+}
+
+/* The least common multiple of `a' and `b', which is never negative. */
+fn lcm(a: i64, b: i64) -> Result<i64, EvalError> {
+    let (a, b) = (a.unsigned_abs(), b.unsigned_abs());
+    if a == 0 || b == 0 {
+        return Ok(0);
+    }
+    (a / gcd_unsigned(a, b)).checked_mul(b)
+                            .and_then(|lcm| i64::try_from(lcm).ok())
+                            .ok_or(EvalError::Overflow)
+}
+
+fn gcd_unsigned(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+/*
+ * The named constants and the fractions that approximate them to 10 decimal
+ * places. Like any other fraction, they are truncated without `--rational',
+ * so `pi' is 3 then. Variables can't shadow them, so `pi' always means the
+ * same in every session (see `assign').
+ */
+const CONSTANTS: [(&str, i64, i64); 2] =
+    [("pi", 312689, 99532), ("e", 517656, 190435)];
+
+/*
+ * The physical constants that are only defined with `--physics', so they
+ * don't take the names `c' and `g' from variables otherwise: the speed of
+ * light in m/s and the standard gravity in m/s^2. Both are plain numbers
+ * without a unit, and `g' (9.80665) is truncated to 9 like `pi' without
+ * `--rational'. They can't be shadowed either.
+ */
+const PHYSICS: [(&str, i64, i64); 2] = [("c", 299792458, 1), ("g", 196133, 20000)];
+
+/* Check if `name' is one of the `PHYSICS' constants of `--physics'. */
+pub fn is_physics_constant(name: &str) -> bool {
+    PHYSICS.iter().any(|(constant, _, _)| *constant == name)
+}
+
+/* Check if `name' is one of the `CONSTANTS', which can't be assigned. */
+pub fn is_constant(name: &str) -> bool {
+    CONSTANTS.iter().any(|(constant, _, _)| *constant == name)
+}
+
+/* Look up the value of the constant `name', see `CONSTANTS'. */
+fn constant<T: Arithmetic>(name: &str) -> Result<T, EvalError> {
+    match CONSTANTS.iter().find(|(constant, _, _)| *constant == name) {
+        Some(&(_, num, den)) => T::from_fraction(num, den),
+        None => Err(EvalError::UnknownVariable(name.to_string())),
+    }
+}
+
+/*
+ * The variables that an expression is evaluated in: the ones of the REPL can
+ * be fractions, the ones of `evaluate_many' are integers.
+ */
+trait Variables {
+    fn get_value(&self, name: &str) -> Option<Value>;
+}
+
+impl Variables for BTreeMap<String, Value> {
+    fn get_value(&self, name: &str) -> Option<Value> {
+        self.get(name).copied()
+    }
+}
+
+/// The variables of an environment of `evaluate_many`, which are integers.
+pub trait Environment {
+    fn get_integer(&self, name: &str) -> Option<i64>;
+}
+
+impl Environment for BTreeMap<String, i64> {
+    fn get_integer(&self, name: &str) -> Option<i64> {
+        self.get(name).copied()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: BuildHasher> Environment for std::collections::HashMap<String, i64, S> {
+    fn get_integer(&self, name: &str) -> Option<i64> {
+        self.get(name).copied()
+    }
+}
+
+/* The variables of an `Environment', see `evaluate_many'. */
+struct Integers<'a, E>(&'a E);
+
+impl<E: Environment> Variables for Integers<'_, E> {
+    fn get_value(&self, name: &str) -> Option<Value> {
+        self.0
+            .get_integer(name)
+            .map(|n| Value::Integer(i128::from(n)))
+    }
+}
+
+/* The `variables' together with the `PHYSICS' constants if `enabled'. */
+struct Physics<'a, V> {
+    variables: &'a V,
+    enabled: bool,
+}
+
+impl<V: Variables> Variables for Physics<'_, V> {
+    fn get_value(&self, name: &str) -> Option<Value> {
+        let constant = PHYSICS.iter().find(|(constant, _, _)| *constant == name);
+        match constant {
+            // the fractions are reduced already
+            Some(&(_, num, den)) if self.enabled => {
+                Some(Value::Rational(Rational { num, den }))
+            }
+            _ => self.variables.get_value(name),
+        }
+    }
+}
+
+/* Look up the value of the variable or constant `name'. */
+fn lookup<T: Arithmetic>(name: &str, variables: &impl Variables)
+                         -> Result<T, EvalError> {
+    match variables.get_value(name) {
+        Some(Value::Integer(n)) => T::from_integer(n),
+        Some(Value::Rational(r)) => T::from_fraction(r.num, r.den),
+        None => constant(name),
+    }
+}
+
+/*
+ * A random integer from `lower' to `upper', which must not be greater. The
+ * randomness comes from the keys that the standard library picks for every
+ * `RandomState', so no seed is needed (and none can be given). The slight
+ * bias of the modulo is negligible for the small ranges of dice and alike.
+ */
+#[cfg(feature = "std")]
+fn random_in(lower: i64, upper: i64) -> Result<i64, EvalError> {
+    let random = RandomState::new().build_hasher().finish();
+    let span = (upper as i128 - lower as i128 + 1) as u128;
+    Ok((lower as i128 + (random as u128 % span) as i128) as i64)
+}
+
+/* Without `std', there is no source of randomness. */
+#[cfg(not(feature = "std"))]
+fn random_in(_: i64, _: i64) -> Result<i64, EvalError> {
+    Err(EvalError::RandDisabled)
+}
+
+/* The condition and the two branches of a `Cond' node. */
+fn get_branches(node: &ParseNode)
+                -> Result<(&ParseNode, &ParseNode, &ParseNode), EvalError> {
+    if let (Some(cond), Some(branches)) = (node.get_lchild(), node.get_rchild()) {
+        if let (Terminal::Else, Some(then), Some(other)) =
+            (&branches.terminal, branches.get_lchild(), branches.get_rchild())
+        {
+            return Ok((cond, then, other));
+        }
+    }
+    Err(EvalError::MalformedTree(String::from("Expected a condition and two branches")))
+}
+
+/* Any nonzero value is true, the logical operators yield 1 or 0. */
+fn is_true<T: Arithmetic>(n: T) -> bool {
+    n != T::from_bool(false)
+}
+
+fn negate(n: i64) -> Result<i64, EvalError> {
+    n.checked_neg().ok_or(EvalError::Overflow)
+}
+
+fn abs(n: i64) -> Result<i64, EvalError> {
+    n.checked_abs().ok_or(EvalError::Overflow)
+}
+
+fn pop_operand<T>(operands: &mut Vec<T>) -> Result<T, EvalError> {
+    operands.pop()
+            .ok_or_else(|| EvalError::MalformedTree(String::from("Expected integer literal")))
+}
+
+/*
+ * Traverse parse tree recursively and push nodes onto the execution stack.
+ * The RHS is pushed before the LHS, so the stack holds the nodes in reversed
+ * postfix order. The operands of `&&', `||' and conditionals are left out
+ * because they are evaluated on demand.
+ */
+fn build_exec_stack<'a>(node: &'a ParseNode, stack: &mut Vec<&'a ParseNode>) {
+    stack.push(node);
+    if matches!(node.terminal, Terminal::And | Terminal::Or | Terminal::Cond) {
+        return;
+    }
 
     match node.get_lchild() {
         // this is a leaf
-        None => return,
+        None => (),
         Some(lchild) => {
-            build_exec_stack(&lchild, &mut stack);
             if let Some(rchild) = node.get_rchild() {
-                build_exec_stack(&rchild, &mut stack);
+                build_exec_stack(rchild, stack);
             }
+            build_exec_stack(lchild, stack);
         }
     }
 }
 
-/* Initialize data that is used by the VM. */
-fn init() -> Keywords {
-    Keywords { quit: vec![String::from("quit"), String::from("q")] }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
 
-fn matches_any(s: &String, s_vec: &Vec<String>) -> bool {
-    for elem in s_vec {
-        // at this point, exact matches could be avoided with `String.contains'
-        if s == elem {
-            return true;
+    fn eval(input: &str) -> Result<i64, EvalError> {
+        match parse_str(input) {
+            Ok(ast) => evaluate(&ast),
+            Err(err) => panic!("`{}' doesn't parse: {}", input, err.msg),
         }
     }
-    false
-}
 
-/* Print a prompt, read from `stdin' and return with new lines trimmed off. */
-fn prompt_and_read(ps1: &str) -> String {
-    print!("{}", ps1);
-    stdout().flush().expect("Failed to flush stdout");
-    let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
-    input.trim().to_string()
+    fn interpreter(rational: bool, wrapping: bool, int_width: IntWidth)
+                   -> Interpreter {
+        Interpreter { rational,
+                      wrapping,
+                      int_width,
+                      ..Interpreter::new() }
+    }
+
+    /* Evaluate `input' like the REPL does, with the mode of `interpreter'. */
+    fn eval_with(interpreter: &mut Interpreter, input: &str)
+                 -> Result<Rational, EvalError> {
+        match interpreter.eval(input) {
+            Ok(Some(value)) => to_rational(value),
+            Ok(None) => panic!("`{}' has no result", input),
+            Err(InterpreterError::Eval(err)) => Err(err),
+            Err(InterpreterError::Parser(err)) => {
+                panic!("`{}' doesn't parse: {}", input, err.msg)
+            }
+        }
+    }
+
+    /* The modes of `interpreter': rational, every width and wrapping. */
+    const MODES: [(bool, bool, IntWidth); 7] = [(true, false, IntWidth::I64),
+                                                (false, false, IntWidth::I64),
+                                                (false, true, IntWidth::I64),
+                                                (false, false, IntWidth::I32),
+                                                (false, true, IntWidth::I32),
+                                                (false, false, IntWidth::I128),
+                                                (false, true, IntWidth::I128)];
+
+    fn eval_in_every_mode(input: &str) -> Vec<Result<Rational, EvalError>> {
+        let eval_in = |&(rational, wrapping, width)| {
+            eval_with(&mut interpreter(rational, wrapping, width), input)
+        };
+        MODES.iter().map(eval_in).collect()
+    }
+
+    #[test]
+    fn negative_exponents_are_rejected() {
+        assert!(matches!(eval("2^-1"), Err(EvalError::NegativeExponent(-1))));
+        assert_eq!(eval("2^0").unwrap(), 1);
+        assert_eq!(eval("0^0").unwrap(), 1);
+        assert_eq!(eval("2^10").unwrap(), 1024);
+    }
+
+    #[test]
+    fn the_deepest_trees_of_the_parser_evaluate() {
+        // trees close to `DEFAULT_MAX_DEPTH' go through `build_exec_stack'
+        // and the evaluation without overflowing the stack
+        let parens = format!("{}1{}", "(".repeat(900), ")".repeat(900));
+        assert_eq!(eval(&parens).unwrap(), 1);
+        let negations = format!("{}7", "-".repeat(900));
+        assert_eq!(eval(&negations).unwrap(), 7);
+        let sums = vec!["1"; 900].join("+");
+        assert_eq!(eval(&sums).unwrap(), 900);
+        let powers = vec!["1"; 900].join("^");
+        assert_eq!(eval(&powers).unwrap(), 1);
+    }
+
+    #[test]
+    fn percentages_must_be_whole_numbers() {
+        assert_eq!(eval("200 + 10%").unwrap(), 220);
+        assert_eq!(eval("200 - 10%").unwrap(), 180);
+        assert_eq!(eval("300%").unwrap(), 3);
+        assert_eq!(eval("7 % -2").unwrap(), 1);
+        assert_eq!(eval("7 mod -2").unwrap(), 1);
+        assert_eq!(eval("7 mod 3").unwrap(), 1);
+        assert_eq!(eval("7 mod 3").unwrap(), eval("7 % 3").unwrap());
+        assert_eq!(eval("(200 + 10%) - 5").unwrap(), 215);
+        for input in ["50%", "200*10%", "201 + 10%"].iter() {
+            for res in eval_in_every_mode(input).into_iter().skip(1) {
+                assert!(matches!(res, Err(EvalError::InexactPercent(_))),
+                        "{}",
+                        input);
+            }
+        }
+        assert!(matches!(eval("50%"), Err(EvalError::InexactPercent(50))));
+        let mut rational = interpreter(true, false, IntWidth::I64);
+        assert_eq!(eval_with(&mut rational, "50%").unwrap(),
+                   Rational::new(1, 2).unwrap());
+        assert_eq!(eval_with(&mut rational, "200*10%").unwrap(),
+                   Rational::from_integer(20));
+        assert_eq!(eval_with(&mut rational, "201 + 10%").unwrap(),
+                   Rational::new(2211, 10).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn rand_stays_in_its_bounds() {
+        let mut random = Interpreter { allow_rand: true,
+                                       ..Interpreter::new() };
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let n = eval_with(&mut random, "rand(1, 6)").unwrap();
+            assert!(n.is_integer() && (1..=6).contains(&n.num), "{}", n);
+            seen[(n.num - 1) as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+        assert_eq!(eval_with(&mut random, "rand(-3, -3)").unwrap(),
+                   Rational::from_integer(-3));
+        assert!(matches!(eval_with(&mut random, "rand(6, 1)"),
+                         Err(EvalError::InvalidBounds(..))));
+        assert!(matches!(eval_with(&mut random, "rand(1)"),
+                         Err(EvalError::WrongArgCount(..))));
+        assert!(matches!(eval_with(&mut Interpreter::new(), "rand(1, 6)"),
+                         Err(EvalError::RandDisabled)));
+    }
+
+    fn variable(interpreter: &Interpreter, name: &str) -> Option<Rational> {
+        interpreter.get_variables()
+                   .get(name)
+                   .copied()
+                   .map(|value| to_rational(value).unwrap())
+    }
+
+    #[test]
+    fn variables_are_kept_between_inputs() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(interpreter.eval("x = 5"), Ok(None)));
+        assert_eq!(eval_with(&mut interpreter, "x + 1").unwrap(),
+                   Rational::from_integer(6));
+        // an expression doesn't change the variable
+        assert_eq!(variable(&interpreter, "x"), Some(Rational::from_integer(5)));
+        assert!(matches!(Interpreter::new().eval("x + 1"),
+                         Err(InterpreterError::Eval(EvalError::UnknownVariable(_)))));
+    }
+
+    #[test]
+    fn assignments_chain_and_compound() {
+        let mut interpreter = Interpreter::new();
+        let int = |n| Some(Rational::from_integer(n));
+        assert!(matches!(interpreter.eval("x = y = 3"), Ok(None)));
+        assert_eq!(variable(&interpreter, "x"), int(3));
+        assert_eq!(variable(&interpreter, "y"), int(3));
+
+        let steps = [("x += 2", "x", 5),
+                     ("y -= 1", "y", 2),
+                     ("x *= y", "x", 10),
+                     ("x /= 4", "x", 2)];
+        for (input, name, value) in steps.iter() {
+            assert!(matches!(interpreter.eval(input), Ok(None)));
+            assert_eq!(variable(&interpreter, name), int(*value), "{}", input);
+        }
+        // the compound assignment is applied before the chained one
+        assert!(matches!(interpreter.eval("z = y += 3"), Ok(None)));
+        assert_eq!(variable(&interpreter, "y"), int(5));
+        assert_eq!(variable(&interpreter, "z"), int(5));
+        assert_eq!(eval_with(&mut interpreter, "x + y + z").unwrap(),
+                   Rational::from_integer(12));
+
+        // a compound assignment needs a defined variable, a failed statement
+        // assigns nothing
+        assert!(matches!(interpreter.eval("w += 1"),
+                         Err(InterpreterError::Eval(EvalError::UnknownVariable(_)))));
+        assert!(matches!(interpreter.eval("v = w += 1"),
+                         Err(InterpreterError::Eval(EvalError::UnknownVariable(_)))));
+        assert_eq!(variable(&interpreter, "v"), None);
+        assert!(matches!(interpreter.eval("pi = 3"),
+                         Err(InterpreterError::Eval(EvalError::AssignToConstant(_)))));
+    }
+
+    fn envs(count: i64) -> Vec<BTreeMap<String, i64>> {
+        (0..count).map(|x| {
+                      let mut env = BTreeMap::new();
+                      env.insert("x".to_string(), x - count / 2);
+                      env.insert("y".to_string(), 3);
+                      env
+                  })
+                  .collect()
+    }
+
+    #[test]
+    fn evaluate_many_agrees_with_every_single_evaluation() {
+        for input in ["x*x + y",
+                      "10 / x",
+                      "x % y - -x",
+                      "x && y || 0",
+                      "x ? y : 1/x"].iter()
+        {
+            let ast = match parse_str(input) {
+                Ok(ast) => ast,
+                Err(err) => panic!("`{}' doesn't parse: {}", input, err.msg),
+            };
+            let envs = envs(50);
+            let results = evaluate_many(&ast, &envs);
+            assert_eq!(results.len(), envs.len());
+            for (env, res) in envs.iter().zip(results) {
+                let single = evaluate_as::<i64>(&ast, false, &Integers(env));
+                assert_eq!(res, single, "{}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn evaluate_many_starts_every_environment_afresh() {
+        // the `1' is left on the reused operand stack when `10/x' fails
+        let ast = parse_str("1 + 10/x").unwrap();
+        let envs: Vec<BTreeMap<String, i64>> =
+            [0, 2, 0, 5].iter()
+                        .map(|x| BTreeMap::from([("x".to_string(), *x)]))
+                        .collect();
+        assert_eq!(evaluate_many(&ast, &envs),
+                   [Err(EvalError::DivisionByZero),
+                    Ok(6),
+                    Err(EvalError::DivisionByZero),
+                    Ok(3)]);
+        assert_eq!(evaluate_many(&ast, &[BTreeMap::new()]),
+                   [Err(EvalError::UnknownVariable("x".to_string()))]);
+        assert!(evaluate_many::<BTreeMap<String, i64>>(&ast, &[]).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn evaluate_many_takes_hash_maps() {
+        use std::collections::HashMap;
+
+        let ast = parse_str("x*x + y").unwrap();
+        let hashed: Vec<HashMap<String, i64>> =
+            envs(20).into_iter()
+                    .map(|env| env.into_iter().collect())
+                    .collect();
+        assert_eq!(evaluate_many(&ast, &hashed), evaluate_many(&ast, &envs(20)));
+    }
+
+    #[test]
+    fn modulo_by_zero_is_an_error() {
+        for input in ["5 % 0", "5 mod 0", "5 % (3 - 3)"].iter() {
+            for res in eval_in_every_mode(input) {
+                assert!(matches!(res, Err(EvalError::ModuloByZero)), "{}", input);
+            }
+        }
+        assert!(matches!(eval("(-9223372036854775807-1) % (0-1)"),
+                         Err(EvalError::OverflowAt(..))));
+        let mut wrapping = interpreter(false, true, IntWidth::I64);
+        assert_eq!(eval_with(&mut wrapping, "(-9223372036854775807-1) % (0-1)").unwrap(),
+                   Rational::from_integer(0));
+    }
+
+    #[test]
+    fn modulo_is_left_associative() {
+        assert_eq!(eval("17 % 5 % 3").unwrap(), 2);
+        assert_eq!(eval("7 % 4 * 2").unwrap(), 6);
+        assert_eq!(eval("1 + 7 % 3").unwrap(), 2);
+    }
+
+    #[test]
+    fn gcd_and_lcm_ignore_the_signs() {
+        assert_eq!(eval("gcd(12, 18)").unwrap(), 6);
+        assert_eq!(eval("lcm(4, 6)").unwrap(), 12);
+        assert_eq!(eval("gcd(0, 0)").unwrap(), 0);
+        assert_eq!(eval("gcd(0, 5)").unwrap(), 5);
+        assert_eq!(eval("lcm(0, 5)").unwrap(), 0);
+        assert_eq!(eval("gcd(-12, 18)").unwrap(), 6);
+        assert_eq!(eval("gcd(-12, -18)").unwrap(), 6);
+        assert_eq!(eval("lcm(-4, 6)").unwrap(), 12);
+        assert!(matches!(eval("lcm(9223372036854775807, 2)"),
+                         Err(EvalError::Overflow)));
+        assert!(matches!(gcd(i64::MIN, 0), Err(EvalError::Overflow)));
+        assert!(matches!(eval("gcd(1)"), Err(EvalError::WrongArgCount(..))));
+    }
+
+    #[test]
+    fn min_max_and_clamp_check_their_arguments() {
+        assert_eq!(eval("min(3,1,2)").unwrap(), 1);
+        assert_eq!(eval("max(3,1,2)").unwrap(), 3);
+        assert_eq!(eval("min(-4)").unwrap(), -4);
+        assert_eq!(eval("clamp(5,0,10)").unwrap(), 5);
+        assert_eq!(eval("clamp(-5,0,10)").unwrap(), 0);
+        assert_eq!(eval("clamp(15,0,10)").unwrap(), 10);
+        assert!(matches!(eval("min()"),
+                         Err(EvalError::WrongArgCount(_,
+                                                      "at least 1 argument",
+                                                      0))));
+        assert!(matches!(eval("clamp(5,0)"),
+                         Err(EvalError::WrongArgCount(_, "3 arguments", 2))));
+        assert!(matches!(eval("clamp(5,10,0)"), Err(EvalError::InvalidBounds(..))));
+    }
+
+    #[test]
+    fn abs_and_neg_take_one_argument() {
+        assert_eq!(eval("abs(-7)").unwrap(), 7);
+        assert_eq!(eval("abs(7)").unwrap(), 7);
+        assert_eq!(eval("neg(3)").unwrap(), -3);
+        assert_eq!(eval("abs(neg(2)) + neg(abs(-5))").unwrap(), -3);
+        assert!(matches!(eval("abs(1, 2)"),
+                         Err(EvalError::WrongArgCount(_, "1 argument", 2))));
+        assert!(matches!(eval("neg()"),
+                         Err(EvalError::WrongArgCount(_, "1 argument", 0))));
+        assert!(matches!(eval("abs(-9223372036854775807-1)"),
+                         Err(EvalError::Overflow)));
+    }
+
+    #[test]
+    fn an_overflow_names_its_operator() {
+        assert_eq!(eval("9223372036854775807 * 2").unwrap_err(),
+                   EvalError::OverflowAt("multiplying", 1));
+        assert_eq!(eval("1 + 9223372036854775807 * 2").unwrap_err(),
+                   EvalError::OverflowAt("multiplying", 3));
+        assert_eq!(eval("9223372036854775807 * 2").unwrap_err().to_string(),
+                   "vm: Integer overflow while multiplying at token 1");
+    }
+
+    #[test]
+    fn overflows_wrap_around_only_with_wrapping() {
+        assert!(matches!(eval("9223372036854775807 + 1"),
+                         Err(EvalError::OverflowAt(..))));
+        let mut wrapping = interpreter(false, true, IntWidth::I64);
+        assert_eq!(eval_with(&mut wrapping, "9223372036854775807 + 1").unwrap(),
+                   Rational::from_integer(i64::MIN));
+        assert_eq!(eval_with(&mut wrapping, "-9223372036854775807 - 2").unwrap(),
+                   Rational::from_integer(i64::MAX));
+        assert_eq!(eval_with(&mut wrapping, "2^64").unwrap(),
+                   Rational::from_integer(0));
+        assert!(matches!(eval_with(&mut wrapping, "1/0"),
+                         Err(EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(eval("1 && 0").unwrap(), 0);
+        assert_eq!(eval("2 && -3").unwrap(), 1);
+        assert_eq!(eval("0 || 2").unwrap(), 1);
+        assert_eq!(eval("0 || 0").unwrap(), 0);
+        assert_eq!(eval("!0").unwrap(), 1);
+        assert_eq!(eval("!7").unwrap(), 0);
+        // the other operand is never evaluated
+        for mode in eval_in_every_mode("0 && 1/0") {
+            assert_eq!(mode.unwrap(), Rational::from_integer(0));
+        }
+        for mode in eval_in_every_mode("1 || 1/0") {
+            assert_eq!(mode.unwrap(), Rational::from_integer(1));
+        }
+        assert!(matches!(eval("1 && 1/0"), Err(EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn conditionals_only_evaluate_the_branch_taken() {
+        assert_eq!(eval("1 ? 5 : 6").unwrap(), 5);
+        assert_eq!(eval("0 ? 5 : 6").unwrap(), 6);
+        assert_eq!(eval("-2 ? 5 : 6").unwrap(), 5);
+        assert_eq!(eval("1 ? 2 : 0 ? 3 : 4").unwrap(), 2);
+        assert_eq!(eval("0 ? 2 : 0 ? 3 : 4").unwrap(), 4);
+        for mode in eval_in_every_mode("1 ? 5 : 1/0") {
+            assert_eq!(mode.unwrap(), Rational::from_integer(5));
+        }
+        for mode in eval_in_every_mode("0 ? 1/0 : 6") {
+            assert_eq!(mode.unwrap(), Rational::from_integer(6));
+        }
+        assert!(matches!(eval("1 ? 1/0 : 6"), Err(EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    fn right_shifts_are_arithmetic_or_logical() {
+        assert_eq!(eval("-8>>1").unwrap(), -4);
+        assert_eq!(eval("-8>>>1").unwrap(), ((-8i64 as u64) >> 1) as i64);
+        assert_eq!(eval("-8>>>1").unwrap(), 9223372036854775804);
+        assert_eq!(eval("8>>>1").unwrap(), eval("8>>1").unwrap());
+        assert_eq!(eval("1<<3").unwrap(), 8);
+        let mut i32 = interpreter(false, false, IntWidth::I32);
+        assert_eq!(eval_with(&mut i32, "-8>>>1").unwrap(),
+                   Rational::from_integer(((-8i32 as u32) >> 1) as i64));
+        assert!(matches!(eval("1<<64"), Err(EvalError::InvalidShift(64, 64))));
+    }
+
+    #[test]
+    fn bitwise_not_flips_every_bit() {
+        assert_eq!(eval("~0").unwrap(), -1);
+        assert_eq!(eval("~5").unwrap(), -6);
+        assert_eq!(eval("~-1").unwrap(), 0);
+        assert_eq!(eval("-~5").unwrap(), 6);
+        assert_eq!(eval("~2^2").unwrap(), -5);
+        assert_eq!(eval("!~-1").unwrap(), 1);
+    }
+
+    #[test]
+    fn long_literals_are_rejected_before_the_evaluation() {
+        let mut limited = Interpreter { max_digits: Some(5),
+                                        ..Interpreter::new() };
+        assert_eq!(eval_with(&mut limited, "12345 + 1").unwrap(),
+                   Rational::from_integer(12346));
+        // the division by zero is never evaluated
+        match limited.eval("1/0 + 123456") {
+            Err(InterpreterError::Parser(err)) => {
+                assert_eq!(err.msg,
+                           "Numeric literal has too many digits (at most 5)")
+            }
+            _ => panic!("`123456' has more than 5 digits"),
+        }
+    }
+
+    #[test]
+    fn constants_are_close_fractions() {
+        let mut rational = interpreter(true, false, IntWidth::I64);
+        let mut approx = |input| {
+            let r = eval_with(&mut rational, input).unwrap();
+            r.num as f64 / r.den as f64
+        };
+        assert!((approx("pi") - core::f64::consts::PI).abs() < 1e-9);
+        assert!((approx("e") - core::f64::consts::E).abs() < 1e-9);
+        assert!((approx("pi * 2 + e") - 9.00146713).abs() < 1e-8);
+        // without fractions, they are truncated
+        assert_eq!(eval("pi").unwrap(), 3);
+    }
+
+    #[test]
+    fn physical_constants_need_physics() {
+        let mut physics = Interpreter { physics: true,
+                                        ..Interpreter::new() };
+        assert_eq!(eval_with(&mut physics, "c").unwrap(),
+                   Rational::from_integer(299792458));
+        let mut rational = Interpreter { physics: true,
+                                         ..interpreter(true, false, IntWidth::I64) };
+        assert_eq!(eval_with(&mut rational, "g").unwrap(),
+                   Rational::new(196133, 20000).unwrap());
+        assert_eq!(eval("c").unwrap_err().to_string(),
+                   "vm: Unknown variable `c'");
+        assert!(matches!(physics.eval("c = 3"),
+                         Err(InterpreterError::Eval(EvalError::AssignToConstant(..)))));
+        assert!(matches!(Interpreter::new().eval("c = 3"), Ok(None)));
+    }
+
+    #[test]
+    fn unicode_operators_evaluate_like_ascii_ones() {
+        assert_eq!(eval("2 × 3").unwrap(), 6);
+        assert_eq!(eval("6 ÷ 2").unwrap(), 3);
+        assert_eq!(eval("5 − 1").unwrap(), 4);
+    }
+
+    #[test]
+    fn i32_overflows_where_i64_does_not() {
+        let mut i32 = interpreter(false, false, IntWidth::I32);
+        let mut wrapping = interpreter(false, true, IntWidth::I32);
+        assert_eq!(eval("2147483647 + 1").unwrap(), 2147483648);
+        assert!(matches!(eval_with(&mut i32, "2147483647 + 1"),
+                         Err(EvalError::OverflowAt("adding", _))));
+        assert_eq!(eval_with(&mut wrapping, "2147483647 + 1").unwrap(),
+                   Rational::from_integer(i32::MIN as i64));
+        assert_eq!(eval_with(&mut i32, "65535 * 32768").unwrap(),
+                   Rational::from_integer(2147450880));
+        assert!(eval_with(&mut i32, "65536 * 32768").is_err());
+        assert_eq!(eval_with(&mut i32, "1 << 31").unwrap(),
+                   Rational::from_integer(i32::MIN as i64));
+    }
+
+    #[test]
+    fn values_beyond_the_width_are_rejected() {
+        // a literal is rejected by the lexer, a variable when it is used
+        let mut i32 = interpreter(false, false, IntWidth::I32);
+        match i32.eval("1 + 3000000000") {
+            Err(InterpreterError::Parser(err)) => {
+                assert_eq!(err.msg,
+                           "Integer literal too large (at most 2147483647)");
+            }
+            _ => panic!("`3000000000' doesn't fit 32 bits"),
+        }
+        assert!(eval_with(&mut i32, "2147483647").is_ok());
+        i32.env
+           .insert(String::from("x"), Value::Integer(3000000000));
+        assert_eq!(eval_with(&mut i32, "x"),
+                   Err(EvalError::OutOfRange(3000000000, 32)));
+    }
+
+    #[test]
+    fn i128_computes_beyond_64_bits() {
+        let mut i128 = interpreter(false, false, IntWidth::I128);
+        let mut wrapping = interpreter(false, true, IntWidth::I128);
+        let value =
+            |interpreter: &mut Interpreter, input| match interpreter.eval(input) {
+                Ok(Some(Value::Integer(n))) => n,
+                _ => panic!("`{}' has no integer result", input),
+            };
+        assert!(eval("9223372036854775807 + 1").is_err());
+        assert_eq!(value(&mut i128, "9223372036854775807 + 1"), 1 << 63);
+        assert_eq!(value(&mut i128, "2^100"), 1 << 100);
+        assert_eq!(value(&mut i128, "-1 >>> 1"), i128::MAX);
+        assert_eq!(value(&mut i128, "1 << 127"), i128::MIN);
+        assert!(matches!(i128.eval("2^127"),
+                         Err(InterpreterError::Eval(EvalError::OverflowAt("exponentiating", _)))));
+        assert_eq!(value(&mut wrapping, "2^127"), i128::MIN);
+        assert!(matches!(i128.eval("1 << 128"),
+                         Err(InterpreterError::Eval(EvalError::InvalidShift(128,
+                                                                            128)))));
+        // the arguments of `gcd' are still 64 bit integers
+        assert_eq!(value(&mut i128, "gcd(2^40, 2^50)"), 1 << 40);
+        assert!(matches!(i128.eval("gcd(2^70, 2)"),
+                         Err(InterpreterError::Eval(EvalError::OutOfRange(_, 64)))));
+        assert!(matches!(i128.eval("x = 2^64"), Ok(None)));
+        assert!(matches!(i128.eval("x += x"), Ok(None)));
+        assert_eq!(value(&mut i128, "x"), 1 << 65);
+    }
 }
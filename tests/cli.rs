@@ -0,0 +1,269 @@
+/*
+ * cli.rs: Run the binary like a user would and check what it prints. This is
+ * for everything that only `main' and the REPL do, e.g. reading `stdin'.
+ */
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/*
+ * The binary with `args', without an `EXPR_PARSER_INPUT' of the environment.
+ * `HOME' points elsewhere, so the REPL doesn't add the inputs to the history
+ * of the user.
+ */
+fn command(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_expr_parser"));
+    command.args(args)
+           .env_remove("EXPR_PARSER_INPUT")
+           .env("HOME", env::temp_dir());
+    command
+}
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    run_command(&mut command(args), stdin)
+}
+
+/* Run `command' and write `stdin' to its standard input. */
+fn run_command(command: &mut Command, stdin: &str) -> Output {
+    let mut child = command.stdin(Stdio::piped())
+                           .stdout(Stdio::piped())
+                           .stderr(Stdio::piped())
+                           .spawn()
+                           .expect("the binary should start");
+    child.stdin
+         .take()
+         .unwrap()
+         .write_all(stdin.as_bytes())
+         .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr_of(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn accumulate_keeps_a_running_total() {
+    let output = run(&["--accumulate"],
+                     "1+2\n4\n:total\n:clear-total\n5\n:total\nquit\n");
+    let stderr = stderr_of(&output);
+    assert!(output.status.success());
+    assert!(stderr.contains("\t3\t(total: 3)\n"));
+    assert!(stderr.contains("\t4\t(total: 7)\n"));
+    assert!(stderr.contains("\ttotal: 7\n"));
+    assert!(stderr.contains("\t5\t(total: 5)\n"));
+    assert!(stderr.contains("\ttotal: 5\n"));
+}
+
+#[test]
+fn quit_ends_the_session_in_any_case() {
+    for quit in ["quit", "QUIT", "Q"].iter() {
+        let output = run(&[], &format!("1+1\n{}\n40+2\n", quit));
+        let stderr = stderr_of(&output);
+        assert!(output.status.success());
+        assert!(stderr.contains("\t2\n"));
+        assert!(!stderr.contains("42"), "{}", quit);
+    }
+}
+
+#[test]
+fn total_needs_accumulate() {
+    let stderr = stderr_of(&run(&[], "1+2\n:total\nquit\n"));
+    assert!(stderr.contains("\t3\n"));
+    assert!(stderr.contains("Start with `--accumulate' to keep a running total"));
+}
+
+#[test]
+fn errors_are_only_colored_with_color() {
+    for input in ["1+", "9223372036854775807*2"].iter() {
+        let always = stderr_of(&run(&["--color=always", "-e", input], ""));
+        assert!(always.contains("\x1b[31m"), "{}", input);
+        let never = stderr_of(&run(&["--color=never", "-e", input], ""));
+        assert!(!never.is_empty());
+        assert!(!never.contains('\x1b'), "{}", input);
+    }
+}
+
+#[test]
+fn output_writes_the_results_to_a_file() {
+    let path = env::temp_dir().join("expr_parser_output_test.txt");
+    let file = path.to_str().unwrap();
+    let output = run(&["-e", "1+2", "--output", file], "");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "3\n");
+
+    // the file is truncated, and every line of `--file' has a result
+    let output = run(&["--file", "/dev/stdin", "--output", file], "1+2\n2*3\n");
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "3\n6\n");
+    fs::remove_file(&path).unwrap();
+
+    let output = run(&["-e", "1+2", "--output", "/nonexistent/result.txt"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr_of(&output).is_empty());
+}
+
+#[test]
+fn the_expression_can_come_from_the_environment() {
+    let output = run_command(command(&[]).env("EXPR_PARSER_INPUT", "1+2"), "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+
+    // `-e' takes precedence, and an empty variable starts the REPL
+    let output =
+        run_command(command(&["-e", "5"]).env("EXPR_PARSER_INPUT", "1+2"), "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+    let output = run_command(command(&[]).env("EXPR_PARSER_INPUT", ""), "7\n");
+    assert!(output.stdout.is_empty());
+    assert!(stderr_of(&output).contains("\t7\n"));
+}
+
+#[test]
+fn a_trailing_backslash_continues_the_input() {
+    let stderr = stderr_of(&run(&[], "1 + \\\n2 * 3\nquit\n"));
+    assert!(stderr.contains("\t7\n"));
+    let stderr = stderr_of(&run(&[], "12\\\n3\nquit\n"));
+    assert!(stderr.contains("Trailing `3'"));
+    let stderr = stderr_of(&run(&[], "4 # note \\\n5\nquit\n"));
+    assert!(stderr.contains("\t4\n"));
+    assert!(stderr.contains("\t5\n"));
+}
+
+#[test]
+fn an_empty_expression_is_an_error() {
+    for input in ["", "   ", " # a comment"].iter() {
+        let output = run(&["-e", input], "");
+        assert_eq!(output.status.code(), Some(1), "`{}'", input);
+        assert_eq!(stderr_of(&output), "expr_parser: error: Empty expression\n");
+        assert!(output.stdout.is_empty());
+    }
+    let output = run(&["-e", " 1 + 2 "], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn strict_eof_rejects_what_follows_the_expression() {
+    let output = run(&["--strict-eof", "-e", "2+3"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+    for input in ["2+3   ", "1 # c"].iter() {
+        let output = run(&["--strict-eof", "-e", input], "");
+        assert_eq!(output.status.code(), Some(1), "`{}'", input);
+        assert!(stderr_of(&output).contains("Unexpected trailing"));
+    }
+    assert!(run(&["-e", "1 # c"], "").status.success());
+}
+
+#[test]
+fn bool_exit_follows_shell_truthiness() {
+    let code = |input: &str| {
+        let output = run(&["--result-as-bool-exit", "-e", input], "");
+        assert!(output.stdout.is_empty());
+        output.status.code()
+    };
+    assert_eq!(code("7%4-2"), Some(0));
+    assert_eq!(code("0-1"), Some(0));
+    assert_eq!(code("2*3-6"), Some(1));
+    assert_eq!(code("1+"), Some(2));
+    assert_eq!(code("1/0"), Some(2));
+}
+
+#[test]
+fn comments_are_skipped_in_a_file() {
+    let output = run(&["--file", "/dev/stdin"], "# header\n1+2 # three\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+    let output = run(&["-e", "1 + 2 # add them"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn int_width_picks_the_bounds() {
+    let output = run(&["--int-width", "128", "-e", "2^100"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "1267650600228229401496703205376\n");
+    let output = run(&["--int-width", "32", "-e", "2147483647 + 1"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let output = run(&["--int-width", "32", "-e", "3000000000"], "");
+    assert!(stderr_of(&output).contains("Integer literal too large (at most 2147483647)"));
+    let output = run(&["-e", "2147483647 + 1"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2147483648\n");
+}
+
+#[test]
+fn verbose_shows_the_parse_before_the_result() {
+    let stderr = stderr_of(&run(&["--verbose"], "2+3*4\nquit\n"));
+    assert!(stderr.contains("\tinfix: 2 + 3 * 4\n\trpn:   2 3 4 * +\n\t14\n"));
+}
+
+#[test]
+fn prefix_prints_the_tree() {
+    let output = run(&["--prefix", "-e", "2+3*4"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "(+ 2 (* 3 4))\n");
+    let output = run(&["--prefix", "-e", "(2+3)*4"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "(* (+ 2 3) 4)\n");
+}
+
+#[test]
+fn blank_lines_are_skipped_in_a_file() {
+    let output = run(&["--file", "/dev/stdin"], "1+2\n\n2*(3+4)\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n14\n");
+}
+
+#[test]
+fn every_error_of_a_line_is_reported() {
+    let output = run(&["--file", "/dev/stdin"], "(1 +) * (2 3)\n");
+    let stderr = stderr_of(&output);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("Token 3: Expected an operand, found `)'.\n"));
+    assert!(stderr.contains("Token 7: Missing operator between `2' and `3'.\n"));
+}
+
+#[test]
+fn info_lists_the_operators_of_the_grammar() {
+    let output = run(&["--info"], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("  0  Bitwise      << >> >>>         left-associative\n"));
+    assert!(stdout.contains("  3  Factor       ^                 right-associative\n"));
+    let output = run(&["--info", "--c-operators"], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  0  Bitwise      << >> >>> ^       left-associative\n"));
+    assert!(stdout.contains("  3  Factor       **                right-associative\n"));
+}
+
+#[test]
+fn dump_ast_prints_the_tree_to_stderr() {
+    let output = run(&["--dump-ast", "-e", "1+2"], "");
+    assert!(stderr_of(&output).starts_with("expr_parser: ParseNode {\n"));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn show_precedence_annotates_every_node() {
+    let stderr = stderr_of(&run(&["--show-precedence", "-e", "2+3*4"], ""));
+    assert!(stderr.starts_with("expr_parser: + (Expression, prec 4, depth 2)\n"));
+    assert!(stderr.contains("\nexpr_parser:   * (Term, prec 5, depth 1)\n"));
+}
+
+#[test]
+fn output_styles_print_in_their_base() {
+    let styles: [(&[&str], &str); 7] = [(&[], "1000000"),
+                                        (&["--radix", "hex"], "0xF4240"),
+                                        (&["--radix", "bin"],
+                                         "0b11110100001001000000"),
+                                        (&["--radix", "oct"], "0o3641100"),
+                                        (&["--sci"], "1e6"),
+                                        (&["--grouping"], "1,000,000"),
+                                        (&["--radix", "hex", "--sci"], "0xF4240")];
+    for (args, expected) in styles.iter() {
+        let output = run(&[*args, &["-e", "1000000"][..]].concat(), "");
+        assert_eq!(String::from_utf8_lossy(&output.stdout),
+                   format!("{}\n", expected),
+                   "{:?}",
+                   args);
+    }
+}
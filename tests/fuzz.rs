@@ -0,0 +1,320 @@
+/*
+ * fuzz.rs: Compare the parser and the vm with an independent evaluator.
+ *
+ * Random arithmetic expressions are written out as strings, evaluated with
+ * `parse_str' and `vm::evaluate' and, independently of the parser, with a
+ * shunting-yard evaluator of the default grammar. Both must agree on every
+ * result and on which expressions fail (e.g. `1/0' or an overflow), which
+ * catches regressions of the precedence levels and the associativity. A
+ * mismatch is shrunk to a small expression that still disagrees.
+ *
+ * It runs with `cargo test' or, on its own, with `cargo test --test fuzz'.
+ * Every test checks `ITERATIONS' expressions of a fixed seed, so a failure
+ * can be reproduced.
+ */
+use expr_parser::parser::parse_str;
+use expr_parser::vm;
+use std::convert::TryFrom;
+
+const ITERATIONS: usize = 5000;
+const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+const MAX_DEPTH: u32 = 6;
+
+/* A xorshift generator, so the expressions are the same for every seed. */
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mult,
+    Div,
+    Mod,
+    Exp,
+}
+
+const OPS: [Op; 6] = [Op::Add, Op::Sub, Op::Mult, Op::Div, Op::Mod, Op::Exp];
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mult => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::Exp => "^",
+        }
+    }
+
+    /* The levels of the default grammar, see GRAMMAR.md. */
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Add | Op::Sub => 1,
+            Op::Mult | Op::Div | Op::Mod => 2,
+            Op::Exp => 4,
+        }
+    }
+
+    fn apply(self, lhs: i64, rhs: i64) -> Option<i64> {
+        match self {
+            Op::Add => lhs.checked_add(rhs),
+            Op::Sub => lhs.checked_sub(rhs),
+            Op::Mult => lhs.checked_mul(rhs),
+            Op::Div => lhs.checked_div(rhs),
+            Op::Mod => lhs.checked_rem(rhs),
+            Op::Exp if rhs < 0 => None,
+            Op::Exp => u32::try_from(rhs).ok().and_then(|exp| lhs.checked_pow(exp)),
+        }
+    }
+}
+
+/*
+ * A generated expression. It is only a tree to be shrunk, the string that
+ * `render' writes is all that the parser and the oracle see.
+ */
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(i64),
+    Neg(Box<Expr>),
+    Paren(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+fn generate(rng: &mut Rng, depth: u32) -> Expr {
+    if depth == 0 || rng.below(4) == 0 {
+        // mostly small literals, sometimes a huge one to provoke overflows
+        return match rng.below(20) {
+            0 => Expr::Literal(i64::MAX),
+            _ => Expr::Literal(rng.below(21) as i64),
+        };
+    }
+    match rng.below(8) {
+        0 => Expr::Neg(Box::new(generate(rng, depth - 1))),
+        1 => Expr::Paren(Box::new(generate(rng, depth - 1))),
+        _ => {
+            let op = OPS[rng.below(OPS.len() as u64) as usize];
+            Expr::Binary(op,
+                         Box::new(generate(rng, depth - 1)),
+                         Box::new(generate(rng, depth - 1)))
+        }
+    }
+}
+
+/*
+ * Write the expression without any other parentheses than its own, so the
+ * parser has to get the precedence right. Every `%' has an operand, so it is
+ * always a modulo (`7 % -2' included).
+ */
+fn render(expr: &Expr) -> String {
+    match expr {
+        Expr::Literal(n) => n.to_string(),
+        Expr::Neg(child) => format!("-{}", render(child)),
+        Expr::Paren(child) => format!("({})", render(child)),
+        Expr::Binary(op, lhs, rhs) => {
+            format!("{} {} {}", render(lhs), op.symbol(), render(rhs))
+        }
+    }
+}
+
+/* The simpler expressions that `expr' can be shrunk to. */
+fn shrink(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Literal(0) => vec![],
+        Expr::Literal(n) => vec![Expr::Literal(0), Expr::Literal(n / 2)],
+        Expr::Neg(child) | Expr::Paren(child) => {
+            let mut candidates = vec![(**child).clone()];
+            for smaller in shrink(child) {
+                candidates.push(match expr {
+                                    Expr::Neg(_) => Expr::Neg(Box::new(smaller)),
+                                    _ => Expr::Paren(Box::new(smaller)),
+                                });
+            }
+            candidates
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let mut candidates = vec![(**lhs).clone(), (**rhs).clone()];
+            for smaller in shrink(lhs) {
+                candidates.push(Expr::Binary(*op, Box::new(smaller), rhs.clone()));
+            }
+            for smaller in shrink(rhs) {
+                candidates.push(Expr::Binary(*op, lhs.clone(), Box::new(smaller)));
+            }
+            candidates
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Item {
+    Number(i64),
+    Binary(Op),
+    Neg,
+    LeftParen,
+    RightParen,
+}
+
+/* Split the rendered string into the items of the oracle. */
+fn scan(input: &str) -> Vec<Item> {
+    let mut items = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        let item = match c {
+            ' ' => continue,
+            '0'..='9' => {
+                let mut digits = c.to_string();
+                while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                    digits.push(*d);
+                    chars.next();
+                }
+                Item::Number(digits.parse().unwrap())
+            }
+            '(' => Item::LeftParen,
+            ')' => Item::RightParen,
+            // a `-' where an operand is expected is a unary minus
+            '-' if !matches!(items.last(),
+                             Some(Item::Number(_) | Item::RightParen)) =>
+            {
+                Item::Neg
+            }
+            c => Item::Binary(*OPS.iter()
+                                  .find(|op| op.symbol().starts_with(c))
+                                  .unwrap()),
+        };
+        items.push(item);
+    }
+    items
+}
+
+/*
+ * The oracle: a shunting-yard evaluator of the default grammar. A unary minus
+ * binds weaker than `^' and tighter than `*', so `-2^2' is `-(2^2)', and `^'
+ * is the only right-associative operator. `None' stands for any error.
+ */
+fn oracle(input: &str) -> Option<i64> {
+    const NEG_PRECEDENCE: u8 = 3;
+    let mut operands: Vec<i64> = vec![];
+    let mut operators: Vec<Item> = vec![];
+
+    fn reduce(operator: Item, operands: &mut Vec<i64>) -> Option<()> {
+        let rhs = operands.pop()?;
+        let res = match operator {
+            Item::Neg => rhs.checked_neg()?,
+            Item::Binary(op) => op.apply(operands.pop()?, rhs)?,
+            _ => return None,
+        };
+        operands.push(res);
+        Some(())
+    }
+    let precedence = |item: &Item| match item {
+        Item::Binary(op) => op.precedence(),
+        Item::Neg => NEG_PRECEDENCE,
+        _ => 0,
+    };
+
+    for item in scan(input) {
+        match item {
+            Item::Number(n) => operands.push(n),
+            Item::Neg | Item::LeftParen => operators.push(item),
+            Item::RightParen => {
+                while let Some(top) = operators.pop() {
+                    if let Item::LeftParen = top {
+                        break;
+                    }
+                    reduce(top, &mut operands)?;
+                }
+            }
+            Item::Binary(op) => {
+                while let Some(top) = operators.last().copied() {
+                    let binds_tighter = match top {
+                        Item::LeftParen => false,
+                        _ if op == Op::Exp => precedence(&top) > op.precedence(),
+                        _ => precedence(&top) >= op.precedence(),
+                    };
+                    if !binds_tighter {
+                        break;
+                    }
+                    operators.pop();
+                    reduce(top, &mut operands)?;
+                }
+                operators.push(item);
+            }
+        }
+    }
+    while let Some(top) = operators.pop() {
+        reduce(top, &mut operands)?;
+    }
+    operands.pop()
+}
+
+/* The result of the parser and the vm, `None' for any error like the oracle. */
+fn evaluate(input: &str) -> Option<i64> {
+    parse_str(input).ok()
+                    .and_then(|ast| vm::evaluate(&ast).ok())
+}
+
+fn agrees(expr: &Expr) -> bool {
+    let input = render(expr);
+    evaluate(&input) == oracle(&input)
+}
+
+/* Shrink a disagreeing expression for as long as a simpler one disagrees, too. */
+fn minimize(expr: Expr) -> Expr {
+    let mut expr = expr;
+    while let Some(smaller) = shrink(&expr).into_iter().find(|e| !agrees(e)) {
+        expr = smaller;
+    }
+    expr
+}
+
+/* Check `ITERATIONS' expressions of the `seed', which must not be 0. */
+fn check_seed(seed: u64) {
+    let mut rng = Rng(seed);
+    for i in 0..ITERATIONS {
+        let expr = generate(&mut rng, MAX_DEPTH);
+        if !agrees(&expr) {
+            let input = render(&minimize(expr));
+            panic!("expression {} of seed {} disagrees, shrunk to `{}': the vm \
+                    returns {:?}, the oracle {:?}",
+                   i + 1,
+                   seed,
+                   input,
+                   evaluate(&input),
+                   oracle(&input));
+        }
+    }
+}
+
+#[test]
+fn the_oracle_follows_the_default_grammar() {
+    assert_eq!(oracle("1 + 2 * 3"), Some(7));
+    assert_eq!(oracle("-2 ^ 2"), Some(-4));
+    assert_eq!(oracle("2 ^ 3 ^ 2"), Some(512));
+    assert_eq!(oracle("17 % 5 % 3"), Some(2));
+    assert_eq!(oracle("(1 - 2) - 3"), Some(-4));
+    assert_eq!(oracle("1 / 0"), None);
+    assert_eq!(oracle("9223372036854775807 + 1"), None);
+}
+
+#[test]
+fn random_expressions_agree_with_the_oracle() {
+    check_seed(SEED);
+}
+
+#[test]
+fn random_expressions_of_another_seed_agree_with_the_oracle() {
+    check_seed(SEED.rotate_left(32));
+}